@@ -4,7 +4,7 @@ use colored::Colorize;
 use comfy_table::presets::UTF8_FULL;
 use comfy_table::{Attribute, Cell, Color, ContentArrangement, Table};
 use hf_hub::api::sync::ApiBuilder;
-use nano_vectordb_rs::{constants, Data, NanoVectorDB};
+use nano_vectordb_rs::{Data, NanoVectorDB};
 use parquet::file::reader::SerializedFileReader;
 use parquet::record::{ListAccessor, RowAccessor};
 use serde::{Deserialize, Serialize};
@@ -97,6 +97,7 @@ fn main() -> Result<()> {
                 id,
                 vector: embedding,
                 fields,
+                weight: None,
             }
         })
         .collect();
@@ -152,7 +153,7 @@ fn main() -> Result<()> {
         .collect();
 
     // Perform search
-    let results = db.query(&query_vector, 5, Some(0.5), None);
+    let results = db.query_typed(&query_vector, 5, Some(0.5), None);
 
     // Results Table
     let mut results_table = Table::new();
@@ -173,18 +174,11 @@ fn main() -> Result<()> {
 
     for result in results {
         results_table.add_row(vec![
-            Cell::new(result[constants::F_ID].as_str().unwrap_or("[Invalid ID]")).fg(Color::Blue),
-            Cell::new(format!("{:.4}", result[constants::F_METRICS])).fg(Color::Yellow),
-            Cell::new(
-                result["text"]
-                    .as_str()
-                    .unwrap_or("")
-                    .chars()
-                    .take(100)
-                    .collect::<String>(),
-            )
-            .fg(Color::White)
-            .add_attribute(Attribute::Dim),
+            Cell::new(&result.id).fg(Color::Blue),
+            Cell::new(format!("{:.4}", result.score)).fg(Color::Yellow),
+            Cell::new(result.field_truncated("text", 100).unwrap_or_default())
+                .fg(Color::White)
+                .add_attribute(Attribute::Dim),
         ]);
     }
 
@@ -20,16 +20,19 @@ fn main() -> Result<()> {
             id: "vec1".into(),
             vector: vec![1.02, 2.0, 3.0],
             fields: [("color".into(), json!("red"))].into(),
+            weight: None,
         },
         Data {
             id: "vec2".into(),
             vector: vec![-4.0, 5.0, 6.0],
             fields: [("color".into(), json!("blue"))].into(),
+            weight: None,
         },
         Data {
             id: "vec3".into(),
             vector: vec![7.0, 8.0, -9.0],
             fields: [("color".into(), json!("green"))].into(),
+            weight: None,
         },
     ];
 
@@ -1,8 +1,31 @@
 //! Benchmarking script with complete metrics
 use nano_vectordb_rs::NanoVectorDB;
 use rand::Rng;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
+/// Counts bytes and allocations so result paths can be compared
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
 fn main() -> anyhow::Result<()> {
     let config = BenchmarkConfig {
         embedding_dim: 1024,
@@ -20,6 +43,11 @@ fn main() -> anyhow::Result<()> {
     }
 
     metrics.print();
+    benchmark_result_fields()?;
+    benchmark_filtered_query()?;
+    benchmark_field_index()?;
+    benchmark_id_index()?;
+    benchmark_query_allocations()?;
     Ok(())
 }
 
@@ -33,6 +61,7 @@ struct BenchmarkMetrics {
     insert_times: Vec<f64>,
     save_times: Vec<f64>,
     query_times: Vec<f64>,
+    pruned_query_times: Vec<f64>,
     file_sizes: Vec<f64>,
 }
 
@@ -42,6 +71,7 @@ impl BenchmarkMetrics {
             insert_times: Vec::with_capacity(capacity),
             save_times: Vec::with_capacity(capacity),
             query_times: Vec::with_capacity(capacity),
+            pruned_query_times: Vec::with_capacity(capacity),
             file_sizes: Vec::with_capacity(capacity),
         }
     }
@@ -50,6 +80,7 @@ impl BenchmarkMetrics {
         self.insert_times.push(metrics.insert_time);
         self.save_times.push(metrics.save_time);
         self.query_times.push(metrics.query_time);
+        self.pruned_query_times.push(metrics.pruned_query_time);
         self.file_sizes.push(metrics.file_size);
     }
 
@@ -57,6 +88,7 @@ impl BenchmarkMetrics {
         let (ins_mean, ins_std) = calculate_stats(&self.insert_times);
         let (save_mean, save_std) = calculate_stats(&self.save_times);
         let (q_mean, q_std) = calculate_stats(&self.query_times);
+        let (pq_mean, pq_std) = calculate_stats(&self.pruned_query_times);
         let (size_mean, size_std) = calculate_stats(&self.file_sizes);
 
         println!("\nBenchmark Results ({} runs):", self.insert_times.len());
@@ -66,6 +98,7 @@ impl BenchmarkMetrics {
         println!("Insert Time: {:7.2}ms ± {:.2}", ins_mean, ins_std);
         println!("Save Time:   {:7.2}ms ± {:.2}", save_mean, save_std);
         println!("Query Time:  {:7.3}ms ± {:.3}", q_mean, q_std);
+        println!("Pruned Query:{:7.3}ms ± {:.3}", pq_mean, pq_std);
         println!("File Size:   {:7.2}MB ± {:.2}", size_mean, size_std);
     }
 }
@@ -74,6 +107,7 @@ struct RunMetrics {
     insert_time: f64,
     save_time: f64,
     query_time: f64,
+    pruned_query_time: f64,
     file_size: f64,
 }
 
@@ -91,6 +125,7 @@ fn benchmark_run(config: &BenchmarkConfig, filename: &str) -> anyhow::Result<Run
                 id: format!("vec_{}", i),
                 vector,
                 fields: std::collections::HashMap::new(),
+                weight: None,
             }
         })
         .collect();
@@ -114,6 +149,12 @@ fn benchmark_run(config: &BenchmarkConfig, filename: &str) -> anyhow::Result<Run
     let _ = db.query(&query_vector, 10, None, None);
     let query_time = duration_to_ms(query_start.elapsed());
 
+    // Time the same query with bound-based early termination
+    let db = db.with_pruned_scoring(true);
+    let query_start = Instant::now();
+    let _ = db.query(&query_vector, 10, None, None);
+    let pruned_query_time = duration_to_ms(query_start.elapsed());
+
     // Get file size
     let file_size = std::fs::metadata(filename)
         .map(|md| md.len() as f64 / 1_000_000.0)
@@ -123,10 +164,264 @@ fn benchmark_run(config: &BenchmarkConfig, filename: &str) -> anyhow::Result<Run
         insert_time,
         save_time,
         query_time,
+        pruned_query_time,
         file_size,
     })
 }
 
+/// Compares cloned (`query`) and borrowed (`query_ref`) results on records
+/// carrying large metadata fields
+fn benchmark_result_fields() -> anyhow::Result<()> {
+    let (embedding_dim, num_vectors, top_k) = (128, 10_000, 100);
+    let filename = "benchmark_fields.json";
+    let mut db = NanoVectorDB::new(embedding_dim, filename)?;
+
+    let mut rng = rand::rng();
+    let text = "lorem ipsum ".repeat(400);
+    let data_vec: Vec<_> = (0..num_vectors)
+        .map(|i| {
+            let mut vector = vec![0.0; embedding_dim];
+            rng.fill(&mut vector[..]);
+
+            nano_vectordb_rs::Data {
+                id: format!("vec_{}", i),
+                vector,
+                fields: [("text".to_string(), text.clone().into())].into(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data_vec)?;
+
+    let mut query_vector = vec![0.0; embedding_dim];
+    rng.fill(&mut query_vector[..]);
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let cloned = db.query(&query_vector, top_k, None, None);
+    let cloned_time = duration_to_ms(start.elapsed());
+    let cloned_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    drop(cloned);
+
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let borrowed = db.query_ref(&query_vector, top_k, None, None);
+    let borrowed_time = duration_to_ms(start.elapsed());
+    let borrowed_bytes = ALLOCATED.load(Ordering::Relaxed) - before;
+    drop(borrowed);
+
+    println!(
+        "\nResult Fields ({} records, ~{}KB field, top {}):",
+        num_vectors,
+        text.len() / 1000,
+        top_k
+    );
+    println!("===================================");
+    println!(
+        "Cloned:   {:7.3}ms | {:9} bytes allocated",
+        cloned_time, cloned_bytes
+    );
+    println!(
+        "Borrowed: {:7.3}ms | {:9} bytes allocated",
+        borrowed_time, borrowed_bytes
+    );
+
+    cleanup_file(filename)
+}
+
+/// Compares interleaved filtering with the filter-first mode on a large matrix
+fn benchmark_filtered_query() -> anyhow::Result<()> {
+    let (embedding_dim, num_vectors, num_queries) = (256, 100_000, 20);
+    let filename = "benchmark_filtered.json";
+    let mut db = NanoVectorDB::new(embedding_dim, filename)?;
+
+    let mut rng = rand::rng();
+    let data_vec: Vec<_> = (0..num_vectors)
+        .map(|i| {
+            let mut vector = vec![0.0; embedding_dim];
+            rng.fill(&mut vector[..]);
+
+            nano_vectordb_rs::Data {
+                id: format!("vec_{}", i),
+                vector,
+                fields: [("shard".to_string(), (i % 10).into())].into(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data_vec)?;
+    let queries: Vec<Vec<f32>> = (0..num_queries)
+        .map(|_| {
+            let mut query_vector = vec![0.0; embedding_dim];
+            rng.fill(&mut query_vector[..]);
+            query_vector
+        })
+        .collect();
+
+    let time_queries = |db: &NanoVectorDB| {
+        let start = Instant::now();
+        for query_vector in &queries {
+            let _ = db.query(
+                query_vector,
+                10,
+                None,
+                Some(Box::new(|d| d.fields["shard"] == 3)),
+            );
+        }
+        duration_to_ms(start.elapsed()) / num_queries as f64
+    };
+    let interleaved_time = time_queries(&db);
+    let db = db.with_filter_first(true);
+    let filter_first_time = time_queries(&db);
+
+    println!(
+        "\nFiltered Query ({} vectors, 10% selectivity):",
+        num_vectors
+    );
+    println!("===================================");
+    println!("Interleaved:  {:7.3}ms", interleaved_time);
+    println!("Filter-first: {:7.3}ms", filter_first_time);
+
+    cleanup_file(filename)
+}
+
+fn benchmark_field_index() -> anyhow::Result<()> {
+    let (embedding_dim, num_vectors, num_queries) = (256, 100_000, 20);
+    let filename = "benchmark_field_index.json";
+    let mut db = NanoVectorDB::new(embedding_dim, filename)?;
+
+    let mut rng = rand::rng();
+    let data_vec: Vec<_> = (0..num_vectors)
+        .map(|i| {
+            let mut vector = vec![0.0; embedding_dim];
+            rng.fill(&mut vector[..]);
+
+            nano_vectordb_rs::Data {
+                id: format!("vec_{}", i),
+                vector,
+                fields: [("source".to_string(), format!("src_{}", i % 100).into())].into(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data_vec)?;
+    let queries: Vec<Vec<f32>> = (0..num_queries)
+        .map(|_| {
+            let mut query_vector = vec![0.0; embedding_dim];
+            rng.fill(&mut query_vector[..]);
+            query_vector
+        })
+        .collect();
+
+    let source = serde_json::json!("src_7");
+    let time_queries = |db: &NanoVectorDB| {
+        let start = Instant::now();
+        for query_vector in &queries {
+            let _ = db.query_where(query_vector, "source", &source, 10, None);
+        }
+        duration_to_ms(start.elapsed()) / num_queries as f64
+    };
+    let scan_time = time_queries(&db);
+    db.build_field_index("source")?;
+    let indexed_time = time_queries(&db);
+
+    println!(
+        "\nEquality Filter ({} vectors, 1% selectivity):",
+        num_vectors
+    );
+    println!("===================================");
+    println!("Scan:    {:7.3}ms", scan_time);
+    println!("Indexed: {:7.3}ms", indexed_time);
+
+    cleanup_file(filename)
+}
+
+fn benchmark_id_index() -> anyhow::Result<()> {
+    let (embedding_dim, num_vectors, num_lookups) = (8, 1_000_000, 100);
+    let filename = "benchmark_id_index.json";
+    let mut db = NanoVectorDB::new(embedding_dim, filename)?;
+
+    let mut rng = rand::rng();
+    let data_vec: Vec<_> = (0..num_vectors)
+        .map(|i| {
+            let mut vector = vec![0.0; embedding_dim];
+            rng.fill(&mut vector[..]);
+
+            nano_vectordb_rs::Data {
+                id: format!("vec_{}", i),
+                vector,
+                fields: Default::default(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data_vec)?;
+    let ids: Vec<String> = (0..5)
+        .map(|i| format!("vec_{}", i * num_vectors / 5))
+        .collect();
+
+    let time_gets = |db: &NanoVectorDB| {
+        let start = Instant::now();
+        for _ in 0..num_lookups {
+            let _ = db.get(&ids);
+        }
+        duration_to_ms(start.elapsed()) / num_lookups as f64
+    };
+    let scan_time = time_gets(&db);
+    db.build_id_index();
+    let indexed_time = time_gets(&db);
+
+    println!("\nGet 5 Ids ({} vectors):", num_vectors);
+    println!("===================================");
+    println!("Scan:    {:7.3}ms", scan_time);
+    println!("Indexed: {:7.3}ms", indexed_time);
+
+    cleanup_file(filename)
+}
+
+fn benchmark_query_allocations() -> anyhow::Result<()> {
+    let (embedding_dim, num_vectors, top_k) = (64, 100_000, 1000);
+    let filename = "benchmark_allocations.json";
+    let mut db = NanoVectorDB::new(embedding_dim, filename)?;
+
+    let mut rng = rand::rng();
+    let data_vec: Vec<_> = (0..num_vectors)
+        .map(|i| {
+            let mut vector = vec![0.0; embedding_dim];
+            rng.fill(&mut vector[..]);
+
+            nano_vectordb_rs::Data {
+                id: format!("vec_{}", i),
+                vector,
+                fields: Default::default(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data_vec)?;
+
+    let mut query_vector = vec![0.0; embedding_dim];
+    rng.fill(&mut query_vector[..]);
+
+    let (count_before, bytes_before) = (
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED.load(Ordering::Relaxed),
+    );
+    let hits = db.query_ref(&query_vector, top_k, None, None);
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - count_before;
+    let bytes = ALLOCATED.load(Ordering::Relaxed) - bytes_before;
+    drop(hits);
+
+    println!(
+        "\nQuery Allocations ({} vectors, top {}):",
+        num_vectors, top_k
+    );
+    println!("===================================");
+    println!("{:6} allocations | {:9} bytes", allocations, bytes);
+
+    cleanup_file(filename)
+}
+
 fn cleanup_file(filename: &str) -> anyhow::Result<()> {
     if std::path::Path::new(filename).exists() {
         std::fs::remove_file(filename)?;
@@ -0,0 +1,93 @@
+//! Gzip compression of large string field values in the storage file
+
+use crate::error::{NanoVectorDbError, Result};
+use crate::Data;
+use base64::{engine::general_purpose, Engine as _};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+/// Sole key of the object stored in place of a compressed string, holding the
+/// base64 gzip bytes: `{"__gzip__": "H4sI..."}`
+pub(crate) const MARKER: &str = "__gzip__";
+
+/// A record serialized like [`Data`], with string fields longer than the
+/// threshold replaced by their compressed form
+#[derive(Serialize)]
+pub(crate) struct CompressedData<'a> {
+    #[serde(rename = "__id__")]
+    id: &'a str,
+    #[serde(flatten)]
+    fields: HashMap<&'a str, Cow<'a, serde_json::Value>>,
+}
+
+impl<'a> CompressedData<'a> {
+    pub(crate) fn new(data: &'a Data, threshold: usize) -> Self {
+        let fields = data
+            .fields
+            .iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(text) if text.len() > threshold => {
+                        Cow::Owned(compress(text))
+                    }
+                    _ => Cow::Borrowed(value),
+                };
+                (key.as_str(), value)
+            })
+            .collect();
+        Self {
+            id: &data.id,
+            fields,
+        }
+    }
+}
+
+fn compress(text: &str) -> serde_json::Value {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing into a Vec cannot fail
+    encoder
+        .write_all(text.as_bytes())
+        .expect("in-memory writes succeed");
+    let bytes = encoder.finish().expect("in-memory writes succeed");
+    serde_json::json!({ MARKER: general_purpose::STANDARD.encode(bytes) })
+}
+
+/// Replaces every compressed field value of `data` by the original string
+pub(crate) fn decompress_fields(data: &mut [Data]) -> Result<()> {
+    for value in data.iter_mut().flat_map(|d| d.fields.values_mut()) {
+        if let Some(text) = decompress(value)? {
+            *value = serde_json::Value::String(text);
+        }
+    }
+    Ok(())
+}
+
+/// The original string if `value` is a compressed one
+fn decompress(value: &serde_json::Value) -> Result<Option<String>> {
+    let Some(object) = value.as_object() else {
+        return Ok(None);
+    };
+    let Some(serde_json::Value::String(encoded)) = object.get(MARKER) else {
+        return Ok(None);
+    };
+    if object.len() != 1 {
+        return Ok(None);
+    }
+
+    let corrupt = |e: &dyn std::fmt::Display| {
+        NanoVectorDbError::Corrupt(format!("Invalid compressed field: {e}"))
+    };
+    let bytes = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|e| corrupt(&e))?;
+    let mut text = String::new();
+    GzDecoder::new(bytes.as_slice())
+        .read_to_string(&mut text)
+        .map_err(|e| corrupt(&e))?;
+    Ok(Some(text))
+}
@@ -0,0 +1,67 @@
+//! Handle sharing one database between threads
+
+use crate::{Data, Float, NanoVectorDB, QueryResult};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// A [`NanoVectorDB`] shared between threads behind a read-write lock
+///
+/// Clones are cheap and refer to the same database. Queries through
+/// [`read`](Self::read) run concurrently; writes through [`write`](Self::write)
+/// are exclusive. The compound operations hold the write lock from their first
+/// step to their last, so no other thread can write in between and what they
+/// report is consistent with what they changed.
+#[derive(Debug, Clone)]
+pub struct ConcurrentNanoVectorDB {
+    inner: Arc<RwLock<NanoVectorDB>>,
+}
+
+impl ConcurrentNanoVectorDB {
+    /// Wraps `db` for sharing between threads
+    pub fn new(db: NanoVectorDB) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(db)),
+        }
+    }
+
+    /// Locks the database for reading, blocking while a writer holds it
+    ///
+    /// A panic in another thread holding the lock does not poison it for good:
+    /// the database is handed out as the panicking thread left it.
+    pub fn read(&self) -> RwLockReadGuard<'_, NanoVectorDB> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Locks the database for writing, blocking while anyone else holds it
+    pub fn write(&self) -> RwLockWriteGuard<'_, NanoVectorDB> {
+        self.inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Deletes the records matching `f` and returns how many remain, under one
+    /// write lock
+    pub fn delete_where_then_len(&self, f: impl Fn(&Data) -> bool) -> usize {
+        let mut db = self.write();
+        db.retain(|data| !f(data));
+        db.len()
+    }
+
+    /// Deletes the records matching `f`, then queries what remains, under one
+    /// write lock
+    ///
+    /// Results never include a record matching `f`, even with other threads
+    /// upserting concurrently.
+    pub fn delete_where_then_query(
+        &self,
+        f: impl Fn(&Data) -> bool,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+    ) -> Vec<QueryResult> {
+        let mut db = self.write();
+        db.retain(|data| !f(data));
+        db.query_typed(query, top_k, better_than, None)
+    }
+}
@@ -0,0 +1,80 @@
+//! Minimal RFC 4180 reader for [`NanoVectorDB::import_csv`](crate::NanoVectorDB::import_csv)
+
+use crate::error::{NanoVectorDbError, Result};
+
+/// Splits `text` into rows of fields, each with the line number it starts on
+///
+/// Fields may be quoted to hold separators, line breaks and doubled quotes.
+/// Blank lines are skipped.
+pub(crate) fn parse(text: &str) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let (mut line, mut row_line) = (1, 1);
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => {
+                    if c == '\n' {
+                        line += 1;
+                    }
+                    field.push(c);
+                }
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => row.push(std::mem::take(&mut field)),
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                if row.len() > 1 || !row[0].is_empty() {
+                    rows.push((row_line, std::mem::take(&mut row)));
+                }
+                row.clear();
+                line += 1;
+                row_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(NanoVectorDbError::AtLine {
+            line: row_line,
+            source: Box::new(NanoVectorDbError::InvalidInput(
+                "Unterminated quoted field".to_string(),
+            )),
+        });
+    }
+    if !row.is_empty() || !field.is_empty() {
+        row.push(field);
+        rows.push((row_line, row));
+    }
+    Ok(rows)
+}
+
+/// The JSON value of a field cell: a boolean, a number, or else the text;
+/// `None` for an empty cell
+pub(crate) fn infer_value(cell: &str) -> Option<serde_json::Value> {
+    if cell.is_empty() {
+        return None;
+    }
+    if let Ok(boolean) = cell.parse::<bool>() {
+        return Some(boolean.into());
+    }
+    if let Ok(integer) = cell.parse::<i64>() {
+        return Some(integer.into());
+    }
+    match cell.parse::<f64>() {
+        Ok(number) if number.is_finite() => Some(number.into()),
+        _ => Some(cell.into()),
+    }
+}
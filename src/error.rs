@@ -0,0 +1,93 @@
+//! Error type of the library API
+
+use crate::{FieldType, Float};
+use thiserror::Error;
+
+/// Errors returned by [`NanoVectorDB`](crate::NanoVectorDB) and the free functions
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NanoVectorDbError {
+    /// A vector, database or stored file has the wrong embedding dimension
+    #[error("Embedding dimension mismatch: expected {expected}, got {got}")]
+    DimensionMismatch {
+        /// Dimension the operation requires
+        expected: usize,
+        /// Dimension that was found
+        got: usize,
+    },
+    /// A stored matrix holds a different number of values than its records need
+    #[error("Matrix size mismatch: expected {expected}, got {got}")]
+    MatrixSizeMismatch {
+        /// Number of values the records need
+        expected: usize,
+        /// Number of values found
+        got: usize,
+    },
+    /// A vector's norm is too small to normalize it
+    #[error("Cannot normalize vector with norm {norm} (must exceed {epsilon})")]
+    ZeroVector {
+        /// Norm of the vector
+        norm: Float,
+        /// Norm the vector had to exceed
+        epsilon: Float,
+    },
+    /// No record has the given id
+    #[error("No record with id {0:?}")]
+    NotFound(String),
+    /// Stored data is inconsistent, e.g. duplicate ids or non-finite values
+    #[error("{0}")]
+    Corrupt(String),
+    /// An argument or configuration is invalid for the operation
+    #[error("{0}")]
+    InvalidInput(String),
+    /// A record field does not have the type declared in the schema, or is
+    /// missing while fields are required
+    #[error(
+        "Field {field:?} must be {expected:?}, got {}",
+        .got.as_ref().map_or_else(|| "nothing".to_string(), ToString::to_string)
+    )]
+    SchemaViolation {
+        /// Name of the field
+        field: String,
+        /// Type the schema declares for it
+        expected: FieldType,
+        /// Value found, or `None` if the field is missing
+        got: Option<serde_json::Value>,
+    },
+    /// Every error found in a batch, when there is more than one to report
+    #[error("{}", .0.iter().map(ToString::to_string).collect::<Vec<_>>().join("; "))]
+    Multiple(Vec<NanoVectorDbError>),
+    /// An error caused by one record of a batch
+    #[error("Record {id:?}: {source}")]
+    Record {
+        /// Id of the offending record
+        id: String,
+        /// What is wrong with it
+        source: Box<NanoVectorDbError>,
+    },
+    /// An error caused by one item of a batch, identified by position
+    #[error("At index {index}: {source}")]
+    AtIndex {
+        /// Position of the offending item
+        index: usize,
+        /// What is wrong with it
+        source: Box<NanoVectorDbError>,
+    },
+    /// An error caused by one row of an imported file, identified by line number
+    #[error("Line {line}: {source}")]
+    AtLine {
+        /// 1-based line the offending row starts on
+        line: usize,
+        /// What is wrong with it
+        source: Box<NanoVectorDbError>,
+    },
+    /// Reading or writing a file failed
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// Stored data or a byte buffer could not be parsed
+    #[error(transparent)]
+    Deserialize(#[from] serde_json::Error),
+}
+
+/// Result type of the library API
+pub type Result<T, E = NanoVectorDbError> = std::result::Result<T, E>;
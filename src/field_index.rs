@@ -0,0 +1,88 @@
+//! Inverted index from the values of one record field to storage indices
+
+use crate::Data;
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+/// Maps each value of a field to the storage indices of the records holding it
+///
+/// Values are keyed by their JSON representation, so `3` and `"3"` are distinct.
+/// Numeric values are also kept sorted for range lookups. Records without the
+/// field are not indexed.
+#[derive(Debug)]
+pub(crate) struct FieldIndex {
+    key: String,
+    /// Field value to storage indices, sorted ascending
+    postings: HashMap<String, Vec<usize>>,
+    /// Numeric values with their storage indices, sorted by value
+    numbers: Vec<(f64, usize)>,
+}
+
+impl FieldIndex {
+    pub(crate) fn new(key: &str) -> Self {
+        Self {
+            key: key.to_string(),
+            postings: HashMap::new(),
+            numbers: Vec::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, index: usize, data: &Data) {
+        let Some(value) = data.fields.get(&self.key) else {
+            return;
+        };
+        if let Some(number) = value.as_f64() {
+            let pos = self.numbers.partition_point(|&(n, _)| n < number);
+            self.numbers.insert(pos, (number, index));
+        }
+        let indices = self.postings.entry(value.to_string()).or_default();
+        if let Err(pos) = indices.binary_search(&index) {
+            indices.insert(pos, index);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, index: usize, data: &Data) {
+        let Some(value) = data.fields.get(&self.key) else {
+            return;
+        };
+        if value.is_number() {
+            self.numbers.retain(|&(_, i)| i != index);
+        }
+        let value = value.to_string();
+        if let Some(indices) = self.postings.get_mut(&value) {
+            indices.retain(|&i| i != index);
+            if indices.is_empty() {
+                self.postings.remove(&value);
+            }
+        }
+    }
+
+    /// Re-indexes every record, e.g. after deletes shifted indices
+    pub(crate) fn rebuild(&mut self, data: &[Data]) {
+        self.postings.clear();
+        self.numbers.clear();
+        for (index, data) in data.iter().enumerate() {
+            self.insert(index, data);
+        }
+    }
+
+    /// Storage indices of the records whose field equals `value`, sorted
+    pub(crate) fn lookup(&self, value: &serde_json::Value) -> &[usize] {
+        self.postings
+            .get(&value.to_string())
+            .map_or(&[], Vec::as_slice)
+    }
+
+    /// Storage indices of the records whose field is a number within `range`,
+    /// sorted
+    pub(crate) fn lookup_range(&self, range: &RangeInclusive<f64>) -> Vec<usize> {
+        let start = self.numbers.partition_point(|&(n, _)| n < *range.start());
+        let end = self.numbers.partition_point(|&(n, _)| n <= *range.end());
+        let mut indices: Vec<usize> = self.numbers[start..end.max(start)]
+            .iter()
+            .map(|&(_, i)| i)
+            .collect();
+        indices.sort_unstable();
+        indices
+    }
+}
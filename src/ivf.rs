@@ -0,0 +1,138 @@
+//! Inverted-file (IVF) index for coarse-to-fine search
+
+use crate::error::{NanoVectorDbError, Result};
+use crate::matrix::Matrix;
+use crate::{normalize_with_epsilon, Float, Metric, QueryScorer, DEFAULT_NORM_EPSILON};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+/// Lloyd iterations run when clustering
+const KMEANS_ITERATIONS: usize = 10;
+
+/// Partitions rows by their nearest k-means centroid
+///
+/// Queries only score the rows of the `nprobe` partitions whose centroids are
+/// nearest to the query. Centroids are fixed once clustered; rows inserted later
+/// join the partition of their nearest centroid.
+#[derive(Debug)]
+pub(crate) struct IvfIndex {
+    embedding_dim: usize,
+    metric: Metric,
+    /// `nlist` centroids stored row-major
+    centroids: Vec<Float>,
+    /// Per partition, the storage indices assigned to it
+    lists: Vec<Vec<usize>>,
+    /// Per storage index, its partition
+    assignments: Vec<usize>,
+}
+
+impl IvfIndex {
+    /// Clusters the rows of `matrix` into `nlist` partitions with k-means,
+    /// seeded by `nlist` distinct rows drawn from `seed`
+    pub(crate) fn build(
+        matrix: &Matrix,
+        embedding_dim: usize,
+        metric: Metric,
+        nlist: usize,
+        seed: u64,
+    ) -> Result<Self> {
+        let rows = matrix.len() / embedding_dim.max(1);
+        if nlist == 0 || nlist > rows {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "nlist must be between 1 and the number of records ({}), got {}",
+                rows, nlist
+            )));
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut index = Self {
+            embedding_dim,
+            metric,
+            centroids: Vec::with_capacity(nlist * embedding_dim),
+            lists: Vec::new(),
+            assignments: Vec::new(),
+        };
+        for row in rand::seq::index::sample(&mut rng, rows, nlist) {
+            index
+                .centroids
+                .extend_from_slice(&matrix[row * embedding_dim..(row + 1) * embedding_dim]);
+        }
+
+        for _ in 0..KMEANS_ITERATIONS {
+            index.rebuild(matrix);
+            let mut sums = vec![0.0; nlist * embedding_dim];
+            for (row, &list) in matrix.chunks(embedding_dim).zip(&index.assignments) {
+                let sum = &mut sums[list * embedding_dim..(list + 1) * embedding_dim];
+                sum.iter_mut().zip(row).for_each(|(s, x)| *s += x);
+            }
+            for (list, sum) in sums.chunks(embedding_dim).enumerate() {
+                // Empty partitions keep their previous centroid
+                let count = index.lists[list].len();
+                if count == 0 {
+                    continue;
+                }
+                let mean: Vec<Float> = sum.iter().map(|s| s / count as Float).collect();
+                let centroid = match metric {
+                    Metric::Cosine | Metric::Pearson => {
+                        normalize_with_epsilon(&mean, DEFAULT_NORM_EPSILON).unwrap_or(mean)
+                    }
+                    _ => mean,
+                };
+                index.centroids[list * embedding_dim..(list + 1) * embedding_dim]
+                    .copy_from_slice(&centroid);
+            }
+        }
+        index.rebuild(matrix);
+        Ok(index)
+    }
+
+    /// Partitions ranked by how close their centroid is to `vector`, best first
+    fn ranked_lists(&self, vector: &[Float]) -> Vec<usize> {
+        let scorer = QueryScorer::new(self.metric, vector, false);
+        let scores: Vec<Float> = self
+            .centroids
+            .chunks(self.embedding_dim)
+            .map(|centroid| scorer.score(centroid))
+            .collect();
+        let mut lists: Vec<usize> = (0..scores.len()).collect();
+        lists.sort_by(|&a, &b| scores[b].total_cmp(&scores[a]));
+        lists
+    }
+
+    pub(crate) fn insert(&mut self, index: usize, vector: &[Float]) {
+        let list = self.ranked_lists(vector)[0];
+        self.lists[list].push(index);
+        if index == self.assignments.len() {
+            self.assignments.push(list);
+        } else {
+            self.assignments[index] = list;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) {
+        if let Some(&list) = self.assignments.get(index) {
+            self.lists[list].retain(|&i| i != index);
+        }
+    }
+
+    /// Re-assigns every row of the matrix, e.g. after deletes shifted indices
+    pub(crate) fn rebuild(&mut self, matrix: &Matrix) {
+        self.lists = vec![Vec::new(); self.centroids.len() / self.embedding_dim];
+        self.assignments.clear();
+        for (index, vector) in matrix.chunks(self.embedding_dim).enumerate() {
+            self.insert(index, vector);
+        }
+    }
+
+    /// Storage indices in the `nprobe` partitions nearest to the query, sorted
+    pub(crate) fn candidates(&self, query: &[Float], nprobe: usize) -> Vec<usize> {
+        let mut candidates: Vec<usize> = self
+            .ranked_lists(query)
+            .into_iter()
+            .take(nprobe.max(1))
+            .flat_map(|list| self.lists[list].iter().copied())
+            .collect();
+        candidates.sort_unstable();
+        candidates
+    }
+}
@@ -4,14 +4,40 @@
 #![warn(missing_docs)]
 #![forbid(unsafe_code)]
 
-use anyhow::Result;
 use base64::{engine::general_purpose, Engine as _};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "parallel")]
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fs;
-use std::path::PathBuf;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{self, AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "compression")]
+mod compress;
+mod concurrent;
+mod csv;
+mod error;
+mod field_index;
+mod ivf;
+mod lsh;
+mod matrix;
+mod quant;
+mod sharded;
+mod wal;
+
+pub use concurrent::ConcurrentNanoVectorDB;
+pub use error::NanoVectorDbError;
+use error::Result;
+use matrix::Matrix;
+pub use quant::QuantParams;
+pub use sharded::ShardedNanoVectorDB;
 
 /// Constants used for special field names
 pub mod constants {
@@ -19,10 +45,228 @@ pub mod constants {
     pub const F_ID: &str = "__id__";
     /// Similarity metrics field name
     pub const F_METRICS: &str = "__metrics__";
+    /// Stored vector field name (only present in full query results)
+    pub const F_VECTOR: &str = "__vector__";
+    /// Write time field name, see [`NanoVectorDB::with_timestamps`](crate::NanoVectorDB::with_timestamps)
+    pub const F_TIMESTAMP: &str = "__timestamp__";
 }
 
 type Float = f32;
 
+/// Milliseconds since the Unix epoch, see [`NanoVectorDB::with_timestamps`]
+pub type Timestamp = u64;
+
+/// Default minimum norm a vector must exceed to be normalized (`√f32::EPSILON`)
+pub const DEFAULT_NORM_EPSILON: Float = 3.452_669_8e-4;
+
+/// Strategy used to assign ids to records upserted with an empty `id`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdStrategy {
+    /// Ids are supplied by the caller and stored as-is
+    #[default]
+    Manual,
+    /// Generate a random UUID (v4) for each record with an empty id
+    Uuid,
+    /// Derive the id from a hash of the vector, so identical vectors share an id
+    ContentHash,
+}
+
+impl IdStrategy {
+    /// UUIDs are drawn from `rng` if given, else from the OS
+    fn generate(&self, vector: &[Float], rng: Option<&mut StdRng>) -> Option<String> {
+        match self {
+            IdStrategy::Manual => None,
+            IdStrategy::Uuid => Some(match rng {
+                Some(rng) => uuid::Builder::from_random_bytes(rng.random())
+                    .into_uuid()
+                    .to_string(),
+                None => uuid::Uuid::new_v4().to_string(),
+            }),
+            IdStrategy::ContentHash => Some(format!("{:016x}", content_hash(vector))),
+        }
+    }
+}
+
+/// FNV-1a offset basis, the hash of no bytes
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Continues an FNV-1a hash over `bytes`
+fn fnv1a(hash: u64, bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(hash, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// FNV-1a hash over the little-endian bytes of a vector
+fn content_hash(vector: &[Float]) -> u64 {
+    fnv1a(FNV_OFFSET, vector.iter().flat_map(|x| x.to_le_bytes()))
+}
+
+/// Metric used to compare vectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Metric {
+    /// Cosine similarity; vectors are normalized on upsert (higher is better)
+    #[default]
+    Cosine,
+    /// Raw dot product on unnormalized vectors (higher is better)
+    Dot,
+    /// Euclidean distance on unnormalized vectors (lower is better)
+    L2,
+    /// Manhattan (L1) distance on unnormalized vectors (lower is better)
+    Manhattan,
+    /// Pearson correlation; vectors are mean-centered, then normalized on
+    /// upsert, so a constant offset across dimensions is ignored (higher is
+    /// better)
+    Pearson,
+}
+
+impl Metric {
+    /// Whether the metric is a distance, where lower scores are better
+    pub fn is_distance(&self) -> bool {
+        matches!(self, Metric::L2 | Metric::Manhattan)
+    }
+
+    fn normalizes(&self) -> bool {
+        matches!(self, Metric::Cosine | Metric::Pearson)
+    }
+
+    /// Normalizes a vector as the metric stores it, mean-centering it first
+    /// under [`Metric::Pearson`]
+    fn normalize(&self, vector: &[Float], epsilon: Float) -> Result<Vec<Float>> {
+        match self {
+            Metric::Pearson => {
+                let mean = vector.iter().sum::<Float>() / vector.len().max(1) as Float;
+                let centered: Vec<Float> = vector.iter().map(|x| x - mean).collect();
+                normalize_with_epsilon(&centered, epsilon)
+            }
+            _ => normalize_with_epsilon(vector, epsilon),
+        }
+    }
+}
+
+/// How the vector matrix grows when inserts outgrow its capacity
+///
+/// Only applies to an unsegmented matrix; a segmented one (see
+/// [`NanoVectorDB::with_segment_bytes`]) already grows one segment at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatrixGrowth {
+    /// Double the capacity, like `Vec`: amortized O(1) inserts, but the
+    /// allocation can overshoot the data by up to 2x
+    #[default]
+    Doubling,
+    /// Grow by room for this many more vectors at a time, bounding the
+    /// overshoot at the cost of more frequent reallocation
+    Chunked(usize),
+}
+
+/// Transform applied to the reported score of distance metrics
+///
+/// Ranking always uses the raw distance; only the `__metrics__` value in
+/// results is transformed. Similarity metrics are never transformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ScoreTransform {
+    /// Report the raw distance
+    #[default]
+    None,
+    /// Report `1 / (1 + d)`, in (0, 1]
+    Reciprocal,
+    /// Report `exp(-d)`, in (0, 1]
+    Exp,
+}
+
+impl ScoreTransform {
+    fn apply(&self, distance: Float) -> Float {
+        match self {
+            ScoreTransform::None => distance,
+            ScoreTransform::Reciprocal => 1.0 / (1.0 + distance),
+            ScoreTransform::Exp => (-distance).exp(),
+        }
+    }
+}
+
+/// How [`NanoVectorDB::query_multi`] combines a record's scores against
+/// several query vectors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QueryAgg {
+    /// The best score, so matching any one query vector is enough
+    #[default]
+    Max,
+    /// The mean score, favoring records close to all query vectors
+    Mean,
+}
+
+/// JSON type a field must have, see [`NanoVectorDB::set_schema`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldType {
+    /// A JSON string
+    String,
+    /// A number without a fractional part, e.g. `2024`
+    Integer,
+    /// Any JSON number, integer or not
+    Number,
+    /// `true` or `false`
+    Bool,
+    /// A JSON array, of any elements
+    Array,
+    /// A JSON object
+    Object,
+}
+
+impl FieldType {
+    fn matches(&self, value: &serde_json::Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Number => value.is_number(),
+            FieldType::Bool => value.is_boolean(),
+            FieldType::Array => value.is_array(),
+            FieldType::Object => value.is_object(),
+        }
+    }
+}
+
+/// Which end of the ranking [`NanoVectorDB::query_ordered`] returns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Order {
+    /// The nearest records first, like [`NanoVectorDB::query`]
+    #[default]
+    Best,
+    /// The farthest records first, e.g. the most anti-correlated under cosine
+    Worst,
+}
+
+/// Output format of [`NanoVectorDB::query_to_writer`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResultFormat {
+    /// One JSON object per line, shaped like a [`NanoVectorDB::query`] result
+    #[default]
+    JsonLines,
+    /// A header row, then one row per result: the id, the score, and a column
+    /// per field
+    Csv,
+}
+
+/// Where [`NanoVectorDB::import_csv`] finds each part of a record, by column
+/// header
+#[derive(Debug, Clone)]
+pub struct ColumnMap {
+    /// Column holding the record ids; `None` assigns ids with the [`IdStrategy`]
+    pub id_col: Option<String>,
+    /// Column(s) holding the embedding
+    pub vector: VectorColumns,
+    /// Columns kept as fields; empty keeps every column besides the id and vector
+    pub field_cols: Vec<String>,
+}
+
+/// How the embedding is laid out in a CSV, see [`ColumnMap`]
+#[derive(Debug, Clone)]
+pub enum VectorColumns {
+    /// One column holding the embedding as a JSON array, e.g. `"[0.1, 0.2]"`
+    Json(String),
+    /// `embedding_dim` contiguous numeric columns, starting at this one
+    Starting(String),
+}
+
 /// A single vector entry with metadata
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Data {
@@ -30,27 +274,566 @@ pub struct Data {
     #[serde(rename = "__id__")]
     pub id: String,
     /// The vector data (non-normalized)
-    #[serde(skip)]
+    ///
+    /// Never serialized. With the `vector-serde` feature, it is read from a
+    /// `vector` array when deserializing a `Data` from external JSON.
+    #[cfg_attr(not(feature = "vector-serde"), serde(skip))]
+    #[cfg_attr(feature = "vector-serde", serde(default, skip_serializing))]
     pub vector: Vec<Float>,
     /// Additional metadata fields stored with the vector
     #[serde(flatten, skip_serializing_if = "HashMap::is_empty")]
     pub fields: HashMap<String, serde_json::Value>,
+    /// Ranking weight, 1.0 if `None`; see [`NanoVectorDB::set_weight`]
+    ///
+    /// Moved into the database's weights on upsert, so stored records hold
+    /// `None`; read it back with [`NanoVectorDB::weight`]. Never serialized.
+    #[serde(skip)]
+    pub weight: Option<Float>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct DataBase {
     embedding_dim: usize,
     data: Vec<Data>,
+    #[serde(
+        serialize_with = "base64_bytes::serialize_matrix",
+        deserialize_with = "base64_bytes::deserialize_matrix"
+    )]
+    matrix: Matrix,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    additional_data: HashMap<String, serde_json::Value>,
+    /// Per-record ranking weights, parallel to `data`; empty while all are 1.0
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    weights: Vec<Float>,
+    /// Pre-normalization L2 norms, parallel to `data`; `None` where unknown
+    /// (records loaded from files written before norms were stored)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    norms: Vec<Option<Float>>,
+    /// Extra embedding spaces by field name, see [`NanoVectorDB::upsert_named_vectors`]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    named_vectors: HashMap<String, NamedMatrix>,
+    /// Storage indices of soft-deleted records, see [`NanoVectorDB::with_soft_delete`]
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    tombstones: BTreeSet<usize>,
+}
+
+/// Vectors of one named embedding space, with rows keyed by record id
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "StoredNamedMatrix")]
+struct NamedMatrix {
+    embedding_dim: usize,
+    ids: Vec<String>,
     #[serde(with = "base64_bytes")]
     matrix: Vec<Float>,
-    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    /// Row of every id in `ids`, rebuilt on load
+    #[serde(skip)]
+    rows: HashMap<String, usize>,
+}
+
+/// A [`NamedMatrix`] as stored, without its row index
+#[derive(Deserialize)]
+struct StoredNamedMatrix {
+    embedding_dim: usize,
+    ids: Vec<String>,
+    #[serde(with = "base64_bytes")]
+    matrix: Vec<Float>,
+}
+
+impl From<StoredNamedMatrix> for NamedMatrix {
+    fn from(stored: StoredNamedMatrix) -> Self {
+        let mut named = Self {
+            embedding_dim: stored.embedding_dim,
+            ids: stored.ids,
+            matrix: stored.matrix,
+            rows: HashMap::new(),
+        };
+        named.index_rows();
+        named
+    }
+}
+
+impl NamedMatrix {
+    fn new(embedding_dim: usize) -> Self {
+        Self {
+            embedding_dim,
+            ids: Vec::new(),
+            matrix: Vec::new(),
+            rows: HashMap::new(),
+        }
+    }
+
+    fn index_rows(&mut self) {
+        self.rows = self
+            .ids
+            .iter()
+            .enumerate()
+            .map(|(row, id)| (id.clone(), row))
+            .collect();
+    }
+
+    /// Get the vector stored for `id`
+    fn get(&self, id: &str) -> Option<&[Float]> {
+        let row = *self.rows.get(id)?;
+        self.matrix
+            .get(row * self.embedding_dim..(row + 1) * self.embedding_dim)
+    }
+
+    /// Stores the vector of `id`, replacing any it already has
+    fn set(&mut self, id: String, vector: &[Float]) {
+        let dim = self.embedding_dim;
+        match self.rows.get(&id) {
+            Some(&row) => self.matrix[row * dim..(row + 1) * dim].copy_from_slice(vector),
+            None => {
+                self.rows.insert(id.clone(), self.ids.len());
+                self.ids.push(id);
+                self.matrix.extend_from_slice(vector);
+            }
+        }
+    }
+
+    /// Keeps only the rows whose id satisfies `keep`, compacting the matrix
+    fn retain_ids(&mut self, keep: impl Fn(&str) -> bool) {
+        let dim = self.embedding_dim;
+        let mut kept = 0;
+        for index in 0..self.ids.len() {
+            if !keep(&self.ids[index]) {
+                continue;
+            }
+            if index != kept {
+                self.ids.swap(index, kept);
+                self.matrix
+                    .copy_within(index * dim..(index + 1) * dim, kept * dim);
+            }
+            kept += 1;
+        }
+        self.ids.truncate(kept);
+        self.matrix.truncate(kept * dim);
+        self.index_rows();
+    }
+}
+
+impl DataBase {
+    fn empty(embedding_dim: usize) -> Self {
+        Self {
+            embedding_dim,
+            data: Vec::new(),
+            matrix: Matrix::default(),
+            additional_data: HashMap::new(),
+            weights: Vec::new(),
+            norms: Vec::new(),
+            named_vectors: HashMap::new(),
+            tombstones: BTreeSet::new(),
+        }
+    }
+
+    /// Reads and validates a storage file
+    fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        let mut db: DataBase = serde_json::from_str(&contents)?;
+        db.check_matrix_len()?;
+        decompress_fields(&mut db.data)?;
+        Ok(db)
+    }
+
+    /// Reads a storage file, dropping whatever does not fit the consistent prefix
+    /// of records and matrix rows
+    fn load_repaired(path: &Path) -> Result<(Self, RepairReport)> {
+        let contents = fs::read_to_string(path)?;
+        let mut db: DataBase = serde_json::from_str(&contents)?;
+        decompress_fields(&mut db.data)?;
+        if db.embedding_dim == 0 {
+            return Err(NanoVectorDbError::Corrupt(
+                "Cannot repair a storage file with zero embedding dimension".to_string(),
+            ));
+        }
+
+        let kept = db.data.len().min(db.matrix.len() / db.embedding_dim);
+        let report = RepairReport {
+            truncated_values: db.matrix.len() - kept * db.embedding_dim,
+            dropped_ids: db.data.drain(kept..).map(|d| d.id).collect(),
+            ..Default::default()
+        };
+        db.matrix.truncate(kept * db.embedding_dim);
+        if !db.weights.is_empty() {
+            db.weights.resize(kept, 1.0);
+        }
+        db.norms.truncate(kept);
+        db.tombstones.retain(|&index| index < kept);
+        db.retain_named_vectors();
+        Ok((db, report))
+    }
+
+    fn check_matrix_len(&self) -> Result<()> {
+        let expected_len = self.data.len() * self.embedding_dim;
+        if self.matrix.len() != expected_len {
+            return Err(NanoVectorDbError::MatrixSizeMismatch {
+                expected: expected_len,
+                got: self.matrix.len(),
+            });
+        }
+        if !self.weights.is_empty() && self.weights.len() != self.data.len() {
+            return Err(NanoVectorDbError::Corrupt(format!(
+                "Weights size mismatch: expected {}, got {}",
+                self.data.len(),
+                self.weights.len()
+            )));
+        }
+        if self.norms.len() > self.data.len() {
+            return Err(NanoVectorDbError::Corrupt(format!(
+                "Norms size mismatch: expected at most {}, got {}",
+                self.data.len(),
+                self.norms.len()
+            )));
+        }
+        if let Some(&index) = self.tombstones.last().filter(|&&i| i >= self.data.len()) {
+            return Err(NanoVectorDbError::Corrupt(format!(
+                "Tombstone {} out of bounds for {} records",
+                index,
+                self.data.len()
+            )));
+        }
+        for (field, named) in &self.named_vectors {
+            if named.matrix.len() != named.ids.len() * named.embedding_dim {
+                return Err(NanoVectorDbError::Corrupt(format!(
+                    "Matrix size mismatch for vector field \"{}\": expected {}, got {}",
+                    field,
+                    named.ids.len() * named.embedding_dim,
+                    named.matrix.len()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops named vectors of records that no longer exist
+    fn retain_named_vectors(&mut self) {
+        if self.named_vectors.is_empty() {
+            return;
+        }
+        let ids: HashSet<&str> = self.data.iter().map(|d| d.id.as_str()).collect();
+        for named in self.named_vectors.values_mut() {
+            named.retain_ids(|id| ids.contains(id));
+        }
+    }
+}
+
+/// Borrowed view of the storage, serialized exactly like [`DataBase`]
+#[derive(Serialize)]
+struct DataBaseView<'a, D = &'a Data> {
+    embedding_dim: usize,
+    data: Vec<D>,
+    #[serde(serialize_with = "base64_bytes::serialize_matrix")]
+    matrix: &'a Matrix,
+    #[serde(skip_serializing_if = "is_empty_map")]
+    additional_data: &'a HashMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "<[Float]>::is_empty")]
+    weights: &'a [Float],
+    #[serde(skip_serializing_if = "<[Option<Float>]>::is_empty")]
+    norms: &'a [Option<Float>],
+    #[serde(skip_serializing_if = "is_empty_named")]
+    named_vectors: &'a HashMap<String, NamedMatrix>,
+    #[serde(skip_serializing_if = "is_empty_set")]
+    tombstones: &'a BTreeSet<usize>,
+}
+
+/// How [`NanoVectorDB::merge`] handles records whose id already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CollisionPolicy {
+    /// Keep the existing record and skip the incoming one
+    #[default]
+    Skip,
+    /// Replace the existing record with the incoming one
+    Overwrite,
+    /// Fail the whole merge without modifying anything
+    Error,
+}
+
+/// Outcome of a bulk import such as [`NanoVectorDB::merge`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpsertReport {
+    /// Ids of existing records that were replaced
+    pub updated: Vec<String>,
+    /// Ids of newly inserted records
+    pub inserted: Vec<String>,
+    /// Ids of incoming records that were skipped
+    pub skipped: Vec<String>,
+}
+
+/// A single query hit with its score kept as a native float
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryResult {
+    /// Id of the matching record
+    pub id: String,
+    /// Score as computed during ranking (after any [`ScoreTransform`])
+    pub score: Float,
+    /// Metadata fields of the matching record
+    pub fields: HashMap<String, serde_json::Value>,
+    /// Storage index of the matching record when the query ran, for
+    /// [`NanoVectorDB::vector_at`] and arrays kept parallel to the storage
+    ///
+    /// Like every storage index, it is invalidated by the next delete or
+    /// compaction, which shift later records down. Equality compares it too.
+    pub index: usize,
+}
+
+impl QueryResult {
+    /// Returns a string field cut to at most `max_chars` characters, for display
+    ///
+    /// Truncation happens on `char` boundaries, so multibyte text is never split
+    /// mid-codepoint. Returns `None` if the field is missing or not a string.
+    pub fn field_truncated(&self, key: &str, max_chars: usize) -> Option<String> {
+        truncate_chars(self.fields.get(key)?, max_chars)
+    }
+}
+
+/// Cosine, Euclidean and dot-product comparisons of a query with one hit,
+/// independent of the ranking metric
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreDiagnostics {
+    /// Cosine similarity (0 if either vector has zero norm)
+    pub cosine: Float,
+    /// Euclidean distance
+    pub l2: Float,
+    /// Dot product
+    pub dot: Float,
+}
+
+impl ScoreDiagnostics {
+    /// Compares a query with a stored vector
+    fn compute(query: &[Float], vector: &[Float]) -> Self {
+        let dot = dot_product_f64_acc(vector, query);
+        let norms = vector_norm(query) * vector_norm(vector);
+        Self {
+            cosine: if norms > 0.0 { dot / norms } else { 0.0 },
+            l2: l2_distance(query, vector),
+            dot,
+        }
+    }
+}
+
+/// A single query hit borrowing its record from the database
+///
+/// Unlike the map returned by [`NanoVectorDB::query`], fields are not cloned;
+/// callers copy only what they need.
+#[derive(Debug, Clone, Copy)]
+pub struct QueryHit<'a> {
+    /// The matching record
+    pub data: &'a Data,
+    /// Score as computed during ranking (after any [`ScoreTransform`])
+    pub score: Float,
+}
+
+impl<'a> QueryHit<'a> {
+    /// Id of the matching record
+    pub fn id(&self) -> &'a str {
+        &self.data.id
+    }
+
+    /// Looks up a metadata field of the matching record
+    pub fn field(&self, key: &str) -> Option<&'a serde_json::Value> {
+        self.data.fields.get(key)
+    }
+
+    /// Returns a string field cut to at most `max_chars` characters, like
+    /// [`QueryResult::field_truncated`]
+    pub fn field_truncated(&self, key: &str, max_chars: usize) -> Option<String> {
+        truncate_chars(self.field(key)?, max_chars)
+    }
+}
+
+/// Cuts a JSON string to at most `max_chars` characters
+fn truncate_chars(value: &serde_json::Value, max_chars: usize) -> Option<String> {
+    Some(value.as_str()?.chars().take(max_chars).collect())
+}
+
+/// What [`NanoVectorDB::new_repaired`] discarded to make a storage file consistent
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Number of trailing matrix values without a matching record
+    pub truncated_values: usize,
+    /// Ids of trailing records without a complete matrix row
+    pub dropped_ids: Vec<String>,
+    /// Parse error of the storage file, if it could not be read at all
+    pub unreadable: Option<String>,
+    /// Whether the records were recovered from the `.bak` copy written by
+    /// [`NanoVectorDB::with_backup`]
+    pub used_backup: bool,
+}
+
+impl RepairReport {
+    /// Whether the file was already consistent
+    pub fn is_clean(&self) -> bool {
+        self.truncated_values == 0 && self.dropped_ids.is_empty() && self.unreadable.is_none()
+    }
+}
+
+/// The records of a storage file without its vectors, opened with
+/// [`NanoVectorDB::open_metadata_only`]
+///
+/// Has no query methods: the matrix is skipped while parsing, never decoded.
+/// Fields stored in a sidecar file (see [`NanoVectorDB::with_fields_sidecar`])
+/// are not read.
+#[derive(Debug, Deserialize)]
+pub struct MetadataView {
+    embedding_dim: usize,
+    data: Vec<Data>,
+    #[serde(default)]
     additional_data: HashMap<String, serde_json::Value>,
 }
 
+impl MetadataView {
+    /// Get the dimensionality of the stored vectors
+    pub fn embedding_dim(&self) -> usize {
+        self.embedding_dim
+    }
+
+    /// Get the number of records
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Check if there are no records
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Get the record ids in storage order
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.data.iter().map(|d| d.id.as_str())
+    }
+
+    /// Get the records in storage order; their `vector`s are empty
+    pub fn records(&self) -> &[Data] {
+        &self.data
+    }
+
+    /// Get the fields of the record with `id`
+    pub fn fields(&self, id: &str) -> Option<&HashMap<String, serde_json::Value>> {
+        self.data.iter().find(|d| d.id == id).map(|d| &d.fields)
+    }
+
+    /// Get the additional metadata stored in the file
+    pub fn additional_data(&self) -> &HashMap<String, serde_json::Value> {
+        &self.additional_data
+    }
+}
+
+/// Near-duplicate records found by [`NanoVectorDB::duplication_report`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DuplicationStats {
+    /// Number of records compared
+    pub total: usize,
+    /// Number of records with at least one other record within the threshold
+    pub duplicated: usize,
+    /// Ids of records linked by near-duplicate pairs, transitively; each cluster
+    /// has at least two records and lists them in storage order
+    pub clusters: Vec<Vec<String>>,
+}
+
+/// Path of the backup copy kept next to a storage file
+fn backup_path(storage_file: &Path) -> PathBuf {
+    let mut path = storage_file.to_path_buf().into_os_string();
+    path.push(".bak");
+    PathBuf::from(path)
+}
+
+/// Runs `op` up to `attempts` times (at least once) while it fails with an I/O
+/// error, sleeping `backoff`, then twice that, and so on between attempts
+fn retry_io<T>(attempts: usize, backoff: Duration, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut delay = backoff;
+    for _ in 1..attempts {
+        match op() {
+            Err(NanoVectorDbError::Io(_)) => {
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+            }
+            result => return result,
+        }
+    }
+    op()
+}
+
+/// Identifies a snapshot written by [`NanoVectorDB::snapshot`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotId {
+    /// File the snapshot was written to
+    pub path: PathBuf,
+    /// Number of records captured in the snapshot
+    pub len: usize,
+}
+
+/// Storage view written in sidecar mode, with record fields left out
+#[derive(Serialize)]
+struct DataBaseIdsOnly<'a> {
+    embedding_dim: usize,
+    data: Vec<IdOnly<'a>>,
+    #[serde(serialize_with = "base64_bytes::serialize_matrix")]
+    matrix: &'a Matrix,
+    #[serde(skip_serializing_if = "is_empty_map")]
+    additional_data: &'a HashMap<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "<[Float]>::is_empty")]
+    weights: &'a [Float],
+    #[serde(skip_serializing_if = "<[Option<Float>]>::is_empty")]
+    norms: &'a [Option<Float>],
+    #[serde(skip_serializing_if = "is_empty_named")]
+    named_vectors: &'a HashMap<String, NamedMatrix>,
+    #[serde(skip_serializing_if = "is_empty_set")]
+    tombstones: &'a BTreeSet<usize>,
+}
+
+#[derive(Serialize)]
+struct IdOnly<'a> {
+    #[serde(rename = "__id__")]
+    id: &'a str,
+}
+
+/// Restores the string fields compressed by
+/// [`NanoVectorDB::with_field_compression`]; without the `compression` feature
+/// they stay in their stored form
+fn decompress_fields(data: &mut [Data]) -> Result<()> {
+    #[cfg(feature = "compression")]
+    compress::decompress_fields(data)?;
+    #[cfg(not(feature = "compression"))]
+    let _ = data;
+    Ok(())
+}
+
+fn is_empty_map(map: &&HashMap<String, serde_json::Value>) -> bool {
+    map.is_empty()
+}
+
+fn is_empty_named(map: &&HashMap<String, NamedMatrix>) -> bool {
+    map.is_empty()
+}
+
+fn is_empty_set(set: &&BTreeSet<usize>) -> bool {
+    set.is_empty()
+}
+
+/// Record fields stored out-of-line, next to the main storage file
+#[derive(Debug)]
+struct FieldsSidecar {
+    path: PathBuf,
+    /// Whether the fields are still only on disk, not yet merged into `storage.data`
+    pending: bool,
+    records: OnceLock<HashMap<String, Data>>,
+}
+
+impl FieldsSidecar {
+    fn read(&self) -> Result<HashMap<String, Data>> {
+        let contents = fs::read_to_string(&self.path)?;
+        let mut records: Vec<Data> = serde_json::from_str(&contents)?;
+        decompress_fields(&mut records)?;
+        Ok(records.into_iter().map(|d| (d.id.clone(), d)).collect())
+    }
+}
+
 mod base64_bytes {
     use super::*;
+    use base64::write::EncoderStringWriter;
     use bytemuck::cast_slice;
     use serde::{Deserializer, Serializer};
+    use std::io::Write;
 
     pub fn serialize<S: Serializer>(vec: &[Float], serializer: S) -> Result<S::Ok, S::Error> {
         let bytes = cast_slice(vec);
@@ -58,237 +841,4723 @@ mod base64_bytes {
         serializer.serialize_str(&b64)
     }
 
+    /// Encodes the segments as one base64 string, without first copying them
+    /// into a contiguous buffer
+    pub fn serialize_matrix<S: Serializer>(
+        matrix: &Matrix,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut writer = EncoderStringWriter::new(&general_purpose::STANDARD);
+        for segment in matrix.segments() {
+            writer
+                .write_all(cast_slice(segment))
+                .map_err(serde::ser::Error::custom)?;
+        }
+        serializer.serialize_str(&writer.into_inner())
+    }
+
+    /// Matrix encodings accepted on load
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Encoded {
+        Base64(String),
+        /// Legacy per-row layout written by older tools; flattened on load and
+        /// rewritten as base64 on the next save
+        Nested(Vec<Vec<Float>>),
+    }
+
+    /// Base64 characters decoded at a time, a whole number of 4-character
+    /// groups that decode to whole floats
+    const DECODE_CHARS: usize = 1 << 16;
+
+    /// Decodes piece by piece into an exactly sized `Vec`, without first
+    /// decoding all the bytes
     pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Float>, D::Error> {
-        let s = String::deserialize(deserializer)?;
-        let bytes = general_purpose::STANDARD
-            .decode(s)
-            .map_err(serde::de::Error::custom)?;
-        Ok(bytes
-            .chunks_exact(4)
-            .map(|chunk| Float::from_le_bytes(chunk.try_into().unwrap()))
-            .collect())
+        let s = match Encoded::deserialize(deserializer)? {
+            Encoded::Base64(s) => s,
+            Encoded::Nested(rows) => return Ok(rows.concat()),
+        };
+        let padding = s.bytes().rev().take_while(|&b| b == b'=').count();
+        let mut values = Vec::with_capacity((s.len() / 4 * 3).saturating_sub(padding) / 4);
+        let mut bytes = Vec::with_capacity(DECODE_CHARS / 4 * 3);
+        for piece in s.as_bytes().chunks(DECODE_CHARS) {
+            bytes.clear();
+            general_purpose::STANDARD
+                .decode_vec(piece, &mut bytes)
+                .map_err(serde::de::Error::custom)?;
+            values.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|chunk| Float::from_le_bytes(chunk.try_into().unwrap())),
+            );
+        }
+        Ok(values)
+    }
+
+    pub fn deserialize_matrix<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Matrix, D::Error> {
+        deserialize(deserializer).map(Matrix::from)
+    }
+}
+
+/// Callback receiving the ids affected by a write
+pub type WriteHook = Box<dyn Fn(&[String]) + Send + Sync>;
+
+/// Write hooks registered with [`NanoVectorDB::on_upsert`] and
+/// [`NanoVectorDB::on_delete`]
+#[derive(Default)]
+struct Hooks {
+    on_upsert: Option<WriteHook>,
+    on_delete: Option<WriteHook>,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("on_upsert", &self.on_upsert.is_some())
+            .field("on_delete", &self.on_delete.is_some())
+            .finish()
     }
 }
 
 /// Main vector database struct
+///
+/// Records keep their insertion order: new records are appended (or placed
+/// with [`insert_at`](NanoVectorDB::insert_at)), upserting an existing id
+/// updates it in place, and deletes shift the later records down without
+/// reordering them. Storage indices follow this order, and it survives `save`
+/// and reloading.
 #[derive(Debug)]
 pub struct NanoVectorDB {
-    /// Dimensionality of stored vectors
-    pub embedding_dim: usize,
-    /// Distance metric used for similarity searches
-    pub metric: String,
+    embedding_dim: usize,
+    metric: Metric,
     storage_file: PathBuf,
     storage: DataBase,
+    id_strategy: IdStrategy,
+    seed: Option<u64>,
+    /// Drawn from `seed` for generated UUIDs, see [`NanoVectorDB::with_seed`]
+    rng: Option<StdRng>,
+    score_transform: ScoreTransform,
+    angular_degrees: bool,
+    growth: MatrixGrowth,
+    score_epsilon: Float,
+    sidecar: Option<FieldsSidecar>,
+    wal: Option<wal::WriteAheadLog>,
+    /// Tombstone ratio that triggers compaction, see [`NanoVectorDB::with_soft_delete`]
+    soft_delete: Option<Float>,
+    timestamps: bool,
+    lsh: Option<lsh::LshIndex>,
+    ivf: Option<ivf::IvfIndex>,
+    ivf_nprobe: usize,
+    quant: Option<quant::QuantizedMatrix>,
+    field_indexes: HashMap<String, field_index::FieldIndex>,
+    /// Storage index of every id, see [`NanoVectorDB::build_id_index`]
+    id_index: Option<HashMap<String, usize>>,
+    id_field: String,
+    metrics_field: String,
+    create_dirs: bool,
+    backup: bool,
+    pretty: bool,
+    filter_first: bool,
+    pruned_scoring: bool,
+    /// Whether every stored row is known to be unit length, which
+    /// [`PrunedScorer`] bounds rely on
+    unit_rows: bool,
+    rerank: bool,
+    rerank_budget: Option<usize>,
+    boundary_ties: bool,
+    normalize: bool,
+    norm_epsilon: Float,
+    hooks: Hooks,
+    f64_accumulation: bool,
+    query_cache: Option<Mutex<QueryCache>>,
+    parallel_limit: Option<ParallelLimit>,
+    /// Field types checked on upsert, see [`NanoVectorDB::set_schema`]
+    schema: HashMap<String, FieldType>,
+    schema_required: bool,
+    /// Duration of the last ranked scan in nanoseconds, 0 before any
+    last_query_nanos: AtomicU64,
+    /// Byte length above which string fields are compressed on save
+    #[cfg(feature = "compression")]
+    field_compression: Option<usize>,
+    /// Sum of the record hashes, computed on first use and then kept up to date
+    content_hash: OnceLock<u64>,
+}
+
+/// Hit and miss counts of the query cache, see [`NanoVectorDB::with_query_cache`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    /// Queries answered from the cache
+    pub hits: u64,
+    /// Cacheable queries that had to scan
+    pub misses: u64,
+    /// Results currently cached
+    pub len: usize,
+    /// Maximum number of cached results
+    pub capacity: usize,
+}
+
+/// Identifies a cacheable scan: the prepared query and its parameters, compared
+/// bitwise
+#[derive(Debug, PartialEq, Eq)]
+struct CacheKey {
+    query: Vec<u32>,
+    top_k: usize,
+    threshold: u32,
+}
+
+impl CacheKey {
+    fn new(query_norm: &[Float], top_k: usize, threshold: Float) -> Self {
+        Self {
+            query: query_norm.iter().map(|x| x.to_bits()).collect(),
+            top_k,
+            threshold: threshold.to_bits(),
+        }
+    }
+}
+
+/// Caps how many scans run on the Rayon pool at once, see
+/// [`NanoVectorDB::with_max_parallel_queries`]
+#[derive(Debug)]
+struct ParallelLimit {
+    max: usize,
+    running: AtomicUsize,
+}
+
+impl ParallelLimit {
+    /// Takes a slot if one is free, without waiting
+    fn try_acquire(&self) -> Option<ParallelPermit<'_>> {
+        self.running
+            .fetch_update(atomic::Ordering::AcqRel, atomic::Ordering::Acquire, |n| {
+                (n < self.max).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| ParallelPermit(&self.running))
+    }
+}
+
+/// A slot of a [`ParallelLimit`], given back on drop
+struct ParallelPermit<'a>(&'a AtomicUsize);
+
+impl Drop for ParallelPermit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::AcqRel);
+    }
+}
+
+/// Least-recently-used cache of scan results, most recent last
+#[derive(Debug)]
+struct QueryCache {
+    entries: VecDeque<(CacheKey, Vec<ScoredIndex>)>,
+    stats: CacheStats,
+}
+
+impl QueryCache {
+    fn get(&mut self, key: &CacheKey) -> Option<Vec<ScoredIndex>> {
+        let Some(pos) = self.entries.iter().position(|(k, _)| k == key) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        let entry = self.entries.remove(pos)?;
+        let scored = entry.1.clone();
+        self.entries.push_back(entry);
+        Some(scored)
+    }
+
+    fn insert(&mut self, key: CacheKey, scored: Vec<ScoredIndex>) {
+        if self.stats.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.stats.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back((key, scored));
+    }
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct ScoredIndex {
     score: Float,
     index: usize,
 }
 
-impl Eq for ScoredIndex {}
+impl Eq for ScoredIndex {}
+
+impl PartialOrd for ScoredIndex {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScoredIndex {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.score.partial_cmp(&self.score).unwrap_or_else(|| {
+            if self.score.is_nan() && other.score.is_nan() {
+                Ordering::Equal
+            } else if self.score.is_nan() {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            }
+        })
+    }
+}
+
+/// Reorders runs of best-first sorted results whose scores are within `epsilon`
+/// of the run's first score by storage index, for reproducible tie ordering
+fn order_ties(sorted: &mut [ScoredIndex], epsilon: Float) {
+    let mut start = 0;
+    while start < sorted.len() {
+        let leader = sorted[start].score;
+        let end = start
+            + sorted[start..]
+                .iter()
+                .take_while(|si| leader - si.score <= epsilon)
+                .count();
+        sorted[start..end].sort_by_key(|si| si.index);
+        start = end.max(start + 1);
+    }
+}
+
+/// The best `capacity` scores pushed so far, plus the ones evicted within
+/// `epsilon` of the worst of them
+///
+/// Keeping the near-ties lets [`into_sorted`](Self::into_sorted) settle the last
+/// places by storage index, like [`order_ties`], instead of by scan order.
+#[derive(Debug)]
+struct TopK {
+    /// Min-heap of the best scores, worst on top
+    heap: BinaryHeap<ScoredIndex>,
+    /// Evicted scores that were within `epsilon` of the heap's worst, possibly
+    /// stale until the next prune
+    ties: Vec<ScoredIndex>,
+    /// Length of `ties` that triggers the next prune
+    prune_at: usize,
+    capacity: usize,
+    epsilon: Float,
+}
+
+impl TopK {
+    fn new(capacity: usize, epsilon: Float) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            ties: Vec::new(),
+            prune_at: capacity.max(8),
+            capacity,
+            epsilon,
+        }
+    }
+
+    /// Worst of the best `capacity` scores
+    fn peek(&self) -> Option<&ScoredIndex> {
+        self.heap.peek()
+    }
+
+    /// Number of scores held, not counting near-ties
+    fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Reserves room for `additional` scores, unless already allocated
+    fn reserve_once(&mut self, additional: usize) {
+        if self.heap.capacity() == 0 {
+            self.heap.reserve_exact(additional);
+        }
+    }
+
+    fn push(&mut self, si: ScoredIndex) {
+        self.heap.push(si);
+        if self.heap.len() <= self.capacity {
+            return;
+        }
+        let evicted = self.heap.pop().unwrap();
+        let Some(worst) = self.heap.peek().map(|worst| worst.score) else {
+            return;
+        };
+        if worst - evicted.score <= self.epsilon {
+            self.ties.push(evicted);
+            if self.ties.len() >= self.prune_at {
+                let epsilon = self.epsilon;
+                self.ties.retain(|tie| worst - tie.score <= epsilon);
+                self.prune_at = (2 * self.ties.len()).max(self.capacity).max(8);
+            }
+        }
+    }
+
+    /// Adds everything `other` holds, near-ties included
+    fn merge(self, other: Self) -> Self {
+        // Drain the smaller side into the larger one, so the empty reduce
+        // identity never allocates
+        let (mut into, from) = if self.heap.len() >= other.heap.len() {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        for si in from.into_iter() {
+            into.push(si);
+        }
+        into
+    }
+
+    /// Every score held, near-ties included, in no particular order
+    fn into_iter(self) -> impl Iterator<Item = ScoredIndex> {
+        self.heap.into_iter().chain(self.ties)
+    }
+
+    /// The best `capacity` scores, best first, with near-ties ordered by index
+    fn into_sorted(self) -> Vec<ScoredIndex> {
+        let (capacity, epsilon) = (self.capacity, self.epsilon);
+        let mut sorted = self.heap.into_sorted_vec();
+        if !self.ties.is_empty() {
+            sorted.extend(self.ties);
+            sorted.sort();
+        }
+        order_ties(&mut sorted, epsilon);
+        sorted.truncate(capacity);
+        sorted
+    }
+}
+
+/// Root of `i` in a union-find forest, halving the path on the way
+fn find_root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Folds over the `(index, row)` pairs of a matrix split into `segments`
+///
+/// Every segment but the last must hold the same whole number of rows. With the
+/// `parallel` feature and `parallel` set, rows are folded on Rayon's thread pool
+/// and the per-thread accumulators combined with `reduce`; otherwise a single
+/// serial fold is used and `reduce` is never called.
+fn fold_rows<E, T, I, F, R>(
+    segments: &[&[E]],
+    embedding_dim: usize,
+    parallel: bool,
+    identity: I,
+    fold: F,
+    reduce: R,
+) -> T
+where
+    E: Sync,
+    T: Send,
+    I: Fn() -> T + Send + Sync,
+    F: Fn(T, (usize, &[E])) -> T + Send + Sync,
+    R: Fn(T, T) -> T + Send + Sync,
+{
+    let segment_rows = segments.first().map_or(0, |s| s.len() / embedding_dim);
+    #[cfg(feature = "parallel")]
+    if parallel {
+        return segments
+            .par_iter()
+            .enumerate()
+            .flat_map(|(segment, rows)| {
+                rows.par_chunks(embedding_dim)
+                    .enumerate()
+                    .map(move |(idx, row)| (segment * segment_rows + idx, row))
+            })
+            .fold(&identity, &fold)
+            .reduce(&identity, &reduce);
+    }
+    #[cfg(not(feature = "parallel"))]
+    let _ = (parallel, reduce);
+    segments
+        .iter()
+        .enumerate()
+        .flat_map(|(segment, rows)| {
+            rows.chunks(embedding_dim)
+                .enumerate()
+                .map(move |(idx, row)| (segment * segment_rows + idx, row))
+        })
+        .fold(identity(), fold)
+}
+
+/// Applies `f` to every row of a matrix, in parallel with the `parallel` feature
+fn for_each_row_mut<F>(matrix: &mut Matrix, embedding_dim: usize, f: F)
+where
+    F: Fn(&mut [Float]) + Send + Sync,
+{
+    for segment in matrix.segments_mut() {
+        #[cfg(feature = "parallel")]
+        segment.par_chunks_mut(embedding_dim).for_each(&f);
+        #[cfg(not(feature = "parallel"))]
+        segment.chunks_mut(embedding_dim).for_each(&f);
+    }
+}
+
+/// Encoded size of a result with an empty id and fields: the id length, score
+/// and fields length
+const MIN_RESULT_BYTES: usize = 12;
+
+/// Cursor over a byte slice for decoding binary results
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(NanoVectorDbError::Corrupt(format!(
+                "Unexpected end of input: need {} bytes, have {}",
+                len,
+                self.bytes.len()
+            )));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// How many times `top_k` candidates a quantized scan keeps for
+/// [`NanoVectorDB::with_rerank`] to re-score exactly
+const RERANK_OVERSAMPLE: usize = 4;
+
+/// Number of equal-width score buckets [`NanoVectorDB::score_quantiles`]
+/// counts into; each quantile is accurate to one bucket width
+pub const SCORE_QUANTILE_BUCKETS: usize = 2048;
+
+/// Leading bytes of the [`NanoVectorDB::to_bytes`] format
+const BYTES_MAGIC: &[u8; 4] = b"NVDB";
+
+/// Records and additional data of the [`NanoVectorDB::to_bytes`] format
+#[derive(Deserialize)]
+struct BytesMetadata {
+    data: Vec<Data>,
+    #[serde(default)]
+    additional_data: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    weights: Vec<Float>,
+    #[serde(default)]
+    norms: Vec<Option<Float>>,
+    #[serde(default)]
+    named_vectors: HashMap<String, NamedMatrix>,
+    #[serde(default)]
+    tombstones: BTreeSet<usize>,
+}
+
+type DataFilter = Box<dyn Fn(&Data) -> bool + Send + Sync>;
+
+/// Cosine scorer that abandons a row once it provably cannot reach a floor
+struct PrunedScorer<'a> {
+    query: &'a [Float],
+    /// `rest_norms[b]` is the norm of the query after block `b`
+    rest_norms: Vec<Float>,
+}
+
+impl<'a> PrunedScorer<'a> {
+    /// Dimensions accumulated between bound checks
+    const BLOCK: usize = 64;
+    /// Absorbs rounding so near-unit rows are never wrongly pruned
+    const SLACK: Float = 1e-4;
+    /// Largest deviation from unit length a row may have for the bounds to hold
+    const UNIT_TOLERANCE: Float = 1e-5;
+
+    /// Whether the bounds hold for `vector`
+    fn accepts(vector: &[Float]) -> bool {
+        (vector_norm(vector) - 1.0).abs() <= Self::UNIT_TOLERANCE
+    }
+
+    fn new(query: &'a [Float]) -> Self {
+        let mut rest_norms: Vec<Float> = query
+            .chunks(Self::BLOCK)
+            .rev()
+            .scan(0.0, |rest_sq: &mut Float, block| {
+                let norm = rest_sq.sqrt();
+                *rest_sq += block.iter().map(|x| x * x).sum::<Float>();
+                Some(norm)
+            })
+            .collect();
+        rest_norms.reverse();
+        Self { query, rest_norms }
+    }
+
+    /// Returns the dot product of a unit `vector` with the query, or `None` as
+    /// soon as it is bounded below `floor`
+    fn score(&self, vector: &[Float], floor: Float) -> Option<Float> {
+        let mut dot = 0.0;
+        let mut vector_sq = 0.0;
+        let blocks = vector
+            .chunks(Self::BLOCK)
+            .zip(self.query.chunks(Self::BLOCK));
+        for ((v, q), rest_norm) in blocks.zip(&self.rest_norms) {
+            for (a, b) in v.iter().zip(q) {
+                dot += a * b;
+                vector_sq += a * a;
+            }
+            let bound = dot + (1.0 - vector_sq).max(0.0).sqrt() * rest_norm + Self::SLACK;
+            if bound < floor {
+                return None;
+            }
+        }
+        Some(dot)
+    }
+}
+
+/// Scores stored vectors against a prepared query, where higher is always better
+struct QueryScorer<'a> {
+    metric: Metric,
+    query: &'a [Float],
+    // Precomputed query chunks for SIMD-friendly operations
+    query_chunks: Vec<[Float; 4]>,
+    f64_accumulation: bool,
+}
+
+impl<'a> QueryScorer<'a> {
+    fn new(metric: Metric, query: &'a [Float], f64_accumulation: bool) -> Self {
+        let query_chunks = query
+            .chunks_exact(4)
+            .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
+            .collect();
+        Self {
+            metric,
+            query,
+            query_chunks,
+            f64_accumulation,
+        }
+    }
+
+    /// Distances are negated so that higher is better for every metric
+    fn score(&self, vector: &[Float]) -> Float {
+        match self.metric {
+            Metric::Cosine | Metric::Pearson | Metric::Dot if self.f64_accumulation => {
+                dot_product_f64_acc(vector, self.query)
+            }
+            Metric::Cosine | Metric::Pearson | Metric::Dot => dot_product(
+                vector,
+                &self.query_chunks,
+                &self.query[self.query_chunks.len() * 4..],
+            ),
+            Metric::L2 => -l2_distance(vector, self.query),
+            Metric::Manhattan => -manhattan_distance(vector, self.query),
+        }
+    }
+}
+
+impl NanoVectorDB {
+    /// Creates a new NanoVectorDB instance
+    pub fn new(embedding_dim: usize, storage_file: &str) -> Result<Self> {
+        let storage_file = PathBuf::from(storage_file);
+        let storage = if storage_file.exists() && storage_file.metadata()?.len() > 0 {
+            DataBase::load(&storage_file)?
+        } else {
+            DataBase::empty(embedding_dim)
+        };
+        Ok(Self::from_storage(embedding_dim, storage_file, storage))
+    }
+
+    /// Reads the ids and fields of a storage file without decoding its matrix
+    ///
+    /// Much faster than [`new`](Self::new) for inspecting large files, since the
+    /// base64 matrix is skipped rather than decoded and copied.
+    pub fn open_metadata_only(storage_file: &str) -> Result<MetadataView> {
+        let contents = fs::read_to_string(storage_file)?;
+        let mut view: MetadataView = serde_json::from_str(&contents)?;
+        decompress_fields(&mut view.data)?;
+        Ok(view)
+    }
+
+    /// Creates a NanoVectorDB instance, recovering from a damaged storage file
+    /// instead of failing like [`new`](Self::new)
+    ///
+    /// Extra matrix values past the last record are truncated, and records past
+    /// the last complete matrix row are dropped, so the consistent prefix of a
+    /// partially written file is kept. A file that cannot be parsed at all (e.g.
+    /// truncated mid-write) is replaced by its `.bak` copy if one exists (see
+    /// [`with_backup`](Self::with_backup)), or else by an empty database. The
+    /// returned report says what was discarded; nothing is written back until
+    /// [`save`](Self::save).
+    pub fn new_repaired(embedding_dim: usize, storage_file: &str) -> Result<(Self, RepairReport)> {
+        let storage_file = PathBuf::from(storage_file);
+        let (storage, report) = if storage_file.exists() && storage_file.metadata()?.len() > 0 {
+            match DataBase::load_repaired(&storage_file) {
+                Err(NanoVectorDbError::Deserialize(e)) => {
+                    let backup = backup_path(&storage_file);
+                    let (storage, mut report) = if backup.exists() {
+                        let (storage, report) = DataBase::load_repaired(&backup)?;
+                        (
+                            storage,
+                            RepairReport {
+                                used_backup: true,
+                                ..report
+                            },
+                        )
+                    } else {
+                        (DataBase::empty(embedding_dim), RepairReport::default())
+                    };
+                    report.unreadable = Some(e.to_string());
+                    (storage, report)
+                }
+                loaded => loaded?,
+            }
+        } else {
+            (DataBase::empty(embedding_dim), RepairReport::default())
+        };
+        Ok((
+            Self::from_storage(embedding_dim, storage_file, storage),
+            report,
+        ))
+    }
+
+    fn from_storage(embedding_dim: usize, storage_file: PathBuf, storage: DataBase) -> Self {
+        Self {
+            embedding_dim,
+            metric: Metric::default(),
+            storage_file,
+            storage,
+            id_strategy: IdStrategy::default(),
+            seed: None,
+            rng: None,
+            score_transform: ScoreTransform::default(),
+            angular_degrees: false,
+            growth: MatrixGrowth::default(),
+            score_epsilon: 0.0,
+            sidecar: None,
+            wal: None,
+            soft_delete: None,
+            timestamps: false,
+            lsh: None,
+            ivf: None,
+            ivf_nprobe: 1,
+            quant: None,
+            field_indexes: HashMap::new(),
+            id_index: None,
+            id_field: constants::F_ID.to_string(),
+            metrics_field: constants::F_METRICS.to_string(),
+            create_dirs: true,
+            backup: false,
+            pretty: false,
+            filter_first: false,
+            pruned_scoring: false,
+            unit_rows: false,
+            rerank: false,
+            rerank_budget: None,
+            boundary_ties: false,
+            normalize: true,
+            norm_epsilon: DEFAULT_NORM_EPSILON,
+            hooks: Hooks::default(),
+            query_cache: None,
+            parallel_limit: None,
+            schema: HashMap::new(),
+            schema_required: false,
+            f64_accumulation: false,
+            last_query_nanos: AtomicU64::new(0),
+            #[cfg(feature = "compression")]
+            field_compression: None,
+            content_hash: OnceLock::new(),
+        }
+    }
+
+    /// Creates a NanoVectorDB instance with room for `expected_count` records
+    ///
+    /// The matrix and record storage are reserved exactly up front, so inserting
+    /// up to `expected_count` records in total never reallocates them. Records
+    /// loaded from `storage_file` count towards `expected_count`.
+    pub fn with_capacity(
+        embedding_dim: usize,
+        storage_file: &str,
+        expected_count: usize,
+    ) -> Result<Self> {
+        let mut db = Self::new(embedding_dim, storage_file)?;
+        let additional = expected_count.saturating_sub(db.len());
+        db.storage.data.reserve_exact(additional);
+        let missing_norms = expected_count.saturating_sub(db.storage.norms.len());
+        db.storage.norms.reserve_exact(missing_norms);
+        db.storage.matrix.reserve_exact(additional * embedding_dim);
+        Ok(db)
+    }
+
+    /// Creates a NanoVectorDB instance that scans an int8-quantized copy of the matrix
+    ///
+    /// Queries score each stored vector from its int8 codes, dequantized by the
+    /// row scale, reading a quarter of the bytes of the f32 scan. This is a speed
+    /// option only, not a memory saving: the f32 matrix is still kept as the
+    /// persisted source of truth (and for exact paths such as `query_full`), so
+    /// the codes add a quarter of its size on top. They are rebuilt from it on
+    /// load. Distance metrics always use the exact f32 scan. See
+    /// [`quantization_error`](Self::quantization_error) for the reconstruction
+    /// error.
+    pub fn with_scalar_quantization(
+        embedding_dim: usize,
+        storage_file: &str,
+        params: QuantParams,
+    ) -> Result<Self> {
+        let mut db = Self::new(embedding_dim, storage_file)?;
+        let mut quant = quant::QuantizedMatrix::new(embedding_dim, params);
+        quant.rebuild(&db.storage.matrix);
+        db.quant = Some(quant);
+        Ok(db)
+    }
+
+    /// Root-mean-square error of the int8 codes against the stored f32 vectors
+    ///
+    /// Returns `None` unless the database was created with
+    /// [`with_scalar_quantization`](Self::with_scalar_quantization).
+    pub fn quantization_error(&self) -> Option<Float> {
+        self.quant
+            .as_ref()
+            .map(|quant| quant.error(&self.storage.matrix))
+    }
+
+    /// Sets the strategy used to generate ids for records with an empty `id`
+    pub fn with_id_strategy(mut self, id_strategy: IdStrategy) -> Self {
+        self.id_strategy = id_strategy;
+        self
+    }
+
+    /// Sets the seed for randomized operations, making them reproducible
+    ///
+    /// [`IdStrategy::Uuid`] ids are then drawn from this seed instead of the
+    /// OS, and so are [`enable_lsh`](Self::enable_lsh) (hyperplanes),
+    /// [`build_ivf`](Self::build_ivf) (initial centroids) and
+    /// [`sample`](Self::sample) when they are passed no seed of their own.
+    /// Nothing else in the database is random.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self.rng = Some(StdRng::seed_from_u64(seed));
+        self
+    }
+
+    /// Get the seed set with [`with_seed`](Self::with_seed), if any
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// The seed for a randomized operation: `seed` if given, else the one from
+    /// [`with_seed`](Self::with_seed), else a fresh one from the OS
+    fn operation_seed(&self, seed: Option<u64>) -> u64 {
+        seed.or(self.seed).unwrap_or_else(|| rand::rng().random())
+    }
+
+    /// Sets the metric used to compare vectors
+    ///
+    /// Only [`Metric::Cosine`] and [`Metric::Pearson`] (which mean-centers
+    /// first) normalize vectors on upsert, so the metric should be chosen before
+    /// any data is inserted.
+    pub fn with_metric(mut self, metric: Metric) -> Self {
+        self.metric = metric;
+        self
+    }
+
+    /// Sets the transform applied to reported scores of distance metrics
+    pub fn with_score_transform(mut self, score_transform: ScoreTransform) -> Self {
+        self.score_transform = score_transform;
+        self
+    }
+
+    /// Sets whether [`Metric::Cosine`] and [`Metric::Pearson`] results report
+    /// the angle between the vectors in degrees instead of its cosine
+    ///
+    /// Identical directions report 0°, orthogonal ones 90° and opposite ones
+    /// 180°, so lower is better in the reported value. Ranking and
+    /// `better_than` still use the cosine.
+    pub fn with_angular_degrees(mut self, enabled: bool) -> Self {
+        self.angular_degrees = enabled;
+        self
+    }
+
+    /// Sets the tolerance within which scores are considered equal
+    ///
+    /// Scores within `epsilon` of `better_than` pass the threshold, and results
+    /// whose scores are within `epsilon` of each other are ordered by storage
+    /// index instead of by their (possibly platform-dependent) last bits. This
+    /// also decides which of them make the cut when they straddle `top_k`.
+    pub fn with_score_epsilon(mut self, epsilon: Float) -> Self {
+        self.score_epsilon = epsilon;
+        self
+    }
+
+    /// Sets whether vectors are normalized at all (enabled by default)
+    ///
+    /// When disabled, `upsert` stores vectors exactly as given and queries are
+    /// scored without normalizing them, whatever the metric. This suits
+    /// pipelines that rank by dot product or normalize upstream. Under
+    /// [`Metric::Cosine`] scores are then plain dot products, so cosine
+    /// semantics are up to the caller, and [`verify`](Self::verify) no longer
+    /// checks for unit length.
+    pub fn with_normalization(mut self, enabled: bool) -> Self {
+        self.normalize = enabled;
+        self
+    }
+
+    /// Whether stored and query vectors are normalized, see
+    /// [`with_normalization`](Self::with_normalization)
+    fn normalizes(&self) -> bool {
+        self.normalize && self.metric.normalizes()
+    }
+
+    /// Sets whether queries keep every record tied with the `top_k`-th result
+    ///
+    /// When several records share the boundary score (within the
+    /// [score epsilon](Self::with_score_epsilon)), the top-k heap would keep an
+    /// arbitrary subset of them; with this enabled, all of them are returned, so
+    /// results may exceed `top_k`. Costs a second scan whenever `top_k` results
+    /// are found.
+    pub fn with_boundary_ties(mut self, enabled: bool) -> Self {
+        self.boundary_ties = enabled;
+        self
+    }
+
+    /// Sets whether [`save`](Self::save) creates missing parent directories of
+    /// the storage file (enabled by default)
+    pub fn with_create_dirs(mut self, create_dirs: bool) -> Self {
+        self.create_dirs = create_dirs;
+        self
+    }
+
+    /// Enables early termination of cosine scoring for records that cannot
+    /// enter the top-k
+    ///
+    /// Dot products are accumulated in blocks; after each block, the
+    /// Cauchy-Schwarz bound on the remaining dimensions of the (unit) stored
+    /// vector and query decides whether the record can still beat the current
+    /// worst hit. This pays off for high dimensions and selective queries. It
+    /// only applies to [`Metric::Cosine`] full scans.
+    ///
+    /// The bound only holds for unit rows, so scans fall back to exact scoring
+    /// while any row stored through
+    /// [`upsert_assume_normalized`](Self::upsert_assume_normalized) is not unit
+    /// length, until [`renormalize_all`](Self::renormalize_all) repairs it.
+    pub fn with_pruned_scoring(mut self, enabled: bool) -> Self {
+        self.pruned_scoring = enabled;
+        self.refresh_unit_rows();
+        self
+    }
+
+    /// Re-checks that every stored row is unit length, if pruning needs to know
+    fn refresh_unit_rows(&mut self) {
+        self.unit_rows = self.pruned_scoring
+            && self
+                .storage
+                .matrix
+                .chunks(self.embedding_dim)
+                .all(PrunedScorer::accepts);
+    }
+
+    /// Re-scores the candidates of an approximate scan exactly before picking
+    /// the final top-k
+    ///
+    /// The int8 scan of [`with_scalar_quantization`](Self::with_scalar_quantization)
+    /// then keeps four times `top_k` candidates (see
+    /// [`with_rerank_budget`](Self::with_rerank_budget)), which are re-ranked by
+    /// their f32 scores, fixing the quantization's ordering errors among them.
+    /// LSH candidates are always scored exactly, so LSH queries are unaffected.
+    pub fn with_rerank(mut self, enabled: bool) -> Self {
+        self.rerank = enabled;
+        self
+    }
+
+    /// Enables [`with_rerank`](Self::with_rerank) with `budget` candidates
+    /// picked by the quantized scan instead of four times `top_k`
+    ///
+    /// A larger budget re-scores more candidates exactly, trading speed for
+    /// recall towards the exact ranking; it never drops below `top_k`. The f32
+    /// matrix is always kept next to the quantized codes, so re-scoring is
+    /// available whenever quantization is.
+    pub fn with_rerank_budget(mut self, budget: usize) -> Self {
+        self.rerank = true;
+        self.rerank_budget = Some(budget);
+        self
+    }
+
+    /// Sets the minimum norm a vector must exceed to be normalized (defaults to
+    /// [`DEFAULT_NORM_EPSILON`])
+    ///
+    /// Under [`Metric::Cosine`] and [`Metric::Pearson`], upserting a vector whose
+    /// norm is at most `epsilon` fails. Lower it for legitimately tiny
+    /// embeddings; raise it to reject near-zero vectors that would blow up when
+    /// scaled to unit length.
+    pub fn with_norm_epsilon(mut self, epsilon: Float) -> Self {
+        self.norm_epsilon = epsilon;
+        self
+    }
+
+    /// Registers a hook called with the updated and inserted ids after every
+    /// successful upsert (including [`merge`](Self::merge) and
+    /// [`replace_all`](Self::replace_all)), replacing any previous one
+    pub fn on_upsert(&mut self, hook: WriteHook) {
+        self.hooks.on_upsert = Some(hook);
+    }
+
+    /// Registers a hook called with the removed ids after every delete (including
+    /// records dropped by [`replace_all`](Self::replace_all)), replacing any
+    /// previous one
+    pub fn on_delete(&mut self, hook: WriteHook) {
+        self.hooks.on_delete = Some(hook);
+    }
+
+    /// Caches the results of up to `capacity` distinct recent queries
+    ///
+    /// Repeating a query with the same vector, `top_k` and `better_than`, and no
+    /// filter, is answered without scanning; filtered queries always scan. The
+    /// cache is cleared by every mutation, so set other `with_*` options first.
+    /// A capacity of 0 disables caching.
+    pub fn with_query_cache(mut self, capacity: usize) -> Self {
+        self.query_cache = (capacity > 0).then(|| {
+            Mutex::new(QueryCache {
+                entries: VecDeque::with_capacity(capacity),
+                stats: CacheStats {
+                    capacity,
+                    ..Default::default()
+                },
+            })
+        });
+        self
+    }
+
+    /// Sets how the matrix grows as vectors are inserted (defaults to
+    /// [`MatrixGrowth::Doubling`])
+    ///
+    /// When a bulk load nearly fills memory, doubling can ask for twice the
+    /// data's size in one allocation; [`MatrixGrowth::Chunked`] keeps capacity
+    /// within one chunk of the data instead. Call
+    /// [`reserve`](Self::reserve) up front when the final size is known.
+    pub fn with_matrix_growth(mut self, growth: MatrixGrowth) -> Self {
+        self.growth = growth;
+        self
+    }
+
+    /// Stores the matrix in segments of at most `bytes` bytes instead of one
+    /// contiguous allocation
+    ///
+    /// A single `Vec` for a million 1024-dimensional vectors needs 4GB in one
+    /// piece, which can fail on a fragmented heap even with enough memory free.
+    /// Segments hold a whole number of vectors (at least one) and scans run
+    /// across all of them, so results are unchanged. A size of 0 goes back to
+    /// a single allocation.
+    pub fn with_segment_bytes(mut self, bytes: usize) -> Self {
+        let row_bytes = self.embedding_dim * std::mem::size_of::<Float>();
+        let segment_len =
+            (bytes > 0 && row_bytes > 0).then(|| (bytes / row_bytes).max(1) * self.embedding_dim);
+        self.storage.matrix.resegment(segment_len);
+        self
+    }
+
+    /// Get hit and miss counts of the query cache, if enabled
+    pub fn query_cache_stats(&self) -> Option<CacheStats> {
+        let cache = self.lock_query_cache()?;
+        Some(CacheStats {
+            len: cache.entries.len(),
+            ..cache.stats
+        })
+    }
+
+    fn lock_query_cache(&self) -> Option<MutexGuard<'_, QueryCache>> {
+        let cache = self.query_cache.as_ref()?;
+        Some(
+            cache
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+
+    /// Drops cached results, which may be stale after a mutation
+    fn clear_query_cache(&self) {
+        if let Some(mut cache) = self.lock_query_cache() {
+            cache.entries.clear();
+        }
+    }
+
+    /// Accumulates dot products in `f64` during exact scans (see
+    /// [`dot_product_f64_acc`])
+    ///
+    /// The matrix stays `f32`; only the running sum is widened, which avoids
+    /// cancellation error reordering near-tied results at high dimensions, at
+    /// some cost in scan speed. Quantized and pruned scans are unaffected.
+    pub fn with_f64_accumulation(mut self, enabled: bool) -> Self {
+        self.f64_accumulation = enabled;
+        self
+    }
+
+    /// Sets whether [`save`](Self::save) first copies the current storage file
+    /// to `<storage_file>.bak`, which [`new_repaired`](Self::new_repaired) falls
+    /// back to when the storage file is unreadable
+    pub fn with_backup(mut self, backup: bool) -> Self {
+        self.backup = backup;
+        self
+    }
+
+    /// Sets whether [`save`](Self::save) pretty-prints the JSON for inspection
+    ///
+    /// The matrix stays a single base64 string either way; files load the same.
+    pub fn with_pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    /// Makes [`save`](Self::save) gzip string fields longer than
+    /// `threshold_bytes`
+    ///
+    /// A compressed value is stored as `{"__gzip__": "<base64>"}` and restored
+    /// to the original string on load, so `get` and query results always see
+    /// plain text. Files with compressed fields load anywhere the `compression`
+    /// feature is enabled, regardless of this setting.
+    #[cfg(feature = "compression")]
+    pub fn with_field_compression(mut self, threshold_bytes: usize) -> Self {
+        self.field_compression = Some(threshold_bytes);
+        self
+    }
+
+    /// Makes [`delete`](Self::delete) mark records as tombstoned instead of
+    /// compacting the matrix on every call
+    ///
+    /// Tombstoned records are skipped by queries and [`get`](Self::get) right
+    /// away but keep their storage slot, so [`len`](Self::len) and storage
+    /// indices still count them. Once the share of tombstoned records reaches
+    /// `compact_ratio`, or on [`compact`](Self::compact), they are removed in a
+    /// single pass. Tombstones are saved with the storage file. Upserting a
+    /// tombstoned id revives its slot with the new vector and fields.
+    pub fn with_soft_delete(mut self, compact_ratio: Float) -> Self {
+        self.soft_delete = Some(compact_ratio);
+        self
+    }
+
+    /// Declares the JSON type of record fields, checked on every upsert
+    ///
+    /// An upsert holding a record with a field of the wrong type fails without
+    /// writing anything, with a [`Multiple`](NanoVectorDbError::Multiple) error
+    /// holding a [`Record`](NanoVectorDbError::Record)-wrapped
+    /// [`SchemaViolation`](NanoVectorDbError::SchemaViolation) for every
+    /// offending record and field. Fields not in
+    /// `schema` are unchecked, and records lacking a declared field pass unless
+    /// [`with_schema_required`](Self::with_schema_required) is set. Records
+    /// already stored are not re-checked, and the schema is not saved. An empty
+    /// schema turns validation off.
+    pub fn set_schema(&mut self, schema: HashMap<String, FieldType>) {
+        self.schema = schema;
+    }
+
+    /// Sets whether records must carry every field of the
+    /// [schema](Self::set_schema) to be upserted
+    pub fn with_schema_required(mut self, required: bool) -> Self {
+        self.schema_required = required;
+        self
+    }
+
+    /// A [`SchemaViolation`](NanoVectorDbError::SchemaViolation) for every
+    /// field of `data` that does not fit the schema, by field name
+    fn schema_violations(&self, data: &Data) -> Vec<NanoVectorDbError> {
+        let mut keys: Vec<&String> = self.schema.keys().collect();
+        keys.sort();
+        keys.into_iter()
+            .filter_map(|key| {
+                let got = data.fields.get(key);
+                let fits = match got {
+                    None => !self.schema_required,
+                    Some(value) => self.schema[key].matches(value),
+                };
+                (!fits).then(|| NanoVectorDbError::SchemaViolation {
+                    field: key.clone(),
+                    expected: self.schema[key],
+                    got: got.cloned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Lets at most `max` queries scan on the Rayon pool at once
+    ///
+    /// Further concurrent queries, and queries issued from inside another
+    /// parallel task, scan serially on their own thread rather than queueing
+    /// more work on a saturated pool. `0` makes every query serial. The other
+    /// full scans, such as [`query_grouped`](Self::query_grouped),
+    /// [`score_quantiles`](Self::score_quantiles) and
+    /// [`duplication_report`](Self::duplication_report), count as queries too.
+    pub fn with_max_parallel_queries(mut self, max: usize) -> Self {
+        self.parallel_limit = Some(ParallelLimit {
+            max,
+            running: AtomicUsize::new(0),
+        });
+        self
+    }
+
+    /// Sets whether upserts stamp each record with the time of the write, for
+    /// [`query_since`](Self::query_since)
+    ///
+    /// The [`Timestamp`] is stored as the [`F_TIMESTAMP`](constants::F_TIMESTAMP)
+    /// field, so it is saved and returned like any other field. Updates replace
+    /// a record's fields, so they are stamped anew too. Records written with
+    /// the field already set keep that value, e.g. when importing.
+    pub fn with_timestamps(mut self, enabled: bool) -> Self {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Evaluates query filters in a separate pass before scoring
+    ///
+    /// The filter runs once over the record metadata and produces a mask, so the
+    /// scoring pass only reads the contiguous matrix and the mask instead of
+    /// interleaving matrix rows with record lookups. Helps most for large
+    /// matrices with cheap filters.
+    pub fn with_filter_first(mut self, enabled: bool) -> Self {
+        self.filter_first = enabled;
+        self
+    }
+
+    /// Sets the result key holding each hit's id (defaults to [`constants::F_ID`])
+    ///
+    /// Useful when records carry a user field named `__id__` that would otherwise
+    /// be overwritten in query results.
+    pub fn with_id_field(mut self, name: &str) -> Self {
+        self.id_field = name.to_string();
+        self
+    }
+
+    /// Sets the result key holding each hit's score (defaults to [`constants::F_METRICS`])
+    pub fn with_metrics_field(mut self, name: &str) -> Self {
+        self.metrics_field = name.to_string();
+        self
+    }
+
+    /// Stores record fields in a sidecar file (`<storage_file>.fields`) instead of
+    /// the main storage file
+    ///
+    /// The main file then only holds ids and the matrix, so it stays small and
+    /// fast to load. Fields of a previously saved sidecar are read lazily on the
+    /// first `get` or query result materialization. If the sidecar cannot be
+    /// read, every method returning a `Result` that needs the fields (e.g.
+    /// [`load_fields`](Self::load_fields), [`upsert`](Self::upsert),
+    /// [`to_bytes`](Self::to_bytes) and [`snapshot`](Self::snapshot)) fails with
+    /// the read error. Methods that cannot fail, such as `get` and queries,
+    /// return records without their sidecar fields meanwhile, and read it again
+    /// on their next call.
+    pub fn with_fields_sidecar(mut self) -> Self {
+        let mut path = self.storage_file.clone().into_os_string();
+        path.push(".fields");
+        let path = PathBuf::from(path);
+        let pending = path.exists();
+        self.sidecar = Some(FieldsSidecar {
+            path,
+            pending,
+            records: OnceLock::new(),
+        });
+        self
+    }
+
+    /// Merges fields from the sidecar file into memory, if they are not loaded yet
+    pub fn load_fields(&mut self) -> Result<()> {
+        let Some(sidecar) = self.sidecar.as_mut().filter(|s| s.pending) else {
+            return Ok(());
+        };
+        let mut records = match sidecar.records.take() {
+            Some(records) => records,
+            None => sidecar.read()?,
+        };
+        for data in self.storage.data.iter_mut() {
+            if let Some(record) = records.remove(&data.id) {
+                data.fields = record.fields;
+            }
+        }
+        sidecar.pending = false;
+        Ok(())
+    }
+
+    /// Sidecar records that have not been merged into `storage.data` yet
+    ///
+    /// A failed read is not remembered, so the next call tries again and
+    /// reports it again.
+    fn lazy_records(&self) -> Result<Option<&HashMap<String, Data>>> {
+        let Some(sidecar) = self.sidecar.as_ref().filter(|s| s.pending) else {
+            return Ok(None);
+        };
+        if let Some(records) = sidecar.records.get() {
+            return Ok(Some(records));
+        }
+        let records = sidecar.read()?;
+        Ok(Some(sidecar.records.get_or_init(|| records)))
+    }
+
+    /// Logs record writes to `<storage_file>.wal`, replaying any writes a
+    /// previous instance flushed there but never saved
+    ///
+    /// Upserts and deletes are buffered in memory until [`flush`](Self::flush)
+    /// appends them to the log and syncs it, which is cheap and durable but
+    /// leaves the log growing; [`save`](Self::save) writes the full snapshot
+    /// and empties the log. After a crash, opening the storage file with the log
+    /// enabled restores every flushed write. Only records are logged, with
+    /// their vectors, fields, weights and original norms: named vectors,
+    /// additional data and positions from [`insert_at`](Self::insert_at) need a
+    /// `save`. A torn last entry (from a crash mid-flush) is ignored.
+    pub fn with_write_ahead_log(mut self) -> Result<Self> {
+        let mut path = self.storage_file.clone().into_os_string();
+        path.push(".wal");
+        let log = wal::WriteAheadLog::new(PathBuf::from(path));
+        // Consecutive upserts replay as one batch, split where an id repeats
+        // since a batch does not apply repeated ids in order
+        let mut batch: Vec<(Data, Option<Float>)> = Vec::new();
+        let mut batch_ids: HashSet<String> = HashSet::new();
+        for op in log.read()? {
+            let ends_batch = match &op {
+                wal::WalOp::Upsert { id, .. } => batch_ids.contains(id),
+                _ => true,
+            };
+            if ends_batch && !batch.is_empty() {
+                batch_ids.clear();
+                self.replay_upserts(std::mem::take(&mut batch))?;
+            }
+            match op {
+                wal::WalOp::Upsert {
+                    id,
+                    vector,
+                    fields,
+                    norm,
+                    weight,
+                } => {
+                    batch_ids.insert(id.clone());
+                    let data = Data {
+                        id,
+                        vector,
+                        fields,
+                        weight,
+                    };
+                    batch.push((data, norm));
+                }
+                wal::WalOp::Delete { ids } => self.delete(&ids),
+                wal::WalOp::Clear => self.replace_all(Vec::new())?,
+            }
+        }
+        if !batch.is_empty() {
+            self.replay_upserts(batch)?;
+        }
+        self.wal = Some(log);
+        Ok(self)
+    }
+
+    /// Applies logged upserts as one batch, restoring their original norms
+    fn replay_upserts(&mut self, batch: Vec<(Data, Option<Float>)>) -> Result<()> {
+        let (datas, norms): (Vec<Data>, Vec<Option<Float>>) = batch.into_iter().unzip();
+        let ids: Vec<String> = datas.iter().map(|data| data.id.clone()).collect();
+        // Norms recomputed from the logged vectors would be those after normalizing
+        self.upsert_inner(datas, false)?;
+        let positions: HashMap<&str, usize> = self
+            .storage
+            .data
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(index, data)| (data.id.as_str(), index))
+            .collect();
+        let positions: Vec<Option<usize>> = ids
+            .iter()
+            .map(|id| positions.get(id.as_str()).copied())
+            .collect();
+        for (pos, norm) in positions.into_iter().zip(norms) {
+            if let (Some(pos), Some(norm)) = (pos, norm) {
+                self.storage.norms[pos] = Some(norm);
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the writes buffered since the last flush durable in the
+    /// write-ahead log, see [`with_write_ahead_log`](Self::with_write_ahead_log)
+    ///
+    /// Unlike [`save`](Self::save), this only appends the new writes instead of
+    /// rewriting the whole snapshot. Does nothing without a write-ahead log.
+    pub fn flush(&mut self) -> Result<()> {
+        match &self.wal {
+            Some(wal) => wal.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends `op` to the write-ahead log, if enabled
+    fn log(&self, op: &wal::WalOp) {
+        if let Some(wal) = &self.wal {
+            wal.append(op);
+        }
+    }
+
+    /// Logs the record at `index` as it is now stored
+    fn log_record(&self, index: usize) {
+        let data = self.record(index);
+        self.log(&wal::WalOp::Upsert {
+            id: data.id.clone(),
+            vector: self.vector_at(index).unwrap_or_default().to_vec(),
+            fields: data.fields.clone(),
+            norm: self.storage.norms.get(index).copied().flatten(),
+            weight: self.storage.weights.get(index).copied(),
+        });
+    }
+
+    /// Logs the whole contents, after writes that replace them
+    fn log_all(&self) {
+        if self.wal.is_none() {
+            return;
+        }
+        self.log(&wal::WalOp::Clear);
+        for index in (0..self.len()).filter(|&index| !self.is_tombstoned(index)) {
+            self.log_record(index);
+        }
+    }
+
+    /// Record at a storage index, with its fields resolved from the sidecar if needed
+    ///
+    /// Falls back to the record without its sidecar fields if the sidecar cannot
+    /// be read; fallible callers check [`lazy_records`](Self::lazy_records) first.
+    fn record(&self, index: usize) -> &Data {
+        let data = &self.storage.data[index];
+        match self.lazy_records() {
+            Ok(Some(records)) => records.get(&data.id).unwrap_or(data),
+            _ => data,
+        }
+    }
+
+    /// Upserts vectors into the database
+    ///
+    /// Records with an empty `id` are assigned one according to the configured
+    /// [`IdStrategy`]; the assigned ids are part of the returned update/insert lists.
+    /// Fails without inserting anything if any record has a field named like the
+    /// reserved id or metrics result keys.
+    pub fn upsert(&mut self, datas: Vec<Data>) -> Result<(Vec<String>, Vec<String>)> {
+        self.upsert_inner(datas, true)
+    }
+
+    /// Upserts the rows of a CSV with a header line, mapped to records by `columns`
+    ///
+    /// Field cells become booleans or numbers where they parse as such, and
+    /// strings otherwise; empty cells are left out. A leading UTF-8 byte order
+    /// mark is skipped. Every row is parsed and checked before anything is
+    /// upserted, so a malformed row, or one the upsert would reject (e.g. a zero
+    /// vector or a schema violation), fails the import with an
+    /// [`AtLine`](NanoVectorDbError::AtLine) error naming its line and changes
+    /// nothing. Returns the number of imported rows.
+    pub fn import_csv(
+        &mut self,
+        mut reader: impl std::io::Read,
+        columns: ColumnMap,
+    ) -> Result<usize> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text)?;
+        let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+        let mut rows = csv::parse(text)?.into_iter();
+        let Some((_, header)) = rows.next() else {
+            return Ok(0);
+        };
+        let column = |name: &str| {
+            header.iter().position(|h| h == name).ok_or_else(|| {
+                NanoVectorDbError::InvalidInput(format!("CSV has no column {name:?}"))
+            })
+        };
+
+        let id_col = columns.id_col.as_deref().map(column).transpose()?;
+        let vector_cols = match &columns.vector {
+            VectorColumns::Json(name) => {
+                let index = column(name)?;
+                index..index + 1
+            }
+            VectorColumns::Starting(name) => {
+                let start = column(name)?;
+                if start + self.embedding_dim > header.len() {
+                    return Err(NanoVectorDbError::InvalidInput(format!(
+                        "CSV has fewer than {} columns from {name:?}",
+                        self.embedding_dim
+                    )));
+                }
+                start..start + self.embedding_dim
+            }
+        };
+        let field_cols: Vec<usize> = if columns.field_cols.is_empty() {
+            (0..header.len())
+                .filter(|&i| Some(i) != id_col && !vector_cols.contains(&i))
+                .collect()
+        } else {
+            columns
+                .field_cols
+                .iter()
+                .map(|name| column(name))
+                .collect::<Result<_>>()?
+        };
+
+        let mut datas = Vec::new();
+        for (line, row) in rows {
+            let at_line = |e: NanoVectorDbError| NanoVectorDbError::AtLine {
+                line,
+                source: Box::new(e),
+            };
+            if row.len() != header.len() {
+                return Err(at_line(NanoVectorDbError::InvalidInput(format!(
+                    "Expected {} columns, got {}",
+                    header.len(),
+                    row.len()
+                ))));
+            }
+            let vector: Vec<Float> = match &columns.vector {
+                VectorColumns::Json(name) => serde_json::from_str(&row[vector_cols.start])
+                    .map_err(|e| {
+                        at_line(NanoVectorDbError::InvalidInput(format!(
+                            "Column {name:?} is not a JSON array of numbers: {e}"
+                        )))
+                    })?,
+                VectorColumns::Starting(_) => vector_cols
+                    .clone()
+                    .map(|i| {
+                        row[i].trim().parse().map_err(|_| {
+                            at_line(NanoVectorDbError::InvalidInput(format!(
+                                "Column {:?} is not a number: {:?}",
+                                header[i], row[i]
+                            )))
+                        })
+                    })
+                    .collect::<Result<_>>()?,
+            };
+            if vector.len() != self.embedding_dim {
+                return Err(at_line(NanoVectorDbError::DimensionMismatch {
+                    expected: self.embedding_dim,
+                    got: vector.len(),
+                }));
+            }
+            let data = Data {
+                id: id_col.map(|i| row[i].clone()).unwrap_or_default(),
+                vector,
+                fields: field_cols
+                    .iter()
+                    .filter_map(|&i| Some((header[i].clone(), csv::infer_value(&row[i])?)))
+                    .collect(),
+                weight: None,
+            };
+            self.check_record(&data).map_err(at_line)?;
+            datas.push(data);
+        }
+
+        let imported = datas.len();
+        self.upsert(datas)?;
+        Ok(imported)
+    }
+
+    /// Fails for a record that [`upsert`](Self::upsert) would reject: one with
+    /// reserved field names, a schema violation or a vector it cannot normalize
+    fn check_record(&self, data: &Data) -> Result<()> {
+        if data.fields.contains_key(&self.id_field) || data.fields.contains_key(&self.metrics_field)
+        {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Fields collide with reserved keys {:?}/{:?}",
+                self.id_field, self.metrics_field
+            )));
+        }
+        let mut violations = self.schema_violations(data);
+        match violations.len() {
+            0 => {}
+            1 => return Err(violations.remove(0)),
+            _ => return Err(NanoVectorDbError::Multiple(violations)),
+        }
+        if self.normalizes() {
+            self.metric.normalize(&data.vector, self.norm_epsilon)?;
+        }
+        Ok(())
+    }
+
+    /// Upserts vectors that the caller has already normalized, storing them as-is
+    ///
+    /// Vectors that are not actually unit length skew cosine scores; use
+    /// [`renormalize_all`](Self::renormalize_all) to repair them afterwards.
+    pub fn upsert_assume_normalized(
+        &mut self,
+        datas: Vec<Data>,
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        self.upsert_inner(datas, false)
+    }
+
+    /// Inserts a new record at storage index `index`, shifting later records up
+    ///
+    /// Validates and normalizes like [`upsert`](Self::upsert), then moves the
+    /// record from the end into place, which costs a copy of every later row.
+    /// Returns the record's id (generated if it was empty). Fails without
+    /// modifying anything if `index` is past the end or the id already exists.
+    pub fn insert_at(&mut self, index: usize, mut data: Data) -> Result<String> {
+        if index > self.len() {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Index {} out of bounds for {} records",
+                index,
+                self.len()
+            )));
+        }
+        if data.id.is_empty() {
+            if let Some(id) = self.id_strategy.generate(&data.vector, self.rng.as_mut()) {
+                data.id = id;
+            }
+        }
+        if self.storage.data.iter().any(|d| d.id == data.id) {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Record {:?} already exists",
+                data.id
+            )));
+        }
+
+        let id = data.id.clone();
+        self.upsert(vec![data])?;
+        let dim = self.embedding_dim;
+        let last = self.len() - 1;
+        if index < last {
+            self.storage.data[index..].rotate_right(1);
+            let row = self.storage.matrix[last * dim..(last + 1) * dim].to_vec();
+            self.storage.matrix.truncate(last * dim);
+            self.storage.matrix.insert(index * dim, &row);
+            if !self.storage.weights.is_empty() {
+                self.storage.weights[index..].rotate_right(1);
+            }
+            self.storage.norms[index..].rotate_right(1);
+            let tombstones = std::mem::take(&mut self.storage.tombstones);
+            self.storage.tombstones = tombstones
+                .into_iter()
+                .map(|tombstone| tombstone + usize::from(tombstone >= index))
+                .collect();
+            self.rebuild_indexes();
+        }
+        Ok(id)
+    }
+
+    /// Makes room for one more row if the growth strategy is chunked
+    fn grow_matrix(&mut self) {
+        if let MatrixGrowth::Chunked(rows) = self.growth {
+            let matrix = &mut self.storage.matrix;
+            if matrix.len() + self.embedding_dim > matrix.capacity() {
+                matrix.reserve_exact(rows.max(1) * self.embedding_dim);
+            }
+        }
+    }
+
+    /// Replaces the vector and fields of the record at `pos`, reviving it if
+    /// it was tombstoned, and keeps the indexes and content hash in step
+    fn overwrite_record(&mut self, pos: usize, data: Data, norm_vec: Vec<Float>) {
+        let start = pos * self.embedding_dim;
+        let end = start + self.embedding_dim;
+        if let Some(lsh) = self.lsh.as_mut() {
+            lsh.remove(pos, &self.storage.matrix[start..end]);
+            lsh.insert(pos, &norm_vec);
+        }
+        if let Some(ivf) = self.ivf.as_mut() {
+            ivf.remove(pos);
+            ivf.insert(pos, &norm_vec);
+        }
+        if let Some(quant) = self.quant.as_mut() {
+            quant.set_row(pos, &norm_vec);
+        }
+        // A tombstoned record no longer counts towards the content hash
+        if !self.storage.tombstones.remove(&pos) {
+            self.update_content_hash(pos, false);
+        }
+        for index in self.field_indexes.values_mut() {
+            index.remove(pos, &self.storage.data[pos]);
+        }
+        self.storage.matrix[start..end].copy_from_slice(&norm_vec);
+        self.store_weight(pos, data.weight.unwrap_or(1.0));
+        self.storage.norms[pos] = Some(vector_norm(&data.vector));
+        self.storage.data[pos].vector = norm_vec;
+        self.storage.data[pos].fields = data.fields;
+        for index in self.field_indexes.values_mut() {
+            index.insert(pos, &self.storage.data[pos]);
+        }
+        self.update_content_hash(pos, true);
+    }
+
+    fn upsert_inner(
+        &mut self,
+        mut datas: Vec<Data>,
+        normalize_vectors: bool,
+    ) -> Result<(Vec<String>, Vec<String>)> {
+        self.load_fields()?;
+        self.clear_query_cache();
+        let normalize_vectors = normalize_vectors && self.normalizes();
+        let (metric, norm_epsilon) = (self.metric, self.norm_epsilon);
+        let prepare = |vector: &[Float]| {
+            if normalize_vectors {
+                metric.normalize(vector, norm_epsilon)
+            } else {
+                Ok(vector.to_vec())
+            }
+        };
+
+        let mut generated = HashSet::new();
+        for data in datas.iter_mut().filter(|d| d.id.is_empty()) {
+            if let Some(id) = self.id_strategy.generate(&data.vector, self.rng.as_mut()) {
+                generated.insert(id.clone());
+                data.id = id;
+            }
+        }
+        if self.timestamps {
+            let now = timestamp_now();
+            for data in datas.iter_mut() {
+                data.fields
+                    .entry(constants::F_TIMESTAMP.to_string())
+                    .or_insert_with(|| now.into());
+            }
+        }
+
+        // Reserved result keys in user fields would be silently overwritten in query results
+        let colliding: Vec<&str> = datas
+            .iter()
+            .filter(|d| {
+                d.fields.contains_key(&self.id_field) || d.fields.contains_key(&self.metrics_field)
+            })
+            .map(|d| d.id.as_str())
+            .collect();
+        if !colliding.is_empty() {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Fields collide with reserved keys {:?}/{:?} in records: {:?}",
+                self.id_field, self.metrics_field, colliding
+            )));
+        }
+        let violations: Vec<NanoVectorDbError> = datas
+            .iter()
+            .flat_map(|d| {
+                self.schema_violations(d)
+                    .into_iter()
+                    .map(|violation| NanoVectorDbError::Record {
+                        id: d.id.clone(),
+                        source: Box::new(violation),
+                    })
+            })
+            .collect();
+        if !violations.is_empty() {
+            return Err(NanoVectorDbError::Multiple(violations));
+        }
+        for data in &datas {
+            if let Some(weight) = data.weight {
+                check_weight(weight).map_err(|e| NanoVectorDbError::Record {
+                    id: data.id.clone(),
+                    source: Box::new(e),
+                })?;
+            }
+        }
+
+        // Rows stored as given may break the unit length pruning relies on
+        if !normalize_vectors && self.unit_rows {
+            self.unit_rows = datas.iter().all(|d| PrunedScorer::accepts(&d.vector));
+        }
+
+        // Reject unnormalizable vectors up front so a failed batch changes nothing
+        if normalize_vectors {
+            for data in &datas {
+                prepare(&data.vector).map_err(|e| NanoVectorDbError::Record {
+                    id: data.id.clone(),
+                    source: Box::new(e),
+                })?;
+            }
+        }
+
+        let mut updates = Vec::new();
+        let mut inserts = Vec::new();
+        self.storage.norms.resize(self.storage.data.len(), None);
+        let positions: Vec<Option<usize>> = {
+            let existing: HashMap<&str, usize> = self
+                .storage
+                .data
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, data)| (data.id.as_str(), index))
+                .collect();
+            datas
+                .iter()
+                .map(|data| existing.get(data.id.as_str()).copied())
+                .collect()
+        };
+
+        // Storage positions written, in order, for the write-ahead log
+        let mut written = BTreeSet::new();
+        let mut new_datas = Vec::new();
+        for (data, pos) in datas.into_iter().zip(positions) {
+            match pos {
+                Some(pos) => {
+                    let norm_vec = prepare(&data.vector)?;
+                    updates.push(data.id.clone());
+                    written.insert(pos);
+                    self.overwrite_record(pos, data, norm_vec);
+                }
+                None => new_datas.push(data),
+            }
+        }
+
+        let mut batch_positions: HashMap<String, usize> = HashMap::new();
+        for data in new_datas {
+            let norm_vec = prepare(&data.vector)?;
+            // Ids generated repeatedly within one batch (identical content hashes)
+            // overwrite in place; repeated caller ids are kept as separate records
+            if let Some(&pos) = batch_positions.get(&data.id) {
+                self.overwrite_record(pos, data, norm_vec);
+                continue;
+            }
+            if generated.contains(&data.id) {
+                batch_positions.insert(data.id.clone(), self.storage.data.len());
+            }
+            if let Some(lsh) = self.lsh.as_mut() {
+                lsh.insert(self.storage.data.len(), &norm_vec);
+            }
+            if let Some(ivf) = self.ivf.as_mut() {
+                ivf.insert(self.storage.data.len(), &norm_vec);
+            }
+            if let Some(quant) = self.quant.as_mut() {
+                quant.set_row(self.storage.data.len(), &norm_vec);
+            }
+            if !self.storage.weights.is_empty() {
+                self.storage.weights.push(1.0);
+            }
+            self.storage.norms.push(Some(vector_norm(&data.vector)));
+            self.grow_matrix();
+            self.storage.matrix.extend_from_slice(&norm_vec);
+            self.storage.data.push(Data {
+                id: data.id.clone(),
+                vector: norm_vec,
+                fields: data.fields,
+                weight: None,
+            });
+            let pos = self.storage.data.len() - 1;
+            self.store_weight(pos, data.weight.unwrap_or(1.0));
+            for index in self.field_indexes.values_mut() {
+                index.insert(pos, &self.storage.data[pos]);
+            }
+            if let Some(ids) = self.id_index.as_mut() {
+                ids.insert(data.id.clone(), pos);
+            }
+            self.update_content_hash(pos, true);
+            written.insert(pos);
+            inserts.push(data.id);
+        }
+
+        if self.wal.is_some() {
+            for index in written {
+                self.log_record(index);
+            }
+        }
+        if let Some(hook) = &self.hooks.on_upsert {
+            hook(&[updates.as_slice(), inserts.as_slice()].concat());
+        }
+        Ok((updates, inserts))
+    }
+
+    /// Replaces every record with `datas`, keeping the configuration and
+    /// additional data
+    ///
+    /// The new records are validated and inserted like [`upsert`](Self::upsert).
+    /// If that fails, the previous records are restored, so the database is
+    /// never observed half-replaced.
+    pub fn replace_all(&mut self, datas: Vec<Data>) -> Result<()> {
+        self.load_fields()?;
+        self.content_hash.take();
+        // Logged as a whole afterwards, so a failed replace logs nothing
+        let wal = self.wal.take();
+        let previous_data = std::mem::take(&mut self.storage.data);
+        let previous_matrix = self.storage.matrix.take();
+        let previous_weights = std::mem::take(&mut self.storage.weights);
+        let previous_norms = std::mem::take(&mut self.storage.norms);
+        let previous_tombstones = std::mem::take(&mut self.storage.tombstones);
+        let previous_unit_rows = std::mem::replace(&mut self.unit_rows, self.pruned_scoring);
+        self.rebuild_indexes();
+
+        if let Err(e) = self.upsert_inner(datas, true) {
+            self.unit_rows = previous_unit_rows;
+            self.storage.data = previous_data;
+            self.storage.matrix = previous_matrix;
+            self.storage.weights = previous_weights;
+            self.storage.norms = previous_norms;
+            self.storage.tombstones = previous_tombstones;
+            self.rebuild_indexes();
+            self.wal = wal;
+            return Err(e);
+        }
+        self.storage.retain_named_vectors();
+        self.wal = wal;
+        self.log_all();
+
+        if let Some(hook) = &self.hooks.on_delete {
+            let remaining: HashSet<&String> = self.storage.data.iter().map(|d| &d.id).collect();
+            let removed: Vec<String> = previous_data
+                .into_iter()
+                .enumerate()
+                .filter(|(index, _)| !previous_tombstones.contains(index))
+                .map(|(_, d)| d.id)
+                .filter(|id| !remaining.contains(id))
+                .collect();
+            if !removed.is_empty() {
+                hook(&removed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Imports every record of `other`, e.g. to combine shards built in parallel
+    ///
+    /// Both databases must share `embedding_dim` and metric, as well as the
+    /// dimension of any named vector field they both have. Vectors, including
+    /// those of [`upsert_named_vectors`](Self::upsert_named_vectors), are copied
+    /// as stored in `other`, without re-normalizing. Ids present in both are
+    /// handled according to `policy`.
+    pub fn merge(
+        &mut self,
+        mut other: NanoVectorDB,
+        policy: CollisionPolicy,
+    ) -> Result<UpsertReport> {
+        if other.embedding_dim != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: other.embedding_dim,
+            });
+        }
+        if other.metric != self.metric {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Metric mismatch: expected {:?}, other has {:?}",
+                self.metric, other.metric
+            )));
+        }
+        for (field, named) in &other.storage.named_vectors {
+            match self.storage.named_vectors.get(field) {
+                Some(own) if own.embedding_dim != named.embedding_dim => {
+                    return Err(NanoVectorDbError::DimensionMismatch {
+                        expected: own.embedding_dim,
+                        got: named.embedding_dim,
+                    });
+                }
+                _ => {}
+            }
+        }
+        other.load_fields()?;
+        other.compact();
+
+        let existing_ids: HashSet<&String> = self.storage.data.iter().map(|d| &d.id).collect();
+        let colliding: Vec<String> = other
+            .storage
+            .data
+            .iter()
+            .filter(|d| existing_ids.contains(&d.id))
+            .map(|d| d.id.clone())
+            .collect();
+        if policy == CollisionPolicy::Error && !colliding.is_empty() {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Merge would overwrite existing ids: {:?}",
+                colliding
+            )));
+        }
+
+        let embedding_dim = self.embedding_dim;
+        let colliding: HashSet<String> = colliding.into_iter().collect();
+        let mut skipped = Vec::new();
+        let mut norms = Vec::new();
+        let datas: Vec<Data> = std::mem::take(&mut other.storage.data)
+            .into_iter()
+            .zip(other.storage.matrix.chunks(embedding_dim))
+            .enumerate()
+            .filter_map(|(index, (data, row))| {
+                if policy == CollisionPolicy::Skip && colliding.contains(&data.id) {
+                    skipped.push(data.id);
+                    return None;
+                }
+                // Rows are imported as stored, so their own norm is not the original
+                norms.push((
+                    data.id.clone(),
+                    other.storage.norms.get(index).copied().flatten(),
+                ));
+                Some(Data {
+                    id: data.id,
+                    vector: row.to_vec(),
+                    fields: data.fields,
+                    weight: other.storage.weights.get(index).copied(),
+                })
+            })
+            .collect();
+
+        let (updated, inserted) = self.upsert_inner(datas, false)?;
+        let positions: HashMap<&str, usize> = self
+            .storage
+            .data
+            .iter()
+            .enumerate()
+            .map(|(index, data)| (data.id.as_str(), index))
+            .collect();
+        for (id, norm) in norms {
+            self.storage.norms[positions[id.as_str()]] = norm;
+        }
+        let skipped_ids: HashSet<&str> = skipped.iter().map(String::as_str).collect();
+        for (field, named) in std::mem::take(&mut other.storage.named_vectors) {
+            let own = self
+                .storage
+                .named_vectors
+                .entry(field)
+                .or_insert_with(|| NamedMatrix::new(named.embedding_dim));
+            for (id, vector) in named
+                .ids
+                .into_iter()
+                .zip(named.matrix.chunks(named.embedding_dim))
+            {
+                if !skipped_ids.contains(id.as_str()) {
+                    own.set(id, vector);
+                }
+            }
+        }
+        Ok(UpsertReport {
+            updated,
+            inserted,
+            skipped,
+        })
+    }
+
+    /// Sets the ranking weight of a record (1.0 unless set)
+    ///
+    /// Weights can also be given on upsert via [`Data::weight`]. Under
+    /// similarity metrics the score is multiplied by the weight, so a higher
+    /// weight ranks a record with a positive score better but one with a
+    /// negative score worse. Under distance metrics the distance is divided by
+    /// it, so a higher weight always ranks a record closer. Weights are
+    /// persisted with the record and apply before thresholds and top-k
+    /// selection. Weighted databases skip
+    /// [`with_pruned_scoring`](Self::with_pruned_scoring) pruning.
+    pub fn set_weight(&mut self, id: &str, weight: Float) -> Result<()> {
+        check_weight(weight)?;
+        let Some(index) = self.position(id) else {
+            return Err(NanoVectorDbError::NotFound(id.to_string()));
+        };
+        self.store_weight(index, weight);
+        self.clear_query_cache();
+        if self.wal.is_some() {
+            self.log_record(index);
+        }
+        Ok(())
+    }
+
+    /// Sets the weight of the record at `pos`, keeping the weights empty while
+    /// all of them are 1.0
+    fn store_weight(&mut self, pos: usize, weight: Float) {
+        if self.storage.weights.is_empty() {
+            if weight == 1.0 {
+                return;
+            }
+            self.storage.weights = vec![1.0; self.storage.data.len()];
+        }
+        self.storage.weights[pos] = weight;
+    }
+
+    /// Get the ranking weight of a record
+    pub fn weight(&self, id: &str) -> Option<Float> {
+        let index = self.position(id)?;
+        Some(self.storage.weights.get(index).copied().unwrap_or(1.0))
+    }
+
+    /// Stores vectors of a named embedding space, e.g. a title embedding next to
+    /// the main body embedding, for [`query_field`](Self::query_field)
+    ///
+    /// Each pair is a record id and its vector in that space; the record must
+    /// already exist. The first vector fixes the field's dimension, which may
+    /// differ from `embedding_dim`. Vectors are normalized like the main ones and
+    /// are dropped with their record. Nothing is stored if any pair is invalid.
+    pub fn upsert_named_vectors(
+        &mut self,
+        field: &str,
+        vectors: Vec<(String, Vec<Float>)>,
+    ) -> Result<()> {
+        let existing: HashSet<&str> = self.storage.data.iter().map(|d| d.id.as_str()).collect();
+        let embedding_dim = match self.storage.named_vectors.get(field) {
+            Some(named) => named.embedding_dim,
+            None => vectors.first().map_or(0, |(_, vector)| vector.len()),
+        };
+        if vectors.is_empty() {
+            return Ok(());
+        }
+        if embedding_dim == 0 {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Vector field \"{}\" cannot have zero dimension",
+                field
+            )));
+        }
+
+        let mut prepared = Vec::with_capacity(vectors.len());
+        for (id, vector) in vectors {
+            if !existing.contains(id.as_str()) {
+                return Err(NanoVectorDbError::NotFound(id));
+            }
+            if vector.len() != embedding_dim {
+                return Err(NanoVectorDbError::DimensionMismatch {
+                    expected: embedding_dim,
+                    got: vector.len(),
+                });
+            }
+            let vector = if self.normalizes() {
+                self.metric
+                    .normalize(&vector, self.norm_epsilon)
+                    .map_err(|e| NanoVectorDbError::Record {
+                        id: id.clone(),
+                        source: Box::new(e),
+                    })?
+            } else {
+                vector
+            };
+            prepared.push((id, vector));
+        }
+
+        let named = self
+            .storage
+            .named_vectors
+            .entry(field.to_string())
+            .or_insert_with(|| NamedMatrix::new(embedding_dim));
+        for (id, vector) in prepared {
+            named.set(id, &vector);
+        }
+        Ok(())
+    }
+
+    /// Get the stored (normalized) vector of a record in a named embedding space
+    pub fn named_vector(&self, id: &str, field: &str) -> Option<&[Float]> {
+        self.storage.named_vectors.get(field)?.get(id)
+    }
+
+    /// Get the names of the embedding spaces stored next to the main one, sorted
+    pub fn vector_fields(&self) -> Vec<&str> {
+        let mut fields: Vec<&str> = self
+            .storage
+            .named_vectors
+            .keys()
+            .map(String::as_str)
+            .collect();
+        fields.sort_unstable();
+        fields
+    }
+
+    /// Get the L2 norm a record's vector had before normalization
+    ///
+    /// Useful as a magnitude or confidence signal under [`Metric::Cosine`].
+    /// Returns `None` for unknown ids and for records loaded from files written
+    /// before norms were stored.
+    pub fn original_norm(&self, id: &str) -> Option<Float> {
+        let index = self.position(id)?;
+        self.storage.norms.get(index).copied().flatten()
+    }
+
+    /// Applies the weight of the record at `index` to a ranking score
+    fn weighted(&self, index: usize, score: Float) -> Float {
+        match self.storage.weights.get(index) {
+            Some(&weight) if self.metric.is_distance() => score / weight,
+            Some(&weight) => score * weight,
+            None => score,
+        }
+    }
+
+    /// Re-normalizes every stored vector to unit length in place
+    ///
+    /// Fails without modifying anything if any stored row has zero length.
+    pub fn renormalize_all(&mut self) -> Result<()> {
+        let embedding_dim = self.embedding_dim;
+        if let Some(index) =
+            self.storage.matrix.chunks(embedding_dim).position(|row| {
+                row.iter().map(|x| x * x).sum::<Float>().sqrt() <= self.norm_epsilon
+            })
+        {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Cannot normalize zero-length vector at index {}",
+                index
+            )));
+        }
+
+        let norm_epsilon = self.norm_epsilon;
+        self.content_hash.take();
+        for_each_row_mut(&mut self.storage.matrix, embedding_dim, |row| {
+            if let Ok(norm_vec) = normalize_with_epsilon(row, norm_epsilon) {
+                row.copy_from_slice(&norm_vec);
+            }
+        });
+        if let Some(quant) = self.quant.as_mut() {
+            quant.rebuild(&self.storage.matrix);
+        }
+        self.unit_rows = self.pruned_scoring;
+        self.clear_query_cache();
+        for (data, row) in self
+            .storage
+            .data
+            .iter_mut()
+            .zip(self.storage.matrix.chunks(embedding_dim))
+        {
+            data.vector = row.to_vec();
+        }
+        self.log_all();
+        Ok(())
+    }
+
+    /// Enables a random-hyperplane LSH index for approximate search
+    ///
+    /// Each of the `num_tables` tables hashes vectors by `num_planes` (at most 64)
+    /// random hyperplanes drawn from `seed` (or the configured seed, see
+    /// [`with_seed`](Self::with_seed)). Queries then only score records
+    /// sharing a bucket with the query in at least one table, trading recall for
+    /// speed; more tables raise recall, more planes shrink the buckets. The index
+    /// is kept up to date on upsert and delete, but is not persisted by `save`.
+    pub fn enable_lsh(
+        &mut self,
+        num_planes: usize,
+        num_tables: usize,
+        seed: Option<u64>,
+    ) -> Result<()> {
+        let seed = self.operation_seed(seed);
+        let mut lsh = lsh::LshIndex::new(self.embedding_dim, num_planes, num_tables, seed)?;
+        lsh.rebuild(&self.storage.matrix);
+        self.lsh = Some(lsh);
+        self.clear_query_cache();
+        Ok(())
+    }
+
+    /// Disables the LSH index, going back to exact scans
+    pub fn disable_lsh(&mut self) {
+        self.lsh = None;
+        self.clear_query_cache();
+    }
+
+    /// Clusters the stored vectors into `nlist` partitions for approximate search
+    ///
+    /// Runs k-means under the configured metric, seeded by `nlist` distinct
+    /// records drawn from `seed` (or the configured seed, see
+    /// [`with_seed`](Self::with_seed)). Queries then only score the records in the
+    /// [`set_ivf_nprobe`](Self::set_ivf_nprobe) partitions (1 by default) whose
+    /// centroids are nearest to the query; more probes raise recall. Upserts and
+    /// deletes keep the partitions up to date against the fixed centroids, so
+    /// rebuild after large changes. The index is not persisted by `save`, and
+    /// LSH takes precedence if both are enabled. Fails if `nlist` is 0 or
+    /// exceeds the number of records.
+    pub fn build_ivf(&mut self, nlist: usize, seed: Option<u64>) -> Result<()> {
+        let seed = self.operation_seed(seed);
+        let ivf = ivf::IvfIndex::build(
+            &self.storage.matrix,
+            self.embedding_dim,
+            self.metric,
+            nlist,
+            seed,
+        )?;
+        self.ivf = Some(ivf);
+        self.clear_query_cache();
+        Ok(())
+    }
+
+    /// Sets how many IVF partitions a query probes (at least 1)
+    pub fn set_ivf_nprobe(&mut self, nprobe: usize) {
+        self.ivf_nprobe = nprobe.max(1);
+        self.clear_query_cache();
+    }
+
+    /// Drops the IVF index, going back to exact scans
+    pub fn drop_ivf(&mut self) {
+        self.ivf = None;
+        self.clear_query_cache();
+    }
+
+    /// Builds an inverted index from the values of field `key` to the records
+    /// holding them, used by [`query_where`](Self::query_where) and
+    /// [`query_range`](Self::query_range)
+    ///
+    /// The index is kept up to date on upsert and delete, but is not persisted
+    /// by `save`. Building an index that already exists re-indexes every record.
+    pub fn build_field_index(&mut self, key: &str) -> Result<()> {
+        self.load_fields()?;
+        let mut index = field_index::FieldIndex::new(key);
+        index.rebuild(&self.storage.data);
+        self.field_indexes.insert(key.to_string(), index);
+        Ok(())
+    }
+
+    /// Drops the index of field `key`, if any, so `query_where` and
+    /// `query_range` scan again
+    pub fn drop_field_index(&mut self, key: &str) {
+        self.field_indexes.remove(key);
+    }
+
+    /// Indexes records by id, so [`get`](Self::get) and other lookups by id
+    /// take time proportional to the ids requested instead of the collection
+    ///
+    /// The index is kept up to date on upsert and rebuilt after deletes, costs
+    /// one map entry per record, and is not persisted by `save`.
+    pub fn build_id_index(&mut self) {
+        self.id_index = Some(
+            self.storage
+                .data
+                .iter()
+                .enumerate()
+                .map(|(index, data)| (data.id.clone(), index))
+                .collect(),
+        );
+    }
+
+    /// Drops the id index, if any, so lookups by id scan again
+    pub fn drop_id_index(&mut self) {
+        self.id_index = None;
+    }
+
+    /// Distinct values of field `key` across the records, most common first
+    ///
+    /// Records without the field are skipped. See
+    /// [`field_value_counts`](Self::field_value_counts) for the counts.
+    pub fn distinct_field_values(&self, key: &str) -> Vec<serde_json::Value> {
+        self.field_value_counts(key)
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect()
+    }
+
+    /// Distinct values of field `key` with how many records hold each, most
+    /// common first
+    ///
+    /// Values are compared by their JSON representation, so `3` and `"3"` are
+    /// distinct; equal counts are ordered by that representation. Records are
+    /// scanned in parallel with the `parallel` feature.
+    pub fn field_value_counts(&self, key: &str) -> Vec<(serde_json::Value, usize)> {
+        type Counts = HashMap<String, (serde_json::Value, usize)>;
+        let count = |mut counts: Counts, idx: usize| {
+            if self.is_tombstoned(idx) {
+                return counts;
+            }
+            if let Some(value) = self.record(idx).fields.get(key) {
+                counts
+                    .entry(value.to_string())
+                    .or_insert_with(|| (value.clone(), 0))
+                    .1 += 1;
+            }
+            counts
+        };
+        let (parallel, _permit) = self.parallel_scan();
+        #[cfg(feature = "parallel")]
+        let counts = if parallel {
+            (0..self.len())
+                .into_par_iter()
+                .fold(Counts::new, count)
+                .reduce(Counts::new, |mut a, b| {
+                    for (text, (value, n)) in b {
+                        a.entry(text).or_insert((value, 0)).1 += n;
+                    }
+                    a
+                })
+        } else {
+            (0..self.len()).fold(Counts::new(), count)
+        };
+        #[cfg(not(feature = "parallel"))]
+        let counts = {
+            let _ = parallel;
+            (0..self.len()).fold(Counts::new(), count)
+        };
+
+        let mut counts: Vec<(String, (serde_json::Value, usize))> = counts.into_iter().collect();
+        counts.sort_by(|(a, (_, m)), (b, (_, n))| n.cmp(m).then_with(|| a.cmp(b)));
+        counts.into_iter().map(|(_, counted)| counted).collect()
+    }
+
+    /// Queries the database for similar vectors
+    ///
+    /// A query that cannot be normalized, such as a zero vector (or a constant
+    /// one under [`Metric::Pearson`]), matches nothing. The other query methods
+    /// do the same, or fail with [`NanoVectorDbError::ZeroVector`] where they
+    /// return a `Result`.
+    pub fn query(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| self.to_result(si))
+            .collect()
+    }
+
+    /// Queries the database like [`query`](Self::query), returning typed results
+    ///
+    /// Scores stay `f32` end to end instead of round-tripping through
+    /// `serde_json`'s `f64` numbers.
+    pub fn query_typed(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<QueryResult> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| self.to_typed_result(si))
+            .collect()
+    }
+
+    /// Approximate quantiles of the query's scores against every record, one
+    /// per entry of `quantiles` (each in `[0, 1]`)
+    ///
+    /// Scores are in the metric's own units, as taken by `better_than`:
+    /// similarities, or distances under a distance metric, without weights or
+    /// any [`ScoreTransform`]. Two streaming passes find the score range and then
+    /// count scores into [`SCORE_QUANTILE_BUCKETS`] equal buckets, so nothing is
+    /// sorted and memory stays fixed; each quantile is interpolated within its
+    /// bucket and is off by at most one bucket width. Returns an empty vector
+    /// when there are no records.
+    pub fn score_quantiles(&self, query: &[Float], quantiles: &[f64]) -> Vec<Float> {
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+        let sign = if self.metric.is_distance() { -1.0 } else { 1.0 };
+        let score = |idx: usize, row: &[Float]| {
+            (!self.is_tombstoned(idx)).then(|| sign * scorer.score(row))
+        };
+        let segments = self.storage.matrix.segments();
+        let (parallel, _permit) = self.parallel_scan();
+
+        let (min, max, count) = fold_rows(
+            &segments,
+            self.embedding_dim,
+            parallel,
+            || (Float::MAX, Float::MIN, 0usize),
+            |(min, max, count), (idx, row)| match score(idx, row) {
+                Some(s) => (min.min(s), max.max(s), count + 1),
+                None => (min, max, count),
+            },
+            |(min1, max1, count1), (min2, max2, count2)| {
+                (min1.min(min2), max1.max(max2), count1 + count2)
+            },
+        );
+        if count == 0 {
+            return Vec::new();
+        }
+        let width = (max - min) / SCORE_QUANTILE_BUCKETS as Float;
+        let bucket = |s: Float| {
+            if width > 0.0 {
+                (((s - min) / width) as usize).min(SCORE_QUANTILE_BUCKETS - 1)
+            } else {
+                0
+            }
+        };
+        let counts = fold_rows(
+            &segments,
+            self.embedding_dim,
+            parallel,
+            || vec![0usize; SCORE_QUANTILE_BUCKETS],
+            |mut counts, (idx, row)| {
+                if let Some(s) = score(idx, row) {
+                    counts[bucket(s)] += 1;
+                }
+                counts
+            },
+            |mut counts1, counts2| {
+                counts1.iter_mut().zip(counts2).for_each(|(a, b)| *a += b);
+                counts1
+            },
+        );
+
+        quantiles
+            .iter()
+            .map(|&q| {
+                // Fractional 0-based rank of the quantile among the sorted scores
+                let rank = q.clamp(0.0, 1.0) * (count - 1) as f64;
+                let mut below = 0;
+                for (index, &n) in counts.iter().enumerate() {
+                    if n > 0 && (below + n) as f64 > rank {
+                        let within = (rank - below as f64 + 0.5) / n as f64;
+                        return (min + width * (index as Float + within as Float)).clamp(min, max);
+                    }
+                    below += n;
+                }
+                max
+            })
+            .collect()
+    }
+
+    /// Queries the database like [`query_typed`](Self::query_typed), returning
+    /// the `top_k` worst matches first when `order` is [`Order::Worst`]
+    ///
+    /// Under a distance metric the worst matches are the largest distances.
+    /// `better_than` keeps its meaning either way: only records beating it are
+    /// ranked. Worst-first queries always scan the full f32 matrix, ignoring
+    /// approximate indexes and the query cache. Scores are reported as usual.
+    pub fn query_ordered(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+        order: Order,
+    ) -> Vec<QueryResult> {
+        if order == Order::Best {
+            return self.query_typed(query, top_k, better_than, filter);
+        }
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+        let keep = |idx: usize| filter.as_ref().is_none_or(|f| f(self.record(idx)));
+
+        // Ranking negated scores puts the worst matches at the top of the heap
+        let heap = self.scan_rows(
+            &self.storage.matrix.segments(),
+            |idx, vector, _| {
+                let score = scorer.score(vector);
+                (self.weighted(idx, score) >= threshold).then_some(-score)
+            },
+            top_k,
+            Float::MIN,
+            &keep,
+        );
+        let sorted = heap.into_sorted();
+        sorted
+            .iter()
+            .map(|si| {
+                self.to_typed_result(&ScoredIndex {
+                    score: -si.score,
+                    index: si.index,
+                })
+            })
+            .collect()
+    }
+
+    /// Queries the database with a caller-supplied scorer instead of the metric
+    ///
+    /// `scorer` receives the query and each stored vector (both normalized
+    /// under [`Metric::Cosine`]) and returns a score; higher scores rank first
+    /// unless `lower_is_better` is set. `better_than` bounds the scores the same
+    /// way. Reported scores are the scorer's own, without any [`ScoreTransform`]
+    /// or record weight.
+    pub fn query_custom(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        scorer: impl Fn(&[Float], &[Float]) -> Float + Send + Sync,
+        lower_is_better: bool,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<QueryResult> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let sign = if lower_is_better { -1.0 } else { 1.0 };
+        let threshold = better_than.map_or(Float::MIN, |value| sign * value);
+        let keep = |idx: usize| filter.as_ref().is_none_or(|f| f(self.record(idx)));
+        // Record weights are defined for the metric's scores, not custom ones
+        let heap = self.scan_rows_with(
+            &self.storage.matrix.segments(),
+            |_, vector, _| Some(sign * scorer(&query_norm, vector)),
+            top_k,
+            threshold,
+            &keep,
+            false,
+        );
+
+        let sorted = heap.into_sorted();
+        sorted
+            .iter()
+            .map(|si| {
+                let data = self.record(si.index);
+                QueryResult {
+                    id: data.id.clone(),
+                    score: sign * si.score,
+                    fields: data.fields.clone(),
+                    index: si.index,
+                }
+            })
+            .collect()
+    }
+
+    /// Queries the database like [`query_typed`](Self::query_typed), building
+    /// each result only when the iterator reaches it
+    ///
+    /// Global ranking still needs the full scan, so the scored indices are
+    /// materialized up front; what is deferred is cloning each hit's id and
+    /// fields, which dominates for large `top_k`. Results come best first.
+    pub fn query_stream(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> impl Iterator<Item = QueryResult> + '_ {
+        let scored = match self.prepare_query(query) {
+            Ok(query_norm) if !self.is_empty() => {
+                let threshold = self.threshold(better_than);
+                self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            }
+            _ => Vec::new(),
+        };
+        scored.into_iter().map(|si| self.to_typed_result(&si))
+    }
+
+    /// Returns the single best match, like the first result of
+    /// [`query_typed`](Self::query_typed) with `top_k = 1`, or `None` if no
+    /// record passes `better_than` and `filter`
+    pub fn nearest(
+        &self,
+        query: &[Float],
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Option<QueryResult> {
+        if self.is_empty() {
+            return None;
+        }
+        let query_norm = self.prepare_query(query).ok()?;
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, 1, threshold, filter.as_ref())
+            .first()
+            .map(|si| self.to_typed_result(si))
+    }
+
+    /// Queries the database like [`query_typed`](Self::query_typed), additionally
+    /// comparing the query with each hit's stored vector under every metric
+    ///
+    /// The comparisons use the query as given and the stored (normalized)
+    /// vector, costing an extra pass over `embedding_dim` values per hit.
+    pub fn query_diagnostics(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<(QueryResult, ScoreDiagnostics)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| {
+                let vector = self.vector_at(si.index).unwrap_or_default();
+                (
+                    self.to_typed_result(si),
+                    ScoreDiagnostics::compute(query, vector),
+                )
+            })
+            .collect()
+    }
+
+    /// Queries the database with several query vectors at once, ranking each
+    /// record by its scores against all of them combined with `agg`
+    ///
+    /// Meant for query expansion, e.g. an original query plus paraphrases. Every
+    /// query is prepared like in [`query`](Self::query), and `better_than`
+    /// applies to the combined score. Always scans the matrix exactly, at the
+    /// cost of one score per query vector per record.
+    pub fn query_multi(
+        &self,
+        queries: &[Vec<Float>],
+        agg: QueryAgg,
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<QueryResult> {
+        if self.is_empty() || queries.is_empty() {
+            return Vec::new();
+        }
+        let Ok(prepared) = queries
+            .iter()
+            .map(|q| self.prepare_query(q))
+            .collect::<Result<Vec<_>>>()
+        else {
+            return Vec::new();
+        };
+        let scorers: Vec<QueryScorer> = prepared
+            .iter()
+            .map(|q| QueryScorer::new(self.metric, q, self.f64_accumulation))
+            .collect();
+        let threshold = self.threshold(better_than);
+
+        let score = |vector: &[Float]| -> Float {
+            let scores = scorers.iter().map(|scorer| scorer.score(vector));
+            match agg {
+                QueryAgg::Max => scores.fold(Float::MIN, Float::max),
+                QueryAgg::Mean => scores.sum::<Float>() / scorers.len() as Float,
+            }
+        };
+        let keep = |idx: usize| filter.as_ref().is_none_or(|f| f(self.record(idx)));
+        let heap = self.scan_rows(
+            &self.storage.matrix.segments(),
+            |_, vector, _| Some(score(vector)),
+            top_k,
+            threshold,
+            &keep,
+        );
+
+        let sorted = heap.into_sorted();
+        sorted.iter().map(|si| self.to_typed_result(si)).collect()
+    }
+
+    /// Queries the database like [`query_typed`](Self::query_typed), scoring only
+    /// the dimensions where `mask` is `true`
+    ///
+    /// Masked-out dimensions are ignored in both the query and the stored vectors,
+    /// so the metric is computed in the unmasked subspace. Stored vectors keep
+    /// their full-space normalization. Always scans the matrix exactly, bypassing
+    /// any LSH or quantized index. Fails unless `mask` has `embedding_dim` entries.
+    pub fn query_masked(
+        &self,
+        query: &[Float],
+        mask: &[bool],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Result<Vec<QueryResult>> {
+        if mask.len() != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: mask.len(),
+            });
+        }
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query_norm = self.prepare_query(query)?;
+        let threshold = self.threshold(better_than);
+        let dims: Vec<usize> = (0..mask.len()).filter(|&d| mask[d]).collect();
+
+        let metric = self.metric;
+        let score = |vector: &[Float]| -> Float {
+            let pairs = dims.iter().map(|&d| (vector[d], query_norm[d]));
+            match metric {
+                Metric::Cosine | Metric::Pearson | Metric::Dot => pairs.map(|(v, q)| v * q).sum(),
+                Metric::L2 => -pairs.map(|(v, q)| (v - q) * (v - q)).sum::<Float>().sqrt(),
+                Metric::Manhattan => -pairs.map(|(v, q)| (v - q).abs()).sum::<Float>(),
+            }
+        };
+        let keep = |idx: usize| filter.as_ref().is_none_or(|f| f(self.record(idx)));
+        let heap = self.scan_rows(
+            &self.storage.matrix.segments(),
+            |_, vector, _| Some(score(vector)),
+            top_k,
+            threshold,
+            &keep,
+        );
+
+        let sorted = heap.into_sorted();
+        Ok(sorted.iter().map(|si| self.to_typed_result(si)).collect())
+    }
+
+    /// Queries the database as if `bias` were added to every stored vector
+    ///
+    /// Shifts the space for one query (e.g. towards a user's preferences)
+    /// without re-storing anything. Under [`Metric::Cosine`] and
+    /// [`Metric::Pearson`] each shifted vector is normalized again, and rows
+    /// that shift to zero length are skipped. Every row is shifted on the fly, so
+    /// this always costs a full scan with an extra pass and allocation per row,
+    /// bypassing the LSH, IVF and quantized indexes and the query cache. Fails
+    /// if `bias` does not have `embedding_dim` values.
+    pub fn query_biased(
+        &self,
+        query: &[Float],
+        bias: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Result<Vec<QueryResult>> {
+        if bias.len() != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: bias.len(),
+            });
+        }
+        if self.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query_norm = self.prepare_query(query)?;
+        let threshold = self.threshold(better_than);
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+
+        let score = |vector: &[Float]| -> Option<Float> {
+            let shifted: Vec<Float> = vector.iter().zip(bias).map(|(v, b)| v + b).collect();
+            if self.normalizes() {
+                let shifted = self.metric.normalize(&shifted, self.norm_epsilon).ok()?;
+                return Some(scorer.score(&shifted));
+            }
+            Some(scorer.score(&shifted))
+        };
+        let keep = |idx: usize| filter.as_ref().is_none_or(|f| f(self.record(idx)));
+        let heap = self.scan_rows(
+            &self.storage.matrix.segments(),
+            |_, vector, _| score(vector),
+            top_k,
+            threshold,
+            &keep,
+        );
+
+        let sorted = heap.into_sorted();
+        Ok(sorted.iter().map(|si| self.to_typed_result(si)).collect())
+    }
+
+    /// Queries the database like [`query_typed`](Self::query_typed), giving up
+    /// early once `cancel` is set
+    ///
+    /// The scan checks the flag before scoring each row, so a query abandoned by
+    /// its caller stops burning CPU. Returns the hits found before cancellation
+    /// (possibly none) and whether the query was cancelled.
+    pub fn query_cancellable(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+        cancel: Arc<AtomicBool>,
+    ) -> (Vec<QueryResult>, bool) {
+        if self.is_empty() {
+            return (Vec::new(), cancel.load(atomic::Ordering::Relaxed));
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return (Vec::new(), cancel.load(atomic::Ordering::Relaxed));
+        };
+        let threshold = self.threshold(better_than);
+
+        let scored = self.top_k_scored_cancellable(
+            &query_norm,
+            top_k,
+            threshold,
+            filter.as_ref(),
+            Some(&cancel),
+        );
+        let results = scored.iter().map(|si| self.to_typed_result(si)).collect();
+        (results, cancel.load(atomic::Ordering::Relaxed))
+    }
+
+    /// Queries a named embedding space stored with
+    /// [`upsert_named_vectors`](Self::upsert_named_vectors), returning typed
+    /// results like [`query_typed`](Self::query_typed)
+    ///
+    /// Records without a vector in `field` never match. Fails if the field is
+    /// unknown or the query has the wrong dimension for it.
+    pub fn query_field(
+        &self,
+        field: &str,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Result<Vec<QueryResult>> {
+        let Some(named) = self.storage.named_vectors.get(field) else {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Unknown vector field \"{}\"",
+                field
+            )));
+        };
+        if query.len() != named.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: named.embedding_dim,
+                got: query.len(),
+            });
+        }
+        if named.ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let query_norm = self.prepare_query(query)?;
+        let threshold = self.threshold(better_than);
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+
+        let positions: HashMap<&str, usize> = self
+            .storage
+            .data
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| !self.is_tombstoned(index))
+            .map(|(index, data)| (data.id.as_str(), index))
+            .collect();
+        let mut heap = TopK::new(top_k, self.score_epsilon);
+        for (id, vector) in named
+            .ids
+            .iter()
+            .zip(named.matrix.chunks_exact(named.embedding_dim))
+        {
+            let Some(&index) = positions.get(id.as_str()) else {
+                continue;
+            };
+            if !filter
+                .as_ref()
+                .map(|f| f(self.record(index)))
+                .unwrap_or(true)
+            {
+                continue;
+            }
+            let score = self.weighted(index, scorer.score(vector));
+            if score >= threshold {
+                heap.push(ScoredIndex { score, index });
+            }
+        }
+
+        let sorted = heap.into_sorted();
+        Ok(sorted.iter().map(|si| self.to_typed_result(si)).collect())
+    }
+
+    /// Queries the database like [`query`](Self::query), borrowing matching records
+    /// instead of cloning their fields
+    pub fn query_ref(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<QueryHit<'_>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| QueryHit {
+                data: self.record(si.index),
+                score: self.reported_score(si.score),
+            })
+            .collect()
+    }
+
+    /// Queries the database like [`query`](Self::query), additionally including the
+    /// stored (normalized) vector of each hit under [`constants::F_VECTOR`]
+    ///
+    /// Each hit copies `embedding_dim` floats out of the matrix into a JSON array,
+    /// so this allocates noticeably more than `query` for large dimensions.
+    pub fn query_full(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| {
+                let mut result = self.to_result(si);
+                let start = si.index * self.embedding_dim;
+                let vector = &self.storage.matrix[start..start + self.embedding_dim];
+                result.insert(constants::F_VECTOR.to_string(), serde_json::json!(vector));
+                result
+            })
+            .collect()
+    }
+
+    /// Queries the database like [`query`](Self::query), including only the
+    /// fields named in `project` (plus the id and score) in each result
+    ///
+    /// Only the projected values are cloned. Fields a record lacks are omitted.
+    pub fn query_projected(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+        project: &[String],
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+            .iter()
+            .map(|si| {
+                let data = self.record(si.index);
+                let mut result: HashMap<String, serde_json::Value> = project
+                    .iter()
+                    .filter_map(|key| Some((key.clone(), data.fields.get(key)?.clone())))
+                    .collect();
+                result.insert(
+                    self.metrics_field.clone(),
+                    serde_json::json!(self.reported_score(si.score)),
+                );
+                result.insert(self.id_field.clone(), serde_json::json!(data.id));
+                result
+            })
+            .collect()
+    }
+
+    /// Queries the database, keeping only hits scoring at least `fraction_of_top`
+    /// times the best score for this query
+    ///
+    /// Unlike a fixed `better_than`, the cutoff adapts to each query's score
+    /// distribution. It is intended for positive top scores (the usual case for
+    /// cosine similarity of related vectors). Under distance metrics the cut is
+    /// made on the raw distance instead: hits at most the best distance divided
+    /// by `fraction_of_top` are kept.
+    pub fn query_relative(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        fraction_of_top: Float,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let scored = self.top_k_scored(&query_norm, top_k, Float::MIN, filter.as_ref());
+
+        let Some(best) = scored.first().map(|si| si.score) else {
+            return Vec::new();
+        };
+        // Distances are ranked negated, so compare `distance * fraction <= best`
+        let passes = |score: Float| {
+            if self.metric.is_distance() {
+                score * fraction_of_top >= best
+            } else {
+                score >= best * fraction_of_top
+            }
+        };
+
+        scored
+            .iter()
+            .take_while(|si| passes(si.score))
+            .map(|si| self.to_result(si))
+            .collect()
+    }
+
+    /// Queries the database like [`query`](Self::query), backfilling with the
+    /// next-best records when fewer than `min_results` pass `better_than`
+    ///
+    /// Each hit is paired with whether it met the threshold; backfilled hits are
+    /// flagged `false`. At most `max(top_k, min_results)` hits are returned.
+    pub fn query_min(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        min_results: usize,
+        filter: Option<DataFilter>,
+    ) -> Vec<(HashMap<String, serde_json::Value>, bool)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let scored = self.top_k_scored(
+            &query_norm,
+            top_k.max(min_results),
+            Float::MIN,
+            filter.as_ref(),
+        );
+
+        // Scores are sorted, so the hits passing the threshold form a prefix
+        let passing = scored
+            .iter()
+            .take(top_k)
+            .take_while(|si| si.score >= threshold)
+            .count();
+        scored
+            .iter()
+            .take(passing.max(min_results))
+            .enumerate()
+            .map(|(rank, si)| (self.to_result(si), rank < passing))
+            .collect()
+    }
+
+    /// Queries the database for up to `max_k` hits, cut at the largest score
+    /// drop between consecutive hits
+    ///
+    /// Useful when the number of relevant records is unknown: a tight cluster of
+    /// good matches followed by a gap yields just the cluster.
+    pub fn query_auto_k(
+        &self,
+        query: &[Float],
+        max_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let scored = self.top_k_scored(&query_norm, max_k, threshold, filter.as_ref());
+
+        // The elbow is after the hit with the largest drop to its successor
+        let cutoff = scored
+            .windows(2)
+            .enumerate()
+            .max_by(|(_, a), (_, b)| {
+                (a[0].score - a[1].score).total_cmp(&(b[0].score - b[1].score))
+            })
+            .map_or(scored.len(), |(i, _)| i + 1);
+
+        scored[..cutoff]
+            .iter()
+            .map(|si| self.to_result(si))
+            .collect()
+    }
+
+    /// Queries the database for the best `per_group` results within each distinct
+    /// value of `fields[group_key]`
+    ///
+    /// Records without `group_key` are skipped. Results from all groups are
+    /// returned together, sorted best first.
+    pub fn query_grouped(
+        &self,
+        query: &[Float],
+        group_key: &str,
+        per_group: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+
+        // One bounded heap per group, keyed by the group value's JSON representation
+        let (parallel, _permit) = self.parallel_scan();
+        let groups = fold_rows(
+            &self.storage.matrix.segments(),
+            self.embedding_dim,
+            parallel,
+            HashMap::<String, TopK>::new,
+            |mut groups, (idx, vector)| {
+                if self.is_tombstoned(idx) {
+                    return groups;
+                }
+                let record = self.record(idx);
+                let Some(group) = record.fields.get(group_key) else {
+                    return groups;
+                };
+                if !filter.as_ref().map(|f| f(record)).unwrap_or(true) {
+                    return groups;
+                }
+                let score = self.weighted(idx, scorer.score(vector));
+                if score >= threshold {
+                    groups
+                        .entry(group.to_string())
+                        .or_insert_with(|| TopK::new(per_group, self.score_epsilon))
+                        .push(ScoredIndex { score, index: idx });
+                }
+                groups
+            },
+            |mut groups1, groups2| {
+                for (group, heap) in groups2 {
+                    let merged = match groups1.remove(&group) {
+                        Some(target) => target.merge(heap),
+                        None => heap,
+                    };
+                    groups1.insert(group, merged);
+                }
+                groups1
+            },
+        );
+
+        let mut scored: Vec<ScoredIndex> =
+            groups.into_values().flat_map(TopK::into_sorted).collect();
+        scored.sort();
+        order_ties(&mut scored, self.score_epsilon);
+        scored.iter().map(|si| self.to_result(si)).collect()
+    }
+
+    /// Queries the database using maximal marginal relevance (MMR) re-ranking
+    ///
+    /// Candidates are the best `4 * top_k` records by the configured metric. From
+    /// those, results are picked greedily by
+    /// `lambda * sim(query, d) - (1 - lambda) * max(sim(d, selected))`, with cosine
+    /// similarity as `sim`. `lambda = 1.0` is plain relevance ranking; lower values
+    /// favour diversity.
+    pub fn query_mmr(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        lambda: Float,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() || top_k == 0 {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let candidates = self.top_k_scored(&query_norm, top_k * 4, Float::MIN, filter.as_ref());
+
+        let relevance: Vec<Float> = candidates
+            .iter()
+            .map(|si| cosine_similarity(&query_norm, self.vector_at(si.index).unwrap()))
+            .collect();
+        let mut remaining: Vec<usize> = (0..candidates.len()).collect();
+        let mut selected: Vec<usize> = Vec::with_capacity(top_k);
+
+        while selected.len() < top_k && !remaining.is_empty() {
+            let mmr_score = |&c: &usize| {
+                let vector = self.vector_at(candidates[c].index).unwrap();
+                let redundancy = selected
+                    .iter()
+                    .map(|&s| {
+                        cosine_similarity(vector, self.vector_at(candidates[s].index).unwrap())
+                    })
+                    .reduce(Float::max)
+                    .unwrap_or(0.0);
+                lambda * relevance[c] - (1.0 - lambda) * redundancy
+            };
+            let (pos, _) = remaining
+                .iter()
+                .map(mmr_score)
+                .enumerate()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .unwrap();
+            selected.push(remaining.remove(pos));
+        }
+
+        selected
+            .iter()
+            .map(|&c| self.to_result(&candidates[c]))
+            .collect()
+    }
+
+    /// Queries only the records whose storage indices are set in `allowed`
+    ///
+    /// Avoids per-record filter closures when the allowed set is already known.
+    /// Storage indices are only stable between mutations: deletes shift the
+    /// indices of every later record, so bitmaps must be rebuilt after deleting.
+    /// Indices beyond the end of the database are ignored.
+    #[cfg(feature = "roaring")]
+    pub fn query_bitmap(
+        &self,
+        query: &[Float],
+        allowed: &roaring::RoaringBitmap,
+        top_k: usize,
+        better_than: Option<Float>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+
+        self.top_k_among(
+            &scorer,
+            allowed.iter().map(|idx| idx as usize),
+            top_k,
+            threshold,
+            None,
+        )
+        .iter()
+        .map(|si| self.to_result(si))
+        .collect()
+    }
+
+    /// Queries only the records whose field `key` equals `value`
+    ///
+    /// With an index built by [`build_field_index`](Self::build_field_index),
+    /// only the matching records are scored, exactly (bypassing LSH and
+    /// quantization); otherwise every record is checked like a `query` filter.
+    /// Values compare as JSON, so `3` does not match `"3"`.
+    pub fn query_where(
+        &self,
+        query: &[Float],
+        key: &str,
+        value: &serde_json::Value,
+        top_k: usize,
+        better_than: Option<Float>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        let scored = match self.field_indexes.get(key) {
+            Some(index) => {
+                let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+                let indices = index.lookup(value).iter().copied();
+                self.top_k_among(&scorer, indices, top_k, threshold, None)
+            }
+            None => {
+                let (key, value) = (key.to_string(), value.clone());
+                let filter: DataFilter = Box::new(move |d| d.fields.get(&key) == Some(&value));
+                self.top_k_scored(&query_norm, top_k, threshold, Some(&filter))
+            }
+        };
+        scored.iter().map(|si| self.to_result(si)).collect()
+    }
+
+    /// Queries only the records whose field `key` is a number within `range`
+    ///
+    /// Records lacking the field or holding a non-numeric value (including
+    /// numeric strings) are excluded. With an index built by
+    /// [`build_field_index`](Self::build_field_index), the matching records are
+    /// found by binary search over the field's sorted numbers and scored exactly,
+    /// like [`query_where`](Self::query_where); otherwise every record is checked.
+    pub fn query_range(
+        &self,
+        query: &[Float],
+        key: &str,
+        range: RangeInclusive<f64>,
+        top_k: usize,
+        better_than: Option<Float>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+
+        let scored = match self.field_indexes.get(key) {
+            Some(index) => {
+                let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+                let indices = index.lookup_range(&range);
+                self.top_k_among(&scorer, indices, top_k, threshold, None)
+            }
+            None => {
+                let key = key.to_string();
+                let filter: DataFilter = Box::new(move |d| {
+                    d.fields
+                        .get(&key)
+                        .and_then(serde_json::Value::as_f64)
+                        .is_some_and(|n| range.contains(&n))
+                });
+                self.top_k_scored(&query_norm, top_k, threshold, Some(&filter))
+            }
+        };
+        scored.iter().map(|si| self.to_result(si)).collect()
+    }
+
+    /// Queries only the records written after `since`, as stamped by
+    /// [`with_timestamps`](Self::with_timestamps)
+    ///
+    /// Records without a timestamp are excluded. With an index on
+    /// [`F_TIMESTAMP`](constants::F_TIMESTAMP) built by
+    /// [`build_field_index`](Self::build_field_index), only the recent records
+    /// are looked up and scored, like [`query_range`](Self::query_range);
+    /// otherwise every record's timestamp is checked during the scan.
+    pub fn query_since(
+        &self,
+        query: &[Float],
+        since: Timestamp,
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<QueryResult> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let Ok(query_norm) = self.prepare_query(query) else {
+            return Vec::new();
+        };
+        let threshold = self.threshold(better_than);
+        let is_recent = move |d: &Data| {
+            d.fields
+                .get(constants::F_TIMESTAMP)
+                .and_then(serde_json::Value::as_u64)
+                .is_some_and(|t| t > since)
+        };
+
+        let scored = match self.field_indexes.get(constants::F_TIMESTAMP) {
+            Some(index) => {
+                let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+                let indices = index.lookup_range(&(since as f64 + 1.0..=f64::INFINITY));
+                self.top_k_among(&scorer, indices, top_k, threshold, filter.as_ref())
+            }
+            None => {
+                let filter: DataFilter =
+                    Box::new(move |d| is_recent(d) && filter.as_ref().is_none_or(|f| f(d)));
+                self.top_k_scored(&query_norm, top_k, threshold, Some(&filter))
+            }
+        };
+        scored.iter().map(|si| self.to_typed_result(si)).collect()
+    }
+
+    /// Compares two arbitrary vectors with the configured metric
+    ///
+    /// Returns the cosine similarity, dot product or distance as for stored
+    /// records, before any [`ScoreTransform`]. Both vectors must have
+    /// `embedding_dim` values.
+    pub fn similarity(&self, a: &[Float], b: &[Float]) -> Result<Float> {
+        for vector in [a, b] {
+            if vector.len() != self.embedding_dim {
+                return Err(NanoVectorDbError::DimensionMismatch {
+                    expected: self.embedding_dim,
+                    got: vector.len(),
+                });
+            }
+        }
+        Ok(match self.metric {
+            Metric::Cosine | Metric::Pearson => {
+                let a = self.metric.normalize(a, self.norm_epsilon)?;
+                let b = self.metric.normalize(b, self.norm_epsilon)?;
+                dot_product_f64_acc(&a, &b)
+            }
+            Metric::Dot => dot_product_f64_acc(a, b),
+            Metric::L2 => l2_distance(a, b),
+            Metric::Manhattan => manhattan_distance(a, b),
+        })
+    }
+
+    /// Index of the centroid closest to `query` under the configured metric
+    ///
+    /// A building block for IVF-style routing: with one database (or shard) per
+    /// centroid, send the query to the partition this returns. Centroids are
+    /// normalized like stored vectors; ties go to the lowest index. Fails if
+    /// there are no centroids or any vector has the wrong dimension.
+    pub fn nearest_centroid(&self, query: &[Float], centroids: &[Vec<Float>]) -> Result<usize> {
+        if query.len() != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: query.len(),
+            });
+        }
+        validate_dim(centroids, self.embedding_dim)?;
+        if centroids.is_empty() {
+            return Err(NanoVectorDbError::InvalidInput(
+                "No centroids to route to".to_string(),
+            ));
+        }
+
+        let prepare = |vector: &[Float]| {
+            if self.normalizes() {
+                self.metric.normalize(vector, self.norm_epsilon)
+            } else {
+                Ok(vector.to_vec())
+            }
+        };
+        let query_norm = prepare(query)?;
+        let scorer = QueryScorer::new(self.metric, &query_norm, self.f64_accumulation);
+        let mut best = (0, Float::MIN);
+        for (index, centroid) in centroids.iter().enumerate() {
+            let score = scorer.score(&prepare(centroid)?);
+            if score > best.1 {
+                best = (index, score);
+            }
+        }
+        Ok(best.0)
+    }
+
+    /// Finds the records that have a near-duplicate among the stored vectors
+    ///
+    /// Two records are near-duplicates when their score under the configured
+    /// metric is better than `threshold`, read like a query's `better_than`
+    /// (at least that similarity, or at most that distance); record weights are
+    /// ignored. Every pair of records is compared, so the cost is quadratic in
+    /// the number of records: the rows are split across Rayon's thread pool with
+    /// the `parallel` feature, but expect minutes rather than seconds beyond a
+    /// few hundred thousand records.
+    pub fn duplication_report(&self, threshold: Float) -> DuplicationStats {
+        let dim = self.embedding_dim;
+        let threshold = self.threshold(Some(threshold));
+        let (parallel, _permit) = self.parallel_scan();
+        let pairs = fold_rows(
+            &self.storage.matrix.segments(),
+            dim,
+            parallel,
+            Vec::new,
+            |mut pairs, (i, row)| {
+                let scorer = QueryScorer::new(self.metric, row, self.f64_accumulation);
+                for (j, other) in self.storage.matrix.chunks(dim).enumerate().skip(i + 1) {
+                    if scorer.score(other) >= threshold {
+                        pairs.push((i, j));
+                    }
+                }
+                pairs
+            },
+            |mut pairs1, pairs2| {
+                pairs1.extend(pairs2);
+                pairs1
+            },
+        );
+
+        // Union-find over the pairs, linking each root to its smallest member
+        let mut parent: Vec<usize> = (0..self.len()).collect();
+        for (i, j) in pairs {
+            let (a, b) = (find_root(&mut parent, i), find_root(&mut parent, j));
+            parent[a.max(b)] = a.min(b);
+        }
+        let mut clusters: HashMap<usize, Vec<String>> = HashMap::new();
+        for i in 0..self.len() {
+            let r = find_root(&mut parent, i);
+            clusters
+                .entry(r)
+                .or_default()
+                .push(self.storage.data[i].id.clone());
+        }
+        let mut clusters: Vec<(usize, Vec<String>)> = clusters
+            .into_iter()
+            .filter(|(_, ids)| ids.len() > 1)
+            .collect();
+        clusters.sort_unstable_by_key(|(r, _)| *r);
+
+        DuplicationStats {
+            total: self.len(),
+            duplicated: clusters.iter().map(|(_, ids)| ids.len()).sum(),
+            clusters: clusters.into_iter().map(|(_, ids)| ids).collect(),
+        }
+    }
+
+    /// Normalizes the query if the metric requires it
+    ///
+    /// Fails with [`NanoVectorDbError::ZeroVector`] for a query too short to
+    /// normalize, e.g. a zero vector, or a constant one under [`Metric::Pearson`].
+    fn prepare_query(&self, query: &[Float]) -> Result<Vec<Float>> {
+        if self.normalizes() {
+            self.metric.normalize(query, self.norm_epsilon)
+        } else {
+            Ok(query.to_vec())
+        }
+    }
+
+    /// Converts a user-facing `better_than` into a ranking score threshold
+    ///
+    /// Distances are ranked by their negation, so "better than" a distance
+    /// means at most that distance. Scores within the configured epsilon of the
+    /// threshold are included.
+    fn threshold(&self, better_than: Option<Float>) -> Float {
+        match better_than {
+            Some(value) if self.metric.is_distance() => -value - self.score_epsilon,
+            Some(value) => value - self.score_epsilon,
+            None => Float::MIN,
+        }
+    }
+
+    /// Converts a ranking score into the value reported in `__metrics__`
+    fn reported_score(&self, score: Float) -> Float {
+        if self.metric.is_distance() {
+            self.score_transform.apply(-score)
+        } else if self.angular_degrees && self.metric.normalizes() {
+            // Rounding can push a cosine just past ±1, where acos is NaN
+            score.clamp(-1.0, 1.0).acos().to_degrees()
+        } else {
+            score
+        }
+    }
+
+    /// Scans the matrix and returns the best `top_k` scores, sorted best first
+    fn top_k_scored(
+        &self,
+        query_norm: &[Float],
+        top_k: usize,
+        threshold: Float,
+        filter: Option<&DataFilter>,
+    ) -> Vec<ScoredIndex> {
+        let start = Instant::now();
+        let scored = self.top_k_scored_cached(query_norm, top_k, threshold, filter);
+        let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.last_query_nanos
+            .store(nanos.max(1), atomic::Ordering::Relaxed);
+        scored
+    }
+
+    /// Looks the scan up in the query cache, if enabled and unfiltered
+    fn top_k_scored_cached(
+        &self,
+        query_norm: &[Float],
+        top_k: usize,
+        threshold: Float,
+        filter: Option<&DataFilter>,
+    ) -> Vec<ScoredIndex> {
+        if filter.is_some() || self.query_cache.is_none() {
+            return self.top_k_scored_with_ties(query_norm, top_k, threshold, filter);
+        }
+        let key = CacheKey::new(query_norm, top_k, threshold);
+        if let Some(scored) = self.lock_query_cache().and_then(|mut c| c.get(&key)) {
+            return scored;
+        }
+        let scored = self.top_k_scored_with_ties(query_norm, top_k, threshold, None);
+        if let Some(mut cache) = self.lock_query_cache() {
+            cache.insert(key, scored.clone());
+        }
+        scored
+    }
+
+    /// Scans for the best `top_k` scores, extended by every record tied with the
+    /// last one if [`with_boundary_ties`](Self::with_boundary_ties) is set
+    fn top_k_scored_with_ties(
+        &self,
+        query_norm: &[Float],
+        top_k: usize,
+        threshold: Float,
+        filter: Option<&DataFilter>,
+    ) -> Vec<ScoredIndex> {
+        let scored = self.top_k_scored_cancellable(query_norm, top_k, threshold, filter, None);
+        match scored.last() {
+            // Rescan for everything at least as good as the boundary score
+            Some(boundary) if self.boundary_ties && scored.len() == top_k => {
+                let tied = boundary.score - self.score_epsilon;
+                self.top_k_scored_cancellable(
+                    query_norm,
+                    usize::MAX,
+                    threshold.max(tied),
+                    filter,
+                    None,
+                )
+            }
+            _ => scored,
+        }
+    }
+
+    /// Like [`top_k_scored`](Self::top_k_scored), skipping every row left once
+    /// `cancel` is set
+    fn top_k_scored_cancellable(
+        &self,
+        query_norm: &[Float],
+        top_k: usize,
+        threshold: Float,
+        filter: Option<&DataFilter>,
+        cancel: Option<&AtomicBool>,
+    ) -> Vec<ScoredIndex> {
+        let cancelled = || cancel.is_some_and(|c| c.load(atomic::Ordering::Relaxed));
+        if cancelled() {
+            return Vec::new();
+        }
+        let matrix = &self.storage.matrix.segments();
+        let scorer = QueryScorer::new(self.metric, query_norm, self.f64_accumulation);
+
+        // With LSH, only the records sharing a bucket with the query are scored
+        if let Some(lsh) = &self.lsh {
+            let candidates = lsh.candidates(query_norm);
+            return self.top_k_among(&scorer, candidates, top_k, threshold, filter);
+        }
+        if let Some(ivf) = &self.ivf {
+            let candidates = ivf.candidates(query_norm, self.ivf_nprobe);
+            return self.top_k_among(&scorer, candidates, top_k, threshold, filter);
+        }
+
+        // In filter-first mode the scoring pass only reads the matrix and a mask
+        let mask = filter
+            .filter(|_| self.filter_first)
+            .map(|f| self.filter_mask(f));
+        let keep = |idx: usize| match (&mask, filter) {
+            _ if cancelled() => false,
+            (Some(mask), _) => mask[idx],
+            (None, Some(f)) => f(self.record(idx)),
+            (None, None) => true,
+        };
+
+        let heap = match &self.quant {
+            Some(quant) if !self.metric.is_distance() => {
+                let candidates = match self.rerank_budget {
+                    _ if !self.rerank => top_k,
+                    Some(budget) => budget.max(top_k),
+                    None => top_k.saturating_mul(RERANK_OVERSAMPLE),
+                };
+                let heap = self.scan_rows(
+                    &[quant.codes.as_slice()],
+                    |idx, codes, _| Some(quant.dot(idx, codes, query_norm)),
+                    candidates,
+                    threshold,
+                    &keep,
+                );
+                if self.rerank {
+                    let indices = heap.into_iter().map(|si| si.index);
+                    return self.top_k_among(&scorer, indices, top_k, threshold, None);
+                }
+                heap
+            }
+            _ if self.pruned_scoring
+                && self.unit_rows
+                && self.metric == Metric::Cosine
+                && self.normalize =>
+            {
+                let pruned = PrunedScorer::new(query_norm);
+                self.scan_rows(
+                    matrix,
+                    |_, vector, floor| pruned.score(vector, floor),
+                    top_k,
+                    threshold,
+                    &keep,
+                )
+            }
+            _ => self.scan_rows(
+                matrix,
+                |_, vector, _| Some(scorer.score(vector)),
+                top_k,
+                threshold,
+                &keep,
+            ),
+        };
+
+        // Convert to sorted results
+        heap.into_sorted()
+    }
+
+    /// Whether a scan may run on the Rayon pool, with the permit to hold while
+    /// it does
+    ///
+    /// Under [`with_max_parallel_queries`](Self::with_max_parallel_queries),
+    /// scans past the limit and scans started from a pool thread (nested in
+    /// another parallel task) run serially on the calling thread instead.
+    fn parallel_scan(&self) -> (bool, Option<ParallelPermit<'_>>) {
+        let Some(limit) = &self.parallel_limit else {
+            return (true, None);
+        };
+        #[cfg(feature = "parallel")]
+        if rayon::current_thread_index().is_some() {
+            return (false, None);
+        }
+        let permit = limit.try_acquire();
+        (permit.is_some(), permit)
+    }
+
+    /// Evaluates `filter` on every record, in storage order
+    fn filter_mask(&self, filter: &DataFilter) -> Vec<bool> {
+        let (parallel, _permit) = self.parallel_scan();
+        #[cfg(feature = "parallel")]
+        if parallel {
+            return (0..self.len())
+                .into_par_iter()
+                .map(|idx| filter(self.record(idx)))
+                .collect();
+        }
+        #[cfg(not(feature = "parallel"))]
+        let _ = parallel;
+        (0..self.len())
+            .map(|idx| filter(self.record(idx)))
+            .collect()
+    }
+
+    /// Folds every row of the `rows` segments kept by `keep` into a bounded heap
+    /// of the best `top_k` scores
+    ///
+    /// `score` receives the lowest score that could still enter the heap and may
+    /// return `None` for rows that provably score below it. Scores are weighted
+    /// by [`set_weight`](Self::set_weight) before ranking.
+    fn scan_rows<E: Sync>(
+        &self,
+        rows: &[&[E]],
+        score: impl Fn(usize, &[E], Float) -> Option<Float> + Send + Sync,
+        top_k: usize,
+        threshold: Float,
+        keep: &(impl Fn(usize) -> bool + Sync),
+    ) -> TopK {
+        self.scan_rows_with(rows, score, top_k, threshold, keep, true)
+    }
+
+    /// Like [`scan_rows`](Self::scan_rows), applying record weights only if
+    /// `weighted` is set
+    fn scan_rows_with<E: Sync>(
+        &self,
+        rows: &[&[E]],
+        score: impl Fn(usize, &[E], Float) -> Option<Float> + Send + Sync,
+        top_k: usize,
+        threshold: Float,
+        keep: &(impl Fn(usize) -> bool + Sync),
+        weighted: bool,
+    ) -> TopK {
+        // Heaps start unallocated and reserve on first push, so splits that
+        // keep nothing cost nothing; never more than the rows can fill
+        let len: usize = rows.iter().map(|segment| segment.len()).sum();
+        let capacity = top_k.min(len / self.embedding_dim.max(1)) + 1;
+        let (parallel, _permit) = self.parallel_scan();
+        fold_rows(
+            rows,
+            self.embedding_dim,
+            parallel,
+            || TopK::new(top_k, self.score_epsilon),
+            |mut heap: TopK, (idx, row)| {
+                if self.is_tombstoned(idx) || !keep(idx) {
+                    return heap;
+                }
+                // Bounds on unweighted scores say nothing about weighted ones
+                let floor = match heap.peek() {
+                    _ if weighted && !self.storage.weights.is_empty() => Float::MIN,
+                    Some(worst) if heap.len() >= top_k => {
+                        (worst.score - self.score_epsilon).max(threshold)
+                    }
+                    _ => threshold,
+                };
+                let Some(score) = score(idx, row, floor) else {
+                    return heap;
+                };
+                let score = if weighted {
+                    self.weighted(idx, score)
+                } else {
+                    score
+                };
+
+                if score >= threshold {
+                    heap.reserve_once(capacity);
+                    heap.push(ScoredIndex { score, index: idx });
+                }
+                heap
+            },
+            TopK::merge,
+        )
+    }
+
+    /// Scores only the given storage indices and returns the best `top_k`, sorted
+    /// best first
+    fn top_k_among(
+        &self,
+        scorer: &QueryScorer,
+        indices: impl IntoIterator<Item = usize>,
+        top_k: usize,
+        threshold: Float,
+        filter: Option<&DataFilter>,
+    ) -> Vec<ScoredIndex> {
+        let mut heap = TopK::new(top_k, self.score_epsilon);
+        for idx in indices {
+            let Some(vector) = self.vector_at(idx) else {
+                continue;
+            };
+            if self.is_tombstoned(idx) || !filter.map(|f| f(self.record(idx))).unwrap_or(true) {
+                continue;
+            }
+            let score = self.weighted(idx, scorer.score(vector));
+            if score >= threshold {
+                heap.push(ScoredIndex { score, index: idx });
+            }
+        }
+        heap.into_sorted()
+    }
+
+    /// Builds the result map for a scored record
+    fn to_result(&self, si: &ScoredIndex) -> HashMap<String, serde_json::Value> {
+        let data = self.record(si.index);
+        let mut result = data.fields.clone();
+        result.insert(
+            self.metrics_field.clone(),
+            serde_json::json!(self.reported_score(si.score)),
+        );
+        result.insert(self.id_field.clone(), serde_json::json!(data.id));
+        result
+    }
+
+    /// Builds the typed result for a scored record
+    fn to_typed_result(&self, si: &ScoredIndex) -> QueryResult {
+        let data = self.record(si.index);
+        QueryResult {
+            id: data.id.clone(),
+            score: self.reported_score(si.score),
+            fields: data.fields.clone(),
+            index: si.index,
+        }
+    }
+
+    /// Checks the internal consistency of the database
+    ///
+    /// Verifies the matrix size, the lengths of the per-record weights and norms,
+    /// that every stored value is finite, that vectors are unit length under
+    /// [`Metric::Cosine`], that ids are unique, and that the
+    /// [id index](Self::build_id_index), if built, maps every id to its record.
+    /// Returns an error describing the first inconsistency found.
+    pub fn verify(&self) -> Result<()> {
+        if self.storage.embedding_dim != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: self.storage.embedding_dim,
+            });
+        }
+
+        let expected_len = self.storage.data.len() * self.embedding_dim;
+        if self.storage.matrix.len() != expected_len {
+            return Err(NanoVectorDbError::MatrixSizeMismatch {
+                expected: expected_len,
+                got: self.storage.matrix.len(),
+            });
+        }
+        let parallel = [
+            ("Weights", self.storage.weights.len()),
+            ("Norms", self.storage.norms.len()),
+        ];
+        for (name, len) in parallel {
+            if len != 0 && len != self.len() {
+                return Err(NanoVectorDbError::Corrupt(format!(
+                    "{} size mismatch: expected {}, got {}",
+                    name,
+                    self.len(),
+                    len
+                )));
+            }
+        }
+
+        let mut seen = HashSet::with_capacity(self.len());
+        for (index, data) in self.storage.data.iter().enumerate() {
+            if !seen.insert(&data.id) {
+                return Err(NanoVectorDbError::Corrupt(format!(
+                    "Duplicate id {:?} at index {}",
+                    data.id, index
+                )));
+            }
+
+            let vector = self.vector_at(index).unwrap();
+            if let Some(pos) = vector.iter().position(|x| !x.is_finite()) {
+                return Err(NanoVectorDbError::Corrupt(format!(
+                    "Non-finite value in vector {:?} at position {}",
+                    data.id, pos
+                )));
+            }
+            if self.normalizes() {
+                let norm = vector.iter().map(|x| x * x).sum::<Float>().sqrt();
+                if (norm - 1.0).abs() > 1e-3 {
+                    return Err(NanoVectorDbError::Corrupt(format!(
+                        "Vector {:?} is not unit length (norm {})",
+                        data.id, norm
+                    )));
+                }
+            }
+        }
+
+        if let Some(id_index) = &self.id_index {
+            if id_index.len() != self.len() {
+                return Err(NanoVectorDbError::Corrupt(format!(
+                    "Id index size mismatch: expected {}, got {}",
+                    self.len(),
+                    id_index.len()
+                )));
+            }
+            for (id, &index) in id_index {
+                if self
+                    .storage
+                    .data
+                    .get(index)
+                    .is_none_or(|data| &data.id != id)
+                {
+                    return Err(NanoVectorDbError::Corrupt(format!(
+                        "Id index maps {:?} to index {}, which holds another record",
+                        id, index
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get vectors by their IDs, in storage order
+    ///
+    /// Scans every record unless an [id index](Self::build_id_index) is built,
+    /// in which case only the requested ids are looked up.
+    pub fn get(&self, ids: &[String]) -> Vec<&Data> {
+        if let Some(id_index) = &self.id_index {
+            let mut indices: Vec<usize> = ids
+                .iter()
+                .filter_map(|id| id_index.get(id))
+                .copied()
+                .collect();
+            indices.sort_unstable();
+            indices.dedup();
+            return indices
+                .into_iter()
+                .filter(|&index| !self.is_tombstoned(index))
+                .map(|index| self.record(index))
+                .collect();
+        }
+        let id_set: HashSet<_> = ids.iter().collect();
+        (0..self.len())
+            .filter(|&index| !self.is_tombstoned(index))
+            .filter(|&index| id_set.contains(&self.storage.data[index].id))
+            .map(|index| self.record(index))
+            .collect()
+    }
+
+    /// Storage index of the live record with `id`, through the id index if built
+    fn position(&self, id: &str) -> Option<usize> {
+        match &self.id_index {
+            Some(id_index) => id_index.get(id).copied(),
+            None => self.storage.data.iter().position(|d| d.id == id),
+        }
+        .filter(|&index| !self.is_tombstoned(index))
+    }
+
+    /// Get the stored (normalized) vectors of `ids` as one flat row-major buffer
+    ///
+    /// Rows follow the order of `ids`; missing ids are skipped. The returned ids
+    /// name each row, so the buffer holds `ids.len() * embedding_dim` values.
+    pub fn get_vectors(&self, ids: &[String]) -> (Vec<Float>, Vec<String>) {
+        let positions: HashMap<&str, usize> = self
+            .storage
+            .data
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| !self.is_tombstoned(index))
+            .map(|(index, data)| (data.id.as_str(), index))
+            .collect();
+
+        let mut matrix = Vec::with_capacity(ids.len() * self.embedding_dim);
+        let mut found = Vec::with_capacity(ids.len());
+        for id in ids {
+            let Some(vector) = positions.get(id.as_str()).and_then(|&i| self.vector_at(i)) else {
+                continue;
+            };
+            matrix.extend_from_slice(vector);
+            found.push(id.clone());
+        }
+        (matrix, found)
+    }
+
+    /// Get the stored (normalized) vector at a storage index, without copying
+    ///
+    /// Storage indices are only stable between mutations: deletes shift the
+    /// indices of every later record.
+    pub fn vector_at(&self, index: usize) -> Option<&[Float]> {
+        let start = index.checked_mul(self.embedding_dim)?;
+        self.storage.matrix.get(start..start + self.embedding_dim)
+    }
+
+    /// Get a uniform random subset of `min(n, len)` distinct records
+    ///
+    /// Uses reservoir sampling over the records, so only `n` references are held
+    /// at once. The same `seed` gives the same sample for the same database;
+    /// without one, the configured seed (see [`with_seed`](Self::with_seed)) is
+    /// used if set.
+    pub fn sample(&self, n: usize, seed: Option<u64>) -> Vec<&Data> {
+        let mut rng = StdRng::seed_from_u64(self.operation_seed(seed));
+        let mut reservoir = Vec::with_capacity(n.min(self.len()));
+        for (seen, data) in (0..self.len()).map(|index| self.record(index)).enumerate() {
+            if seen < n {
+                reservoir.push(data);
+            } else {
+                let slot = rng.random_range(0..=seen);
+                if slot < n {
+                    reservoir[slot] = data;
+                }
+            }
+        }
+        reservoir
+    }
+
+    /// Get the id of the record at a storage index
+    ///
+    /// Like [`vector_at`](Self::vector_at), indices are only stable between mutations.
+    pub fn id_at(&self, index: usize) -> Option<&str> {
+        self.storage.data.get(index).map(|d| d.id.as_str())
+    }
+
+    /// Delete vectors by their IDs
+    ///
+    /// The remaining records keep their relative order. With
+    /// [`with_soft_delete`](Self::with_soft_delete), the records are only
+    /// tombstoned until the next compaction.
+    pub fn delete(&mut self, ids: &[String]) {
+        let id_set: HashSet<_> = ids.iter().collect();
+        if let Some(compact_ratio) = self.soft_delete {
+            let indices: Vec<usize> = (0..self.len())
+                .filter(|&index| {
+                    !self.is_tombstoned(index) && id_set.contains(&self.storage.data[index].id)
+                })
+                .collect();
+            self.tombstone(&indices);
+            if self.tombstone_ratio() >= compact_ratio {
+                self.compact();
+            }
+            return;
+        }
+        let keep: Vec<bool> = self
+            .storage
+            .data
+            .iter()
+            .map(|data| !id_set.contains(&data.id))
+            .collect();
+        self.remove_unkept(&keep);
+    }
+
+    /// Marks the live records at `indices` as deleted without moving any row
+    fn tombstone(&mut self, indices: &[usize]) {
+        if indices.is_empty() {
+            return;
+        }
+        self.clear_query_cache();
+        for &index in indices {
+            self.update_content_hash(index, false);
+            self.storage.tombstones.insert(index);
+        }
+        let removed: Vec<String> = indices
+            .iter()
+            .map(|&index| self.storage.data[index].id.clone())
+            .collect();
+        self.log(&wal::WalOp::Delete {
+            ids: removed.clone(),
+        });
+        if let Some(hook) = &self.hooks.on_delete {
+            hook(&removed);
+        }
+    }
+
+    /// Whether the record at `index` was soft-deleted, see
+    /// [`with_soft_delete`](Self::with_soft_delete)
+    fn is_tombstoned(&self, index: usize) -> bool {
+        !self.storage.tombstones.is_empty() && self.storage.tombstones.contains(&index)
+    }
+
+    /// Get the number of soft-deleted records awaiting compaction
+    pub fn tombstone_count(&self) -> usize {
+        self.storage.tombstones.len()
+    }
+
+    /// Get the share of stored records that are soft-deleted, 0 when empty
+    pub fn tombstone_ratio(&self) -> Float {
+        if self.is_empty() {
+            return 0.0;
+        }
+        self.tombstone_count() as Float / self.len() as Float
+    }
+
+    /// Removes every soft-deleted record, compacting the matrix in one pass
+    ///
+    /// Storage indices of the remaining records shift down like after a
+    /// regular delete.
+    pub fn compact(&mut self) {
+        if self.storage.tombstones.is_empty() {
+            return;
+        }
+        let keep = vec![true; self.len()];
+        self.remove_unkept(&keep);
+    }
+
+    /// Keeps only the records for which `f` returns true, like `Vec::retain`
+    ///
+    /// `f` runs once per record, in storage order, and the matrix is compacted
+    /// in a single pass afterwards. The kept records keep their relative order.
+    pub fn retain(&mut self, f: impl Fn(&Data) -> bool) {
+        let keep: Vec<bool> = (0..self.len()).map(|index| f(self.record(index))).collect();
+        self.remove_unkept(&keep);
+    }
+
+    /// Removes the records whose `keep` entry is false, compacting the matrix
+    ///
+    /// Tombstoned records are removed too, without being reported again.
+    fn remove_unkept(&mut self, keep: &[bool]) {
+        let tombstones = std::mem::take(&mut self.storage.tombstones);
+        let newly_removed: Vec<bool> = (0..keep.len())
+            .map(|index| !keep[index] && !tombstones.contains(&index))
+            .collect();
+        let keep: Vec<bool> = (0..keep.len())
+            .map(|index| keep[index] && !tombstones.contains(&index))
+            .collect();
+        for index in (0..keep.len()).filter(|&index| newly_removed[index]) {
+            self.update_content_hash(index, false);
+        }
+
+        // Filter out deleted entries
+        let mut removed = Vec::new();
+        let mut indices = 0..;
+        self.storage.data.retain(|data| {
+            let index = indices.next().unwrap();
+            if newly_removed[index] {
+                removed.push(data.id.clone());
+            }
+            keep[index]
+        });
+
+        // Compact the matrix down to the remaining rows
+        let embedding_dim = self.embedding_dim;
+        let mut kept = 0;
+        for (index, _) in keep.iter().enumerate().filter(|(_, &k)| k) {
+            if index != kept {
+                self.storage.matrix.copy_within(
+                    index * embedding_dim..(index + 1) * embedding_dim,
+                    kept * embedding_dim,
+                );
+            }
+            kept += 1;
+        }
+        self.storage.matrix.truncate(kept * embedding_dim);
+        if !self.storage.weights.is_empty() {
+            let mut keep_iter = keep.iter();
+            self.storage.weights.retain(|_| *keep_iter.next().unwrap());
+        }
+        let mut keep_iter = keep.iter();
+        self.storage
+            .norms
+            .retain(|_| keep_iter.next().copied().unwrap_or(false));
+        if !removed.is_empty() || !tombstones.is_empty() {
+            self.storage.retain_named_vectors();
+        }
+        if !removed.is_empty() {
+            self.log(&wal::WalOp::Delete {
+                ids: removed.clone(),
+            });
+        }
+
+        // Deletes shift storage indices, so the indexes are rebuilt
+        self.rebuild_indexes();
+
+        if let Some(hook) = &self.hooks.on_delete {
+            if !removed.is_empty() {
+                hook(&removed);
+            }
+        }
+    }
+
+    /// Delete the records at storage indices `range`, returning their ids
+    ///
+    /// The matrix is compacted with a single shift of the rows after the range.
+    /// Tombstoned records in the range are dropped but not returned, as their
+    /// deletion was already reported. Fails without deleting anything if the
+    /// range is out of bounds.
+    pub fn delete_range(&mut self, range: Range<usize>) -> Result<Vec<String>> {
+        if range.start > range.end || range.end > self.len() {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Range {:?} out of bounds for {} records",
+                range,
+                self.len()
+            )));
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let live: Vec<bool> = range
+            .clone()
+            .map(|index| !self.is_tombstoned(index))
+            .collect();
+        for index in range.clone().filter(|&index| live[index - range.start]) {
+            self.update_content_hash(index, false);
+        }
+        let tombstones = std::mem::take(&mut self.storage.tombstones);
+        self.storage.tombstones = tombstones
+            .into_iter()
+            .filter(|index| !range.contains(index))
+            .map(|index| {
+                if index >= range.end {
+                    index - range.len()
+                } else {
+                    index
+                }
+            })
+            .collect();
+        let embedding_dim = self.embedding_dim;
+        let removed: Vec<String> = self
+            .storage
+            .data
+            .drain(range.clone())
+            .zip(live)
+            .filter(|(_, live)| *live)
+            .map(|(data, _)| data.id)
+            .collect();
+        self.storage
+            .matrix
+            .drain(range.start * embedding_dim..range.end * embedding_dim);
+        if !self.storage.weights.is_empty() {
+            self.storage.weights.drain(range.clone());
+        }
+        let norms_len = self.storage.norms.len();
+        self.storage
+            .norms
+            .drain(range.start.min(norms_len)..range.end.min(norms_len));
+        self.storage.retain_named_vectors();
+        self.rebuild_indexes();
+        self.log(&wal::WalOp::Delete {
+            ids: removed.clone(),
+        });
 
-impl PartialOrd for ScoredIndex {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+        if let Some(hook) = &self.hooks.on_delete {
+            if !removed.is_empty() {
+                hook(&removed);
+            }
+        }
+        Ok(removed)
     }
-}
 
-impl Ord for ScoredIndex {
-    fn cmp(&self, other: &Self) -> Ordering {
-        other.score.partial_cmp(&self.score).unwrap_or_else(|| {
-            if self.score.is_nan() && other.score.is_nan() {
-                Ordering::Equal
-            } else if self.score.is_nan() {
-                Ordering::Less
-            } else {
-                Ordering::Greater
-            }
+    /// Order-independent hash of every record's id, stored vector and fields
+    ///
+    /// Databases holding the same records hash equally regardless of insertion
+    /// order, so comparing hashes is a cheap consistency check between replicas
+    /// (equal hashes make identical contents overwhelmingly likely, not
+    /// certain). Weights, norms, named vectors and additional data are not
+    /// included. The first call hashes every record; after that, upserts and
+    /// deletes update the hash incrementally, while bulk changes like
+    /// [`replace_all`](Self::replace_all) make the next call rehash.
+    pub fn content_hash(&self) -> u64 {
+        *self.content_hash.get_or_init(|| {
+            (0..self.len())
+                .filter(|&index| !self.is_tombstoned(index))
+                .map(|index| self.record_hash(index))
+                .fold(0, u64::wrapping_add)
         })
     }
-}
 
-type DataFilter = Box<dyn Fn(&Data) -> bool + Send + Sync>;
+    /// Hash of the record at `index`, mixed so that sums of them stay well spread
+    fn record_hash(&self, index: usize) -> u64 {
+        let data = self.record(index);
+        let row =
+            &self.storage.matrix[index * self.embedding_dim..(index + 1) * self.embedding_dim];
+        let mut fields: Vec<_> = data.fields.iter().collect();
+        fields.sort_unstable_by_key(|(key, _)| *key);
+        // 0xff never occurs in UTF-8, so it separates the id from what follows
+        let mut hash = fnv1a(FNV_OFFSET, data.id.bytes().chain([0xff]));
+        hash = fnv1a(hash, row.iter().flat_map(|x| x.to_le_bytes()));
+        hash = fnv1a(hash, serde_json::to_vec(&fields).unwrap_or_default());
+        // SplitMix64 finalizer
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^ (hash >> 31)
+    }
 
-impl NanoVectorDB {
-    /// Creates a new NanoVectorDB instance
-    pub fn new(embedding_dim: usize, storage_file: &str) -> Result<Self> {
-        let storage_file = PathBuf::from(storage_file);
-        let storage = if storage_file.exists() && storage_file.metadata()?.len() > 0 {
-            let contents = fs::read_to_string(&storage_file)?;
-            let db: DataBase = serde_json::from_str(&contents)?;
-
-            let expected_len = db.data.len() * db.embedding_dim;
-            if db.matrix.len() != expected_len {
-                anyhow::bail!(
-                    "Matrix size mismatch: expected {}, got {}",
-                    expected_len,
-                    db.matrix.len()
-                );
-            }
+    /// Hash of the record at `index`, if the content hash is being kept up to
+    /// date
+    fn tracked_record_hash(&self, index: usize) -> Option<u64> {
+        self.content_hash.get().map(|_| self.record_hash(index))
+    }
 
-            db
-        } else {
-            DataBase {
-                embedding_dim,
-                data: Vec::new(),
-                matrix: Vec::new(),
-                additional_data: HashMap::new(),
-            }
+    /// Adds the hash of the record at `index` to the content hash, or subtracts
+    /// it, if the content hash has been computed
+    fn update_content_hash(&mut self, index: usize, add: bool) {
+        let Some(record) = self.tracked_record_hash(index) else {
+            return;
         };
+        if let Some(hash) = self.content_hash.get_mut() {
+            *hash = if add {
+                hash.wrapping_add(record)
+            } else {
+                hash.wrapping_sub(record)
+            };
+        }
+    }
 
-        Ok(Self {
-            embedding_dim,
-            metric: "cosine".to_string(),
-            storage_file,
-            storage,
-        })
+    /// Rebuilds every enabled index from the current matrix
+    fn rebuild_indexes(&mut self) {
+        self.clear_query_cache();
+        if let Some(lsh) = self.lsh.as_mut() {
+            lsh.rebuild(&self.storage.matrix);
+        }
+        if let Some(ivf) = self.ivf.as_mut() {
+            ivf.rebuild(&self.storage.matrix);
+        }
+        if let Some(quant) = self.quant.as_mut() {
+            quant.rebuild(&self.storage.matrix);
+        }
+        for index in self.field_indexes.values_mut() {
+            index.rebuild(&self.storage.data);
+        }
+        if self.id_index.is_some() {
+            self.build_id_index();
+        }
     }
 
-    /// Upserts vectors into the database
-    pub fn upsert(&mut self, mut datas: Vec<Data>) -> Result<(Vec<String>, Vec<String>)> {
-        let mut updates = Vec::new();
-        let mut inserts = Vec::new();
-        let existing_ids: HashSet<_> = self.storage.data.iter().map(|d| &d.id).collect();
-
-        for data in datas.iter_mut() {
-            if existing_ids.contains(&data.id) {
-                if let Some(pos) = self.storage.data.iter().position(|d| d.id == data.id) {
-                    let norm_vec = normalize(&data.vector);
-                    let start = pos * self.embedding_dim;
-                    let end = start + self.embedding_dim;
-                    self.storage.matrix[start..end].copy_from_slice(&norm_vec);
-                    updates.push(data.id.clone());
-                }
+    /// Saves the database to disk
+    pub fn save(&self) -> Result<()> {
+        if self.storage_file.as_os_str().is_empty() {
+            return Err(NanoVectorDbError::InvalidInput(
+                "Database has no storage file".to_string(),
+            ));
+        }
+        if let Some(parent) = self.storage_file.parent() {
+            if self.create_dirs {
+                fs::create_dir_all(parent)?;
+            } else if !parent.as_os_str().is_empty() && !parent.exists() {
+                return Err(NanoVectorDbError::InvalidInput(format!(
+                    "Storage directory {} does not exist",
+                    parent.display()
+                )));
             }
         }
+        if self.backup && self.storage_file.exists() {
+            fs::copy(&self.storage_file, backup_path(&self.storage_file))?;
+        }
+        self.write_snapshot()?;
+        // The snapshot now holds everything logged
+        if let Some(wal) = &self.wal {
+            wal.truncate()?;
+        }
+        Ok(())
+    }
 
-        let new_datas: Vec<Data> = datas
-            .into_iter()
-            .filter(|d| !existing_ids.contains(&d.id))
-            .collect();
+    /// Writes the storage file (and sidecar) for [`save`](Self::save)
+    fn write_snapshot(&self) -> Result<()> {
+        #[cfg(feature = "compression")]
+        if let Some(threshold) = self.field_compression {
+            let data = self
+                .storage
+                .data
+                .iter()
+                .map(|d| compress::CompressedData::new(d, threshold))
+                .collect();
+            return self.write_records(data);
+        }
+        self.write_records(self.storage.data.iter().collect())
+    }
 
-        for data in new_datas {
-            let norm_vec = normalize(&data.vector);
-            let vec_clone = norm_vec.clone();
-            self.storage.matrix.extend(vec_clone);
-            self.storage.data.push(Data {
-                id: data.id.clone(),
-                vector: norm_vec,
-                fields: data.fields,
-            });
-            inserts.push(data.id);
+    /// Writes the storage file (and sidecar) with `data` serialized in place of
+    /// the records
+    fn write_records<D: Serialize>(&self, data: Vec<D>) -> Result<()> {
+        let Some(sidecar) = &self.sidecar else {
+            let view = DataBaseView {
+                embedding_dim: self.storage.embedding_dim,
+                data,
+                matrix: &self.storage.matrix,
+                additional_data: &self.storage.additional_data,
+                weights: &self.storage.weights,
+                norms: &self.storage.norms,
+                named_vectors: &self.storage.named_vectors,
+                tombstones: &self.storage.tombstones,
+            };
+            fs::write(&self.storage_file, self.to_json(&view)?)?;
+            return Ok(());
+        };
+
+        // Pending fields are already on disk in the sidecar and unchanged
+        if !sidecar.pending {
+            fs::write(&sidecar.path, self.to_json(&data)?)?;
         }
+        let ids_only = DataBaseIdsOnly {
+            embedding_dim: self.storage.embedding_dim,
+            data: self
+                .storage
+                .data
+                .iter()
+                .map(|d| IdOnly { id: &d.id })
+                .collect(),
+            matrix: &self.storage.matrix,
+            additional_data: &self.storage.additional_data,
+            weights: &self.storage.weights,
+            norms: &self.storage.norms,
+            named_vectors: &self.storage.named_vectors,
+            tombstones: &self.storage.tombstones,
+        };
+        fs::write(&self.storage_file, self.to_json(&ids_only)?)?;
+        Ok(())
+    }
 
-        Ok((updates, inserts))
+    /// Saves like [`save`](Self::save), retrying I/O failures up to `attempts`
+    /// times in total with exponential backoff starting at `backoff`
+    ///
+    /// Meant for networked filesystems where writes occasionally fail
+    /// transiently. Other errors are returned at once; if every attempt fails,
+    /// the last I/O error is returned.
+    pub fn save_with_retry(&self, attempts: usize, backoff: Duration) -> Result<()> {
+        retry_io(attempts, backoff, || self.save())
     }
 
-    /// Queries the database for similar vectors
-    pub fn query(
+    /// Serializes for `save`, honoring [`with_pretty`](Self::with_pretty)
+    fn to_json<T: Serialize>(&self, value: &T) -> Result<String> {
+        Ok(if self.pretty {
+            serde_json::to_string_pretty(value)?
+        } else {
+            serde_json::to_string(value)?
+        })
+    }
+
+    /// Queries the database like [`query`](Self::query), writing each result
+    /// to `writer` as it is built instead of collecting them
+    ///
+    /// CSV columns are the id and metrics field names, then every field key of
+    /// the hits in sorted order. String values are written as-is and other
+    /// values as JSON, quoted where needed; missing fields are left empty.
+    /// Returns the number of results written.
+    pub fn query_to_writer(
         &self,
         query: &[Float],
         top_k: usize,
         better_than: Option<Float>,
         filter: Option<DataFilter>,
-    ) -> Vec<HashMap<String, serde_json::Value>> {
-        let query_norm = normalize(query);
-        let embedding_dim = self.embedding_dim;
-        let matrix = &self.storage.matrix;
-        let threshold = better_than.unwrap_or(Float::MIN);
+        mut writer: impl std::io::Write,
+        format: ResultFormat,
+    ) -> Result<usize> {
+        if self.is_empty() {
+            return Ok(0);
+        }
+        self.lazy_records()?;
+        let query_norm = self.prepare_query(query)?;
+        let threshold = self.threshold(better_than);
+        let scored = self.top_k_scored(&query_norm, top_k, threshold, filter.as_ref());
 
-        // Precompute query chunks for SIMD-friendly operations
-        let query_chunks: Vec<[Float; 4]> = query_norm
-            .chunks_exact(4)
-            .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
-            .collect();
-        let query_remainder = &query_norm[query_chunks.len() * 4..];
+        match format {
+            ResultFormat::JsonLines => {
+                for si in &scored {
+                    serde_json::to_writer(&mut writer, &self.to_result(si))?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+            ResultFormat::Csv => {
+                let mut keys: Vec<&String> = scored
+                    .iter()
+                    .flat_map(|si| self.record(si.index).fields.keys())
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+                keys.sort_unstable();
+                let header = [&self.id_field, &self.metrics_field]
+                    .into_iter()
+                    .chain(keys.iter().copied())
+                    .map(|key| csv_field(key))
+                    .collect::<Vec<_>>();
+                writeln!(writer, "{}", header.join(","))?;
+                for si in &scored {
+                    let data = self.record(si.index);
+                    let mut row = vec![
+                        csv_field(&data.id),
+                        self.reported_score(si.score).to_string(),
+                    ];
+                    row.extend(keys.iter().map(|key| match data.fields.get(*key) {
+                        Some(serde_json::Value::String(text)) => csv_field(text),
+                        Some(value) => csv_field(&value.to_string()),
+                        None => String::new(),
+                    }));
+                    writeln!(writer, "{}", row.join(","))?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(scored.len())
+    }
 
-        // Parallel processing with Rayon
-        let heap = matrix
-            .par_chunks(embedding_dim)
-            .enumerate()
-            .filter(|(idx, _)| {
-                filter
-                    .as_ref()
-                    .map(|f| f(&self.storage.data[*idx]))
-                    .unwrap_or(true)
-            })
-            .fold(
-                || BinaryHeap::with_capacity(top_k + 1),
-                |mut heap, (idx, vector)| {
-                    let score = dot_product(vector, &query_chunks, query_remainder);
-
-                    if score >= threshold {
-                        heap.push(ScoredIndex { score, index: idx });
-                        if heap.len() > top_k {
-                            heap.pop();
-                        }
-                    }
-                    heap
-                },
-            )
-            .reduce(
-                || BinaryHeap::with_capacity(top_k + 1),
-                |mut heap1, heap2| {
-                    for si in heap2 {
-                        heap1.push(si);
-                        if heap1.len() > top_k {
-                            heap1.pop();
-                        }
-                    }
-                    heap1
-                },
-            );
+    /// Encodes query results into a compact binary layout for transport
+    ///
+    /// The layout is a little-endian `u32` result count, then per result: a `u32`
+    /// length-prefixed UTF-8 id, the score as an `f32`, and a `u32`
+    /// length-prefixed JSON object of the remaining fields. Decode with
+    /// [`decode_results`](Self::decode_results) on a database configured with the
+    /// same result field names.
+    pub fn encode_results(
+        &self,
+        results: &[HashMap<String, serde_json::Value>],
+    ) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.extend((results.len() as u32).to_le_bytes());
+        for result in results {
+            let id = result
+                .get(&self.id_field)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| {
+                    NanoVectorDbError::InvalidInput(format!(
+                        "Result is missing {:?}",
+                        self.id_field
+                    ))
+                })?;
+            let score = result
+                .get(&self.metrics_field)
+                .and_then(|v| v.as_f64())
+                .ok_or_else(|| {
+                    NanoVectorDbError::InvalidInput(format!(
+                        "Result is missing {:?}",
+                        self.metrics_field
+                    ))
+                })?;
+            let fields: HashMap<&String, &serde_json::Value> = result
+                .iter()
+                .filter(|(k, _)| **k != self.id_field && **k != self.metrics_field)
+                .collect();
+            let fields = serde_json::to_vec(&fields)?;
 
-        // Convert to sorted results
-        let sorted = heap.into_sorted_vec();
+            bytes.extend((id.len() as u32).to_le_bytes());
+            bytes.extend(id.as_bytes());
+            bytes.extend((score as Float).to_le_bytes());
+            bytes.extend((fields.len() as u32).to_le_bytes());
+            bytes.extend(fields);
+        }
+        Ok(bytes)
+    }
 
-        sorted
-            .into_iter()
-            .map(|si| {
-                let data = &self.storage.data[si.index];
-                let mut result = data.fields.clone();
-                result.insert(
-                    constants::F_METRICS.to_string(),
-                    serde_json::json!(si.score),
-                );
-                result.insert(constants::F_ID.to_string(), serde_json::json!(data.id));
-                result
-            })
-            .collect()
+    /// Decodes query results produced by [`encode_results`](Self::encode_results)
+    pub fn decode_results(&self, bytes: &[u8]) -> Result<Vec<HashMap<String, serde_json::Value>>> {
+        let mut reader = ByteReader { bytes };
+        let count = reader.u32()? as usize;
+        // The count is untrusted, so reserve no more records than could fit
+        let mut results = Vec::with_capacity(count.min(reader.bytes.len() / MIN_RESULT_BYTES));
+        for _ in 0..count {
+            let id_len = reader.u32()? as usize;
+            let id = std::str::from_utf8(reader.take(id_len)?)
+                .map_err(|e| NanoVectorDbError::Corrupt(format!("Invalid id: {}", e)))?
+                .to_string();
+            let score = Float::from_le_bytes(reader.take(4)?.try_into().unwrap());
+            let fields_len = reader.u32()? as usize;
+            let mut result: HashMap<String, serde_json::Value> =
+                serde_json::from_slice(reader.take(fields_len)?)?;
+            result.insert(self.metrics_field.clone(), serde_json::json!(score));
+            result.insert(self.id_field.clone(), serde_json::json!(id));
+            results.push(result);
+        }
+        Ok(results)
     }
 
-    /// Get vectors by their IDs
-    pub fn get(&self, ids: &[String]) -> Vec<&Data> {
-        let id_set: HashSet<_> = ids.iter().collect();
-        self.storage
-            .data
-            .iter()
-            .filter(|data| id_set.contains(&data.id))
-            .collect()
+    /// Serializes the database into a compact in-memory binary form
+    ///
+    /// The layout is the magic `NVDB`, the little-endian `u32` embedding
+    /// dimension and record count, the raw little-endian `f32` matrix, then a
+    /// `u32` length-prefixed JSON object holding the records and additional data.
+    /// Read it back with [`from_bytes`](Self::from_bytes). Fails if the
+    /// dimension, record count or JSON length does not fit in a `u32`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let header = |name: &str, value: usize| {
+            u32::try_from(value).map_err(|_| {
+                NanoVectorDbError::InvalidInput(format!(
+                    "{} {} does not fit the byte format",
+                    name, value
+                ))
+            })
+        };
+        let embedding_dim = header("Embedding dimension", self.embedding_dim)?;
+        let count = header("Record count", self.len())?;
+        self.lazy_records()?;
+        let metadata = serde_json::to_vec(&serde_json::json!({
+            "data": (0..self.len()).map(|index| self.record(index)).collect::<Vec<_>>(),
+            "additional_data": &self.storage.additional_data,
+            "weights": &self.storage.weights,
+            "norms": &self.storage.norms,
+            "named_vectors": &self.storage.named_vectors,
+            "tombstones": &self.storage.tombstones,
+        }))?;
+
+        let mut bytes = Vec::with_capacity(16 + self.storage.matrix.len() * 4 + metadata.len());
+        bytes.extend(BYTES_MAGIC);
+        bytes.extend(embedding_dim.to_le_bytes());
+        bytes.extend(count.to_le_bytes());
+        for value in self.storage.matrix.iter() {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(header("Metadata length", metadata.len())?.to_le_bytes());
+        bytes.extend(metadata);
+        Ok(bytes)
     }
 
-    /// Delete vectors by their IDs
-    pub fn delete(&mut self, ids: &[String]) {
-        let id_set: HashSet<_> = ids.iter().collect();
+    /// Creates a database from bytes written by [`to_bytes`](Self::to_bytes)
+    ///
+    /// The result has no storage file, so [`save`](Self::save) fails; use
+    /// [`snapshot`](Self::snapshot) or `to_bytes` to persist it.
+    pub fn from_bytes(embedding_dim: usize, bytes: &[u8]) -> Result<Self> {
+        let mut reader = ByteReader { bytes };
+        if reader.take(4)? != BYTES_MAGIC {
+            return Err(NanoVectorDbError::Corrupt(
+                "Not a NanoVectorDB byte buffer".to_string(),
+            ));
+        }
+        let stored_dim = reader.u32()? as usize;
+        if stored_dim != embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: embedding_dim,
+                got: stored_dim,
+            });
+        }
+        let count = reader.u32()? as usize;
+        let matrix = reader
+            .take(count * embedding_dim * 4)?
+            .chunks_exact(4)
+            .map(|chunk| Float::from_le_bytes(chunk.try_into().unwrap()))
+            .collect::<Vec<_>>()
+            .into();
+        let metadata_len = reader.u32()? as usize;
+        let metadata: BytesMetadata = serde_json::from_slice(reader.take(metadata_len)?)?;
 
-        // Filter out deleted entries
-        self.storage.data.retain(|data| !id_set.contains(&data.id));
+        let storage = DataBase {
+            embedding_dim,
+            data: metadata.data,
+            matrix,
+            additional_data: metadata.additional_data,
+            weights: metadata.weights,
+            norms: metadata.norms,
+            named_vectors: metadata.named_vectors,
+            tombstones: metadata.tombstones,
+        };
+        storage.check_matrix_len()?;
+        Ok(Self::from_storage(embedding_dim, PathBuf::new(), storage))
+    }
 
-        // Rebuild matrix from remaining vectors
-        self.storage.matrix = self
-            .storage
-            .data
-            .iter()
-            .flat_map(|data| data.vector.iter().copied())
+    /// Writes the stored vectors as raw little-endian `f32` rows to `matrix_path`
+    /// and their ids, one per line in the same order, to `ids_path`
+    ///
+    /// This is the layout external index builders (e.g. FAISS) read directly:
+    /// row `i` of the matrix belongs to line `i` of the id list. Vectors are
+    /// written as stored, so normalized under [`Metric::Cosine`]. Soft-deleted
+    /// records are left out. Fails before writing anything if an id contains a
+    /// line break. Returns the number of rows written.
+    pub fn export_for_external(&self, matrix_path: &str, ids_path: &str) -> Result<usize> {
+        let rows: Vec<usize> = (0..self.len())
+            .filter(|&index| !self.is_tombstoned(index))
             .collect();
+        if let Some(&index) = rows
+            .iter()
+            .find(|&&index| self.storage.data[index].id.contains(['\n', '\r']))
+        {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "Id {:?} cannot be written on one line",
+                self.storage.data[index].id
+            )));
+        }
+
+        use std::io::Write;
+        let mut matrix = std::io::BufWriter::new(fs::File::create(matrix_path)?);
+        let mut ids = std::io::BufWriter::new(fs::File::create(ids_path)?);
+        for &index in &rows {
+            for value in self.vector_at(index).unwrap_or_default() {
+                matrix.write_all(&value.to_le_bytes())?;
+            }
+            writeln!(ids, "{}", self.storage.data[index].id)?;
+        }
+        matrix.flush()?;
+        ids.flush()?;
+        Ok(rows.len())
     }
 
-    /// Saves the database to disk
-    pub fn save(&self) -> Result<()> {
-        let serialized = serde_json::to_string(&self.storage)?;
-        fs::write(&self.storage_file, serialized)?;
+    /// Writes a point-in-time snapshot of the database to `path`
+    ///
+    /// Snapshots are separate from the live storage file and always hold the
+    /// records' fields inline, so they can be restored regardless of sidecar mode.
+    pub fn snapshot(&self, path: &str) -> Result<SnapshotId> {
+        self.lazy_records()?;
+        let view = DataBaseView {
+            embedding_dim: self.storage.embedding_dim,
+            data: (0..self.len()).map(|index| self.record(index)).collect(),
+            matrix: &self.storage.matrix,
+            additional_data: &self.storage.additional_data,
+            weights: &self.storage.weights,
+            norms: &self.storage.norms,
+            named_vectors: &self.storage.named_vectors,
+            tombstones: &self.storage.tombstones,
+        };
+        fs::write(path, serde_json::to_string(&view)?)?;
+        Ok(SnapshotId {
+            path: PathBuf::from(path),
+            len: self.len(),
+        })
+    }
+
+    /// Replaces the in-memory state with a snapshot written by [`snapshot`](Self::snapshot)
+    ///
+    /// The live storage file is untouched until the next `save`.
+    pub fn restore(&mut self, path: &str) -> Result<()> {
+        let storage = DataBase::load(Path::new(path))?;
+        if storage.embedding_dim != self.embedding_dim {
+            return Err(NanoVectorDbError::DimensionMismatch {
+                expected: self.embedding_dim,
+                got: storage.embedding_dim,
+            });
+        }
+
+        let segment_len = self.storage.matrix.segment_len();
+        self.storage = storage;
+        self.storage.matrix.resegment(segment_len);
+        self.content_hash.take();
+        self.refresh_unit_rows();
+        if let Some(sidecar) = self.sidecar.as_mut() {
+            sidecar.pending = false;
+            sidecar.records = OnceLock::new();
+        }
+        self.rebuild_indexes();
+        self.log_all();
         Ok(())
     }
 
@@ -302,6 +5571,16 @@ impl NanoVectorDB {
         self.storage.additional_data = data;
     }
 
+    /// Get the dimensionality of stored vectors
+    pub fn embedding_dim(&self) -> usize {
+        self.embedding_dim
+    }
+
+    /// Get the metric used for similarity searches
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
     /// Get the number of vectors in the database
     pub fn len(&self) -> usize {
         self.storage.data.len()
@@ -316,6 +5595,135 @@ impl NanoVectorDB {
     pub fn vector_bytes_len(&self) -> usize {
         self.storage.matrix.len()
     }
+
+    /// Get how many vectors the matrix can hold without reallocating
+    pub fn capacity(&self) -> usize {
+        self.storage.matrix.capacity() / self.embedding_dim.max(1)
+    }
+
+    /// Approximate heap bytes held by the vector matrix, its quantized copy,
+    /// and the records
+    ///
+    /// Counts allocated capacity rather than length. Field values are counted
+    /// by their inline size only, so records with large fields use more.
+    pub fn memory_usage(&self) -> usize {
+        let matrix = self.storage.matrix.capacity() * std::mem::size_of::<Float>();
+        let quant = self.quant.as_ref().map_or(0, |q| q.codes.capacity());
+        let records = self.storage.data.capacity() * std::mem::size_of::<Data>()
+            + self
+                .storage
+                .data
+                .iter()
+                .map(|d| d.id.capacity())
+                .sum::<usize>();
+        matrix + quant + records
+    }
+
+    /// Reads one value from every memory page of the matrix and its quantized
+    /// copy, so the next query does not pay for page faults
+    ///
+    /// Pages are read in order within chunks, with chunks spread over threads.
+    /// Storage is read fully into memory on load, so this only helps once the
+    /// OS has paged parts of it out; it never changes results.
+    pub fn prewarm(&self) {
+        const PAGE_BYTES: usize = 4096;
+        const CHUNK_BYTES: usize = 1 << 20;
+        let floats_per_page = PAGE_BYTES / std::mem::size_of::<Float>();
+        let touch =
+            |chunk: &[Float]| -> Float { chunk.iter().step_by(floats_per_page).sum::<Float>() };
+        let segments = self.storage.matrix.segments();
+        let chunk_len = CHUNK_BYTES / std::mem::size_of::<Float>();
+        #[cfg(feature = "parallel")]
+        let sum: Float = segments
+            .par_iter()
+            .flat_map(|segment| segment.par_chunks(chunk_len))
+            .map(touch)
+            .sum();
+        #[cfg(not(feature = "parallel"))]
+        let sum: Float = segments
+            .iter()
+            .flat_map(|segment| segment.chunks(chunk_len))
+            .map(touch)
+            .sum();
+        std::hint::black_box(sum);
+
+        if let Some(quant) = &self.quant {
+            let codes: i64 = quant
+                .codes
+                .iter()
+                .step_by(PAGE_BYTES)
+                .map(|&c| i64::from(c))
+                .sum();
+            std::hint::black_box(codes);
+        }
+    }
+
+    /// How long the last ranked query took, or `None` before the first
+    ///
+    /// Covers the scan of [`query`](Self::query) and the methods built on it,
+    /// including query cache hits, but not building the results.
+    pub fn last_query_latency(&self) -> Option<Duration> {
+        match self.last_query_nanos.load(atomic::Ordering::Relaxed) {
+            0 => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    /// Renders the collection size, embedding dimension, last query latency,
+    /// memory usage and tombstone ratio in the Prometheus text exposition format
+    ///
+    /// The latency gauge is omitted until a query has run. Serve the string
+    /// from a `/metrics` endpoint to scrape it.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(&self) -> String {
+        let mut metrics = vec![
+            (
+                "nanovectordb_records",
+                "Number of records in the collection",
+                self.len() as f64,
+            ),
+            (
+                "nanovectordb_embedding_dim",
+                "Dimension of stored vectors",
+                self.embedding_dim as f64,
+            ),
+        ];
+        if let Some(latency) = self.last_query_latency() {
+            metrics.push((
+                "nanovectordb_last_query_seconds",
+                "Duration of the last ranked query",
+                latency.as_secs_f64(),
+            ));
+        }
+        metrics.push((
+            "nanovectordb_memory_bytes",
+            "Approximate heap bytes held by vectors and records",
+            self.memory_usage() as f64,
+        ));
+        metrics.push((
+            "nanovectordb_tombstone_ratio",
+            "Share of stored records that are soft-deleted",
+            self.tombstone_ratio() as f64,
+        ));
+        metrics
+            .into_iter()
+            .map(|(name, help, value)| {
+                format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n")
+            })
+            .collect()
+    }
+
+    /// Reserve room for at least `additional` more vectors
+    pub fn reserve(&mut self, additional: usize) {
+        self.storage.data.reserve(additional);
+        self.storage.matrix.reserve(additional * self.embedding_dim);
+    }
+
+    /// Release any spare matrix capacity
+    pub fn shrink_to_fit(&mut self) {
+        self.storage.data.shrink_to_fit();
+        self.storage.matrix.shrink_to_fit();
+    }
 }
 
 #[inline]
@@ -343,19 +5751,131 @@ pub fn dot_product(vec: &[Float], query_chunks: &[[Float; 4]], query_remainder:
         .sum::<Float>()
 }
 
+/// Calculate the dot product between two vectors, accumulating in `f64`
+///
+/// Each product of two `f32` values is exact in `f64`, so only the summation
+/// rounds. The result is cast back to `f32`.
+pub fn dot_product_f64_acc(vec: &[Float], query: &[Float]) -> Float {
+    assert_eq!(
+        vec.len(),
+        query.len(),
+        "Mismatched lengths between vector and query"
+    );
+    vec.iter()
+        .zip(query)
+        .map(|(&a, &b)| a as f64 * b as f64)
+        .sum::<f64>() as Float
+}
+
+/// Cosine similarity between two vectors, without assuming unit length
+fn cosine_similarity(a: &[Float], b: &[Float]) -> Float {
+    let dot: Float = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: Float = a.iter().map(|x| x * x).sum::<Float>().sqrt();
+    let norm_b: Float = b.iter().map(|x| x * x).sum::<Float>().sqrt();
+    if norm_a <= Float::EPSILON || norm_b <= Float::EPSILON {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+/// Euclidean distance between two vectors
+fn l2_distance(a: &[Float], b: &[Float]) -> Float {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<Float>()
+        .sqrt()
+}
+
+/// Manhattan (L1) distance between two vectors
+fn manhattan_distance(a: &[Float], b: &[Float]) -> Float {
+    a.iter().zip(b).map(|(x, y)| (x - y).abs()).sum()
+}
+
+/// Rejects ranking weights that are not positive and finite
+fn check_weight(weight: Float) -> Result<()> {
+    if !(weight.is_finite() && weight > 0.0) {
+        return Err(NanoVectorDbError::InvalidInput(format!(
+            "Weight must be positive and finite, got {}",
+            weight
+        )));
+    }
+    Ok(())
+}
+
+/// L2 norm of a vector
+fn vector_norm(vector: &[Float]) -> Float {
+    vector.iter().map(|x| x * x).sum::<Float>().sqrt()
+}
+
 /// Normalize a vector to unit length
+///
+/// Panics if the norm does not exceed [`DEFAULT_NORM_EPSILON`]; see
+/// [`normalize_with_epsilon`] for a fallible version with a custom bound.
 pub fn normalize(vector: &[Float]) -> Vec<Float> {
-    let norm_sq: Float = vector
+    normalize_with_epsilon(vector, DEFAULT_NORM_EPSILON)
+        .expect("Cannot normalize zero-length vector")
+}
+
+/// Normalize a vector to unit length, rejecting vectors whose norm is at most
+/// `epsilon`
+pub fn normalize_with_epsilon(vector: &[Float], epsilon: Float) -> Result<Vec<Float>> {
+    let norm = vector
         .iter()
-        .fold(0.0 as Float, |acc, &x| x.mul_add(x, acc));
+        .fold(0.0 as Float, |acc, &x| x.mul_add(x, acc))
+        .sqrt();
 
-    assert!(
-        norm_sq > Float::EPSILON,
-        "Cannot normalize zero-length vector"
-    );
+    if norm.is_nan() || norm <= epsilon {
+        return Err(NanoVectorDbError::ZeroVector { norm, epsilon });
+    }
+
+    let inv_norm = 1.0 / norm;
+    Ok(vector.iter().map(|&x| x * inv_norm).collect())
+}
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+/// The current time as a [`Timestamp`]
+pub fn timestamp_now() -> Timestamp {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as Timestamp)
+}
+
+/// Check whether a vector is unit length, with its norm within `epsilon` of 1
+///
+/// Useful before [`NanoVectorDB::upsert_assume_normalized`], which trusts the
+/// caller. The zero vector is never normalized.
+pub fn is_normalized(vector: &[Float], epsilon: Float) -> bool {
+    let norm = vector_norm(vector);
+    norm > 0.0 && (norm - 1.0).abs() <= epsilon
+}
 
-    let inv_norm = 1.0 / norm_sq.sqrt();
-    vector.iter().map(|&x| x * inv_norm).collect()
+/// Check that every vector has `expected` values, reporting the index and
+/// length of the first one that does not
+pub fn validate_dim(vectors: &[Vec<Float>], expected: usize) -> Result<()> {
+    if let Some((index, vector)) = vectors
+        .iter()
+        .enumerate()
+        .find(|(_, vector)| vector.len() != expected)
+    {
+        return Err(NanoVectorDbError::AtIndex {
+            index,
+            source: Box::new(NanoVectorDbError::DimensionMismatch {
+                expected,
+                got: vector.len(),
+            }),
+        });
+    }
+    Ok(())
 }
 
 /// Tests
@@ -365,6 +5885,38 @@ mod tests {
     use std::collections::HashMap;
     use tempfile::NamedTempFile;
 
+    #[test]
+    fn test_retry_io_until_success() {
+        let transient = || NanoVectorDbError::Io(std::io::ErrorKind::TimedOut.into());
+        let mut calls = 0;
+        let result = retry_io(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls <= 2 {
+                Err(transient())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        calls = 0;
+        let result: Result<()> = retry_io(2, Duration::ZERO, || {
+            calls += 1;
+            Err(transient())
+        });
+        assert!(matches!(result, Err(NanoVectorDbError::Io(_))));
+        assert_eq!(calls, 2);
+
+        // Only I/O errors are retried
+        calls = 0;
+        let result: Result<()> = retry_io(5, Duration::ZERO, || {
+            calls += 1;
+            Err(NanoVectorDbError::InvalidInput("bad".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+
     #[test]
     fn test_base64_deserialization_edge_cases() {
         // Test valid base64 deserialization
@@ -374,13 +5926,29 @@ mod tests {
                 id: "test".to_string(),
                 vector: vec![1.0, 2.0],
                 fields: HashMap::new(),
+                weight: None,
             }],
-            matrix: vec![1.0, 2.0],
+            matrix: vec![1.0, 2.0].into(),
             additional_data: HashMap::new(),
+            weights: Vec::new(),
+            norms: Vec::new(),
+            named_vectors: HashMap::new(),
+            tombstones: BTreeSet::new(),
         };
         let serialized = serde_json::to_string(&valid_db).unwrap();
         let deserialized: DataBase = serde_json::from_str(&serialized).unwrap();
-        assert_eq!(deserialized.matrix, vec![1.0, 2.0]);
+        assert_eq!(deserialized.matrix[0..2], [1.0, 2.0]);
+
+        // Long matrices are decoded in several pieces
+        let values: Vec<Float> = (0..20_000).map(|i| i as Float).collect();
+        let long_db = DataBase {
+            embedding_dim: 20_000,
+            matrix: values.clone().into(),
+            ..valid_db
+        };
+        let serialized = serde_json::to_string(&long_db).unwrap();
+        let deserialized: DataBase = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.matrix[0..20_000], values[..]);
 
         // Test invalid base64 string
         let invalid_json = r#"{
@@ -405,9 +5973,14 @@ mod tests {
                 id: "bad_entry".to_string(),
                 vector: vec![1.0, 2.0], // Valid 2D vector
                 fields: HashMap::new(),
+                weight: None,
             }],
-            matrix: vec![1.0], // Should be 2 elements for 2D embedding
+            matrix: vec![1.0].into(), // Should be 2 elements for 2D embedding
             additional_data: HashMap::new(),
+            weights: Vec::new(),
+            norms: Vec::new(),
+            named_vectors: HashMap::new(),
+            tombstones: BTreeSet::new(),
         };
 
         // Write corrupted data to file
@@ -424,6 +5997,57 @@ mod tests {
         assert!(err_msg.contains("got 1"));
     }
 
+    #[test]
+    fn test_verify_detects_corruption() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+
+        let mut db = NanoVectorDB::new(2, path).unwrap();
+        db.upsert(vec![
+            Data {
+                id: "a".to_string(),
+                vector: vec![1.0, 2.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+            Data {
+                id: "b".to_string(),
+                vector: vec![3.0, -1.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+        ])
+        .unwrap();
+        db.verify().unwrap();
+
+        db.build_id_index();
+        db.verify().unwrap();
+        db.id_index.as_mut().unwrap().insert("a".to_string(), 1);
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("Id index maps \"a\""), "{err}");
+        db.id_index.as_mut().unwrap().insert("c".to_string(), 0);
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("Id index size mismatch"), "{err}");
+        db.build_id_index();
+
+        db.storage.weights = vec![2.0];
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("Weights size mismatch"), "{err}");
+        db.storage.weights.clear();
+
+        db.storage.matrix[3] = f32::NAN;
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("Non-finite value"), "{err}");
+
+        db.storage.matrix[3] = 5.0;
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("not unit length"), "{err}");
+
+        db.storage.matrix.truncate(3);
+        let err = db.verify().unwrap_err().to_string();
+        assert!(err.contains("Matrix size mismatch"), "{err}");
+    }
+
     #[test]
     fn test_scored_index_ordering() {
         let cases = vec![
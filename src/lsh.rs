@@ -0,0 +1,122 @@
+//! Random-hyperplane LSH index for approximate cosine search
+
+use crate::error::{NanoVectorDbError, Result};
+use crate::matrix::Matrix;
+use crate::Float;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+
+/// Hashes vectors into buckets by which side of random hyperplanes they fall on
+///
+/// Each table has its own set of `num_planes` hyperplanes, producing a
+/// `num_planes`-bit bucket key. Vectors with a small angle between them are
+/// likely to share a bucket in at least one table.
+#[derive(Debug)]
+pub(crate) struct LshIndex {
+    embedding_dim: usize,
+    num_planes: usize,
+    /// Per table, `num_planes` hyperplane normals stored row-major
+    planes: Vec<Vec<Float>>,
+    /// Per table, bucket key to storage indices
+    tables: Vec<HashMap<u64, Vec<usize>>>,
+}
+
+impl LshIndex {
+    pub(crate) fn new(
+        embedding_dim: usize,
+        num_planes: usize,
+        num_tables: usize,
+        seed: u64,
+    ) -> Result<Self> {
+        if num_planes == 0 || num_planes > 64 {
+            return Err(NanoVectorDbError::InvalidInput(format!(
+                "num_planes must be between 1 and 64, got {}",
+                num_planes
+            )));
+        }
+        if num_tables == 0 {
+            return Err(NanoVectorDbError::InvalidInput(
+                "num_tables must be at least 1".to_string(),
+            ));
+        }
+
+        let mut rng = StdRng::seed_from_u64(seed);
+        let planes = (0..num_tables)
+            .map(|_| {
+                (0..num_planes * embedding_dim)
+                    .map(|_| gaussian(&mut rng))
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            embedding_dim,
+            num_planes,
+            planes,
+            tables: vec![HashMap::new(); num_tables],
+        })
+    }
+
+    fn key(&self, table: usize, vector: &[Float]) -> u64 {
+        self.planes[table]
+            .chunks(self.embedding_dim)
+            .take(self.num_planes)
+            .enumerate()
+            .fold(0, |key, (bit, plane)| {
+                let side: Float = plane.iter().zip(vector).map(|(p, v)| p * v).sum();
+                if side >= 0.0 {
+                    key | (1 << bit)
+                } else {
+                    key
+                }
+            })
+    }
+
+    pub(crate) fn insert(&mut self, index: usize, vector: &[Float]) {
+        for table in 0..self.tables.len() {
+            let key = self.key(table, vector);
+            self.tables[table].entry(key).or_default().push(index);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, index: usize, vector: &[Float]) {
+        for table in 0..self.tables.len() {
+            let key = self.key(table, vector);
+            if let Some(bucket) = self.tables[table].get_mut(&key) {
+                bucket.retain(|&i| i != index);
+                if bucket.is_empty() {
+                    self.tables[table].remove(&key);
+                }
+            }
+        }
+    }
+
+    /// Re-hashes every row of the matrix, e.g. after deletes shifted indices
+    pub(crate) fn rebuild(&mut self, matrix: &Matrix) {
+        self.tables.iter_mut().for_each(HashMap::clear);
+        for (index, vector) in matrix.chunks(self.embedding_dim).enumerate() {
+            self.insert(index, vector);
+        }
+    }
+
+    /// Storage indices sharing a bucket with the query in any table, sorted
+    pub(crate) fn candidates(&self, query: &[Float]) -> Vec<usize> {
+        let mut candidates: HashSet<usize> = HashSet::new();
+        for (table, buckets) in self.tables.iter().enumerate() {
+            if let Some(bucket) = buckets.get(&self.key(table, query)) {
+                candidates.extend(bucket);
+            }
+        }
+        let mut candidates: Vec<usize> = candidates.into_iter().collect();
+        candidates.sort_unstable();
+        candidates
+    }
+}
+
+/// Standard normal sample via the Box-Muller transform
+fn gaussian(rng: &mut StdRng) -> Float {
+    let u1: f64 = rng.random_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.random();
+    ((-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()) as Float
+}
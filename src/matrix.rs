@@ -0,0 +1,323 @@
+//! Row-major vector matrix stored in one or more fixed-size segments
+
+use crate::Float;
+use std::ops::{Index, IndexMut, Range};
+
+/// Flat row-major matrix, optionally split into segments of `segment_len`
+/// values so that no single allocation holds the whole matrix
+///
+/// Every segment but the last is full, and `segment_len` is a multiple of the
+/// embedding dimension, so a row never straddles two segments. Without a
+/// segment length the matrix is a single growable segment, like a `Vec`.
+#[derive(Debug, Default)]
+pub(crate) struct Matrix {
+    segments: Vec<Vec<Float>>,
+    segment_len: Option<usize>,
+    len: usize,
+}
+
+impl From<Vec<Float>> for Matrix {
+    fn from(values: Vec<Float>) -> Self {
+        Self {
+            len: values.len(),
+            segments: vec![values],
+            segment_len: None,
+        }
+    }
+}
+
+impl Matrix {
+    /// An empty matrix segmented like this one
+    pub(crate) fn take(&mut self) -> Self {
+        let empty = Self {
+            segment_len: self.segment_len,
+            ..Default::default()
+        };
+        std::mem::replace(self, empty)
+    }
+
+    pub(crate) fn segment_len(&self) -> Option<usize> {
+        self.segment_len
+    }
+
+    /// Moves the values into segments of `segment_len` values, one source
+    /// segment at a time
+    ///
+    /// A single source segment is split from the back and shrunk as it goes,
+    /// so the values are never held twice.
+    pub(crate) fn resegment(&mut self, segment_len: Option<usize>) {
+        if segment_len == self.segment_len {
+            return;
+        }
+        let mut old = std::mem::take(&mut self.segments);
+        let len = std::mem::take(&mut self.len);
+        self.segment_len = segment_len;
+        match segment_len {
+            Some(segment_len) if old.len() == 1 => {
+                let mut source = old.pop().unwrap();
+                let mut segments = Vec::with_capacity(len.div_ceil(segment_len));
+                for start in (0..len).step_by(segment_len).rev() {
+                    segments.push(source[start..].to_vec());
+                    source.truncate(start);
+                    source.shrink_to_fit();
+                }
+                segments.reverse();
+                self.segments = segments;
+                self.len = len;
+            }
+            _ => {
+                if segment_len.is_none() {
+                    self.reserve_exact(len);
+                }
+                for segment in old {
+                    self.extend_from_slice(&segment);
+                }
+            }
+        }
+    }
+
+    /// The segments in order, for scans
+    pub(crate) fn segments(&self) -> Vec<&[Float]> {
+        self.segments.iter().map(Vec::as_slice).collect()
+    }
+
+    pub(crate) fn segments_mut(&mut self) -> impl Iterator<Item = &mut [Float]> {
+        self.segments.iter_mut().map(Vec::as_mut_slice)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        match self.segment_len {
+            // Allocated segments are filled up to `segment_len` before new ones
+            Some(segment_len) => self
+                .segments
+                .iter()
+                .map(|s| s.capacity().min(segment_len))
+                .sum(),
+            None => self.segments.first().map_or(0, Vec::capacity),
+        }
+    }
+
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let Some(segment_len) = self.segment_len else {
+            self.last_segment().reserve(additional);
+            return;
+        };
+        let mut missing = (self.len + additional).saturating_sub(self.capacity());
+        if missing == 0 {
+            return;
+        }
+        if let Some(last) = self.segments.last_mut() {
+            let room = segment_len - last.capacity().min(segment_len);
+            last.reserve_exact(segment_len - last.len());
+            missing -= missing.min(room);
+        }
+        while missing > 0 {
+            self.segments.push(Vec::with_capacity(segment_len));
+            missing -= missing.min(segment_len);
+        }
+    }
+
+    /// Like [`reserve`](Self::reserve), but without over-allocating the single
+    /// segment of an unsegmented matrix
+    pub(crate) fn reserve_exact(&mut self, additional: usize) {
+        match self.segment_len {
+            Some(_) => self.reserve(additional),
+            None => self.last_segment().reserve_exact(additional),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.segments.retain(|s| !s.is_empty());
+        self.segments.iter_mut().for_each(Vec::shrink_to_fit);
+    }
+
+    /// Appends values, starting new segments as the current one fills up
+    pub(crate) fn extend_from_slice(&mut self, mut values: &[Float]) {
+        let Some(segment_len) = self.segment_len else {
+            self.last_segment().extend_from_slice(values);
+            self.len += values.len();
+            return;
+        };
+        while !values.is_empty() {
+            // Segments past the one being filled may exist empty, from `reserve`
+            let (segment, offset) = self.locate(self.len);
+            if segment == self.segments.len() {
+                self.segments.push(Vec::with_capacity(segment_len));
+            }
+            let take = values.len().min(segment_len - offset);
+            self.segments[segment].extend_from_slice(&values[..take]);
+            self.len += take;
+            values = &values[take..];
+        }
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        match self.segment_len {
+            Some(segment_len) => {
+                self.segments.truncate(len.div_ceil(segment_len));
+                if let Some(last) = self.segments.last_mut() {
+                    last.truncate(len - (len - 1) / segment_len * segment_len);
+                }
+            }
+            None => self.last_segment().truncate(len),
+        }
+        self.len = len;
+    }
+
+    /// The values of `range`, which must lie within one segment (rows always do)
+    pub(crate) fn get(&self, range: Range<usize>) -> Option<&[Float]> {
+        if range.end > self.len || range.start > range.end {
+            return None;
+        }
+        let (segment, offset) = self.locate(range.start);
+        self.segments
+            .get(segment)?
+            .get(offset..offset + range.len())
+    }
+
+    fn get_mut(&mut self, range: Range<usize>) -> Option<&mut [Float]> {
+        if range.end > self.len || range.start > range.end {
+            return None;
+        }
+        let (segment, offset) = self.locate(range.start);
+        self.segments
+            .get_mut(segment)?
+            .get_mut(offset..offset + range.len())
+    }
+
+    /// Iterates over consecutive `chunk` values, like `slice::chunks`
+    ///
+    /// Chunks never span segments, so `chunk` must divide the segment length;
+    /// the embedding dimension always does.
+    pub(crate) fn chunks(&self, chunk: usize) -> impl Iterator<Item = &[Float]> {
+        self.segments.iter().flat_map(move |s| s.chunks(chunk))
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Float> {
+        self.segments.iter().flatten()
+    }
+
+    /// Copies `src` values to `dest`, like `slice::copy_within`
+    pub(crate) fn copy_within(&mut self, src: Range<usize>, dest: usize) {
+        debug_assert!(src.end <= self.len && dest + src.len() <= self.len);
+        if dest <= src.start {
+            let (mut from, mut to) = (src.start, dest);
+            while from < src.end {
+                let (from_segment, from_offset) = self.locate(from);
+                let (to_segment, to_offset) = self.locate(to);
+                let piece = (src.end - from)
+                    .min(self.segments[from_segment].len() - from_offset)
+                    .min(self.segments[to_segment].len() - to_offset);
+                self.copy_piece((from_segment, from_offset), (to_segment, to_offset), piece);
+                from += piece;
+                to += piece;
+            }
+        } else {
+            // Overlapping upward copies must go back to front
+            let (mut from_end, mut to_end) = (src.end, dest + src.len());
+            while from_end > src.start {
+                let (from_segment, from_last) = self.locate(from_end - 1);
+                let (to_segment, to_last) = self.locate(to_end - 1);
+                let piece = (from_end - src.start).min(from_last + 1).min(to_last + 1);
+                self.copy_piece(
+                    (from_segment, from_last + 1 - piece),
+                    (to_segment, to_last + 1 - piece),
+                    piece,
+                );
+                from_end -= piece;
+                to_end -= piece;
+            }
+        }
+    }
+
+    /// Copies `len` values between positions given as segment and offset
+    fn copy_piece(&mut self, from: (usize, usize), to: (usize, usize), len: usize) {
+        let ((from_segment, from_offset), (to_segment, to_offset)) = (from, to);
+        let source = from_offset..from_offset + len;
+        let target = to_offset..to_offset + len;
+        if from_segment == to_segment {
+            self.segments[from_segment].copy_within(source, to_offset);
+        } else if to_segment < from_segment {
+            let (low, high) = self.segments.split_at_mut(from_segment);
+            low[to_segment][target].copy_from_slice(&high[0][source]);
+        } else {
+            let (low, high) = self.segments.split_at_mut(to_segment);
+            high[0][target].copy_from_slice(&low[from_segment][source]);
+        }
+    }
+
+    /// Inserts `values` at `index`, shifting the rest up, like `Vec::insert`
+    ///
+    /// The inserted values must land within one segment (rows always do).
+    pub(crate) fn insert(&mut self, index: usize, values: &[Float]) {
+        let old_len = self.len;
+        self.extend_from_slice(values);
+        self.copy_within(index..old_len, index + values.len());
+        self[index..index + values.len()].copy_from_slice(values);
+    }
+
+    /// Removes the values of `range`, shifting the rest down
+    pub(crate) fn drain(&mut self, range: Range<usize>) {
+        let removed = range.len();
+        self.copy_within(range.end..self.len, range.start);
+        self.truncate(self.len - removed);
+    }
+
+    /// Segment index and offset of a value
+    fn locate(&self, index: usize) -> (usize, usize) {
+        match self.segment_len {
+            Some(segment_len) => (index / segment_len, index % segment_len),
+            None => (0, index),
+        }
+    }
+
+    fn last_segment(&mut self) -> &mut Vec<Float> {
+        if self.segments.is_empty() {
+            self.segments.push(Vec::new());
+        }
+        self.segments.last_mut().unwrap()
+    }
+}
+
+impl Index<usize> for Matrix {
+    type Output = Float;
+
+    fn index(&self, index: usize) -> &Float {
+        &self
+            .get(index..index + 1)
+            .expect("matrix index out of bounds")[0]
+    }
+}
+
+impl IndexMut<usize> for Matrix {
+    fn index_mut(&mut self, index: usize) -> &mut Float {
+        &mut self
+            .get_mut(index..index + 1)
+            .expect("matrix index out of bounds")[0]
+    }
+}
+
+impl Index<Range<usize>> for Matrix {
+    type Output = [Float];
+
+    fn index(&self, range: Range<usize>) -> &[Float] {
+        self.get(range).expect("matrix range out of bounds")
+    }
+}
+
+impl IndexMut<Range<usize>> for Matrix {
+    fn index_mut(&mut self, range: Range<usize>) -> &mut [Float] {
+        self.get_mut(range).expect("matrix range out of bounds")
+    }
+}
@@ -0,0 +1,104 @@
+//! Scalar int8 quantization of the vector matrix
+
+use crate::matrix::Matrix;
+use crate::Float;
+
+/// Parameters for scalar int8 quantization
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantParams {
+    /// Use one scale per vector (`max |x| / 127`) instead of a single global scale
+    ///
+    /// The global scale assumes components lie in `[-1, 1]`, as they do for
+    /// cosine-normalized vectors; larger components are clamped.
+    pub per_vector_scale: bool,
+}
+
+impl Default for QuantParams {
+    fn default() -> Self {
+        Self {
+            per_vector_scale: true,
+        }
+    }
+}
+
+/// Int8 codes of every stored vector, with the scale to dequantize each row
+#[derive(Debug)]
+pub(crate) struct QuantizedMatrix {
+    params: QuantParams,
+    embedding_dim: usize,
+    pub(crate) codes: Vec<i8>,
+    scales: Vec<Float>,
+}
+
+impl QuantizedMatrix {
+    pub(crate) fn new(embedding_dim: usize, params: QuantParams) -> Self {
+        Self {
+            params,
+            embedding_dim,
+            codes: Vec::new(),
+            scales: Vec::new(),
+        }
+    }
+
+    fn quantize(&self, row: &[Float]) -> (Vec<i8>, Float) {
+        let scale = if self.params.per_vector_scale {
+            row.iter().fold(0.0 as Float, |m, x| m.max(x.abs())) / 127.0
+        } else {
+            1.0 / 127.0
+        };
+        if scale == 0.0 {
+            return (vec![0; row.len()], 0.0);
+        }
+        let codes = row
+            .iter()
+            .map(|x| (x / scale).round().clamp(-127.0, 127.0) as i8)
+            .collect();
+        (codes, scale)
+    }
+
+    /// Sets the codes of the row at `index`, appending when `index == len`
+    pub(crate) fn set_row(&mut self, index: usize, row: &[Float]) {
+        let (codes, scale) = self.quantize(row);
+        let start = index * self.embedding_dim;
+        if index == self.scales.len() {
+            self.codes.extend(codes);
+            self.scales.push(scale);
+        } else {
+            self.codes[start..start + self.embedding_dim].copy_from_slice(&codes);
+            self.scales[index] = scale;
+        }
+    }
+
+    /// Re-quantizes the whole matrix, e.g. after deletes shifted indices
+    pub(crate) fn rebuild(&mut self, matrix: &Matrix) {
+        self.codes.clear();
+        self.scales.clear();
+        for (index, row) in matrix.chunks(self.embedding_dim).enumerate() {
+            self.set_row(index, row);
+        }
+    }
+
+    /// Dot product of a quantized row with an f32 query, dequantized by the row scale
+    pub(crate) fn dot(&self, index: usize, codes: &[i8], query: &[Float]) -> Float {
+        let sum: Float = codes.iter().zip(query).map(|(&c, q)| c as Float * q).sum();
+        sum * self.scales[index]
+    }
+
+    /// Root-mean-square error between the dequantized codes and `matrix`
+    pub(crate) fn error(&self, matrix: &Matrix) -> Float {
+        if matrix.is_empty() {
+            return 0.0;
+        }
+        let squared: Float = matrix
+            .chunks(self.embedding_dim)
+            .zip(self.codes.chunks(self.embedding_dim))
+            .zip(&self.scales)
+            .flat_map(|((row, codes), scale)| {
+                row.iter()
+                    .zip(codes)
+                    .map(move |(x, &c)| (x - c as Float * scale).powi(2))
+            })
+            .sum();
+        (squared / matrix.len() as Float).sqrt()
+    }
+}
@@ -0,0 +1,106 @@
+//! Fan-out queries over several databases sharing one dimension and metric
+
+use crate::error::{NanoVectorDbError, Result};
+use crate::{DataFilter, Float, NanoVectorDB};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Several [`NanoVectorDB`] shards queried as one
+///
+/// Each shard keeps its own storage file; queries run on every shard (in
+/// parallel with the `parallel` feature) and the per-shard top-k lists are
+/// merged into a global top-k.
+#[derive(Debug)]
+pub struct ShardedNanoVectorDB {
+    shards: Vec<NanoVectorDB>,
+}
+
+impl ShardedNanoVectorDB {
+    /// Groups `shards`, which must share embedding dimension and metric
+    pub fn new(shards: Vec<NanoVectorDB>) -> Result<Self> {
+        if let Some(first) = shards.first() {
+            for (i, shard) in shards.iter().enumerate().skip(1) {
+                if shard.embedding_dim != first.embedding_dim {
+                    return Err(NanoVectorDbError::AtIndex {
+                        index: i,
+                        source: Box::new(NanoVectorDbError::DimensionMismatch {
+                            expected: first.embedding_dim,
+                            got: shard.embedding_dim,
+                        }),
+                    });
+                }
+                if shard.metric != first.metric {
+                    return Err(NanoVectorDbError::InvalidInput(format!(
+                        "Shard {} metric mismatch: expected {:?}, got {:?}",
+                        i, first.metric, shard.metric
+                    )));
+                }
+            }
+        }
+        Ok(Self { shards })
+    }
+
+    /// Get the shards
+    pub fn shards(&self) -> &[NanoVectorDB] {
+        &self.shards
+    }
+
+    /// Get mutable access to the shards, e.g. to upsert into one of them
+    pub fn shards_mut(&mut self) -> &mut [NanoVectorDB] {
+        &mut self.shards
+    }
+
+    /// Get the total number of vectors across shards
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(NanoVectorDB::len).sum()
+    }
+
+    /// Check if every shard is empty
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(NanoVectorDB::is_empty)
+    }
+
+    /// Queries every shard and returns the global top-k, like [`NanoVectorDB::query`]
+    pub fn query(
+        &self,
+        query: &[Float],
+        top_k: usize,
+        better_than: Option<Float>,
+        filter: Option<DataFilter>,
+    ) -> Vec<HashMap<String, serde_json::Value>> {
+        let shard_top_k = |(shard_index, shard): (usize, &NanoVectorDB)| {
+            if shard.is_empty() {
+                return Vec::new();
+            }
+            let Ok(query_norm) = shard.prepare_query(query) else {
+                return Vec::new();
+            };
+            let threshold = shard.threshold(better_than);
+            shard
+                .top_k_scored(&query_norm, top_k, threshold, filter.as_ref())
+                .into_iter()
+                .map(|si| (shard_index, si))
+                .collect::<Vec<_>>()
+        };
+
+        #[cfg(feature = "parallel")]
+        let per_shard: Vec<_> = self
+            .shards
+            .par_iter()
+            .enumerate()
+            .map(shard_top_k)
+            .collect();
+        #[cfg(not(feature = "parallel"))]
+        let per_shard: Vec<_> = self.shards.iter().enumerate().map(shard_top_k).collect();
+
+        // Ties keep shard order, then each shard's own order
+        let mut merged: Vec<_> = per_shard.into_iter().flatten().collect();
+        merged.sort_by(|(_, a), (_, b)| b.score.total_cmp(&a.score));
+        merged
+            .iter()
+            .take(top_k)
+            .map(|(shard_index, si)| self.shards[*shard_index].to_result(si))
+            .collect()
+    }
+}
@@ -0,0 +1,105 @@
+//! Append-only log of record writes made since the last snapshot
+
+use crate::error::Result;
+use crate::Float;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// One logged write, stored as a JSON line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum WalOp {
+    /// A record as stored after the write, with its vector already normalized
+    Upsert {
+        id: String,
+        vector: Vec<Float>,
+        fields: HashMap<String, serde_json::Value>,
+        /// The vector's norm before normalization; absent in older logs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        norm: Option<Float>,
+        /// The record's ranking weight; absent if never set or in older logs
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        weight: Option<Float>,
+    },
+    Delete {
+        ids: Vec<String>,
+    },
+    /// Every record was removed
+    Clear,
+}
+
+/// Writes are buffered in memory until [`flush`](Self::flush) appends them to
+/// the log file and syncs it
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    path: PathBuf,
+    /// JSON lines not yet written to the file
+    pending: Mutex<String>,
+}
+
+impl WriteAheadLog {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            pending: Mutex::new(String::new()),
+        }
+    }
+
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, String> {
+        self.pending
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn append(&self, op: &WalOp) {
+        // String keys and plain values cannot fail to serialize
+        let line = serde_json::to_string(op).expect("log entries always serialize");
+        let mut pending = self.lock_pending();
+        pending.push_str(&line);
+        pending.push('\n');
+    }
+
+    /// Appends the buffered writes to the log file and waits until they are on
+    /// disk
+    pub(crate) fn flush(&self) -> Result<()> {
+        let mut pending = self.lock_pending();
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(pending.as_bytes())?;
+        file.sync_data()?;
+        pending.clear();
+        Ok(())
+    }
+
+    /// Empties the log, once a snapshot holds everything it recorded
+    pub(crate) fn truncate(&self) -> Result<()> {
+        let mut pending = self.lock_pending();
+        if self.path.exists() {
+            fs::write(&self.path, "")?;
+        }
+        pending.clear();
+        Ok(())
+    }
+
+    /// The logged writes in order, stopping at the first line that does not
+    /// parse (the torn tail of a write interrupted by a crash)
+    pub(crate) fn read(&self) -> Result<Vec<WalOp>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(contents
+            .lines()
+            .map_while(|line| serde_json::from_str(line).ok())
+            .collect())
+    }
+}
@@ -24,6 +24,7 @@ fn test_large_dataset() {
             id: format!("vec_{i}"),
             vector: vec![0.1; 128],
             fields: std::collections::HashMap::new(),
+            weight: None,
         })
         .collect();
 
@@ -49,6 +50,7 @@ fn test_update_operations() {
         id: "vec_0".to_string(),
         vector: vec![0.1; 128],
         fields: std::collections::HashMap::new(),
+        weight: None,
     };
     let (_, inserts) = db.upsert(vec![data]).unwrap();
     assert_eq!(inserts.len(), 1);
@@ -58,6 +60,7 @@ fn test_update_operations() {
         id: "vec_0".to_string(),
         vector: vec![0.2; 128],
         fields: std::collections::HashMap::new(),
+        weight: None,
     };
     let (updates, _) = db.upsert(vec![updated_data]).unwrap();
     assert_eq!(updates.len(), 1);
@@ -178,6 +181,7 @@ fn test_real_embeddings_with_candle() -> Result<()> {
                 id: id.to_string(),
                 vector: embedding,
                 fields,
+                weight: None,
             }
         })
         .collect();
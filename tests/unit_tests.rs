@@ -1,7 +1,61 @@
-use nano_vectordb_rs::{constants, dot_product, normalize, Data, NanoVectorDB};
+use nano_vectordb_rs::{
+    constants, dot_product, dot_product_f64_acc, is_normalized, normalize, normalize_with_epsilon,
+    timestamp_now, validate_dim, CacheStats, CollisionPolicy, ColumnMap, ConcurrentNanoVectorDB,
+    Data, DuplicationStats, FieldType, IdStrategy, MatrixGrowth, Metric, NanoVectorDB,
+    NanoVectorDbError, Order, QuantParams, QueryAgg, QueryHit, QueryResult, RepairReport,
+    ResultFormat, ScoreDiagnostics, ScoreTransform, ShardedNanoVectorDB, VectorColumns,
+};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use tempfile::NamedTempFile;
 
+/// Vectors drawn from `rng` with components uniform in [-1, 1)
+fn random_vectors(rng: &mut rand::rngs::StdRng, dim: usize, count: usize) -> Vec<Vec<f32>> {
+    use rand::Rng;
+
+    (0..count)
+        .map(|_| (0..dim).map(|_| rng.random_range(-1.0..1.0)).collect())
+        .collect()
+}
+
+/// Records `v{first}`, `v{first + 1}`, ... holding `vectors`
+fn numbered_records(first: usize, vectors: &[Vec<f32>]) -> Vec<Data> {
+    vectors
+        .iter()
+        .enumerate()
+        .map(|(i, vector)| Data {
+            id: format!("v{}", first + i),
+            vector: vector.clone(),
+            fields: HashMap::new(),
+            weight: None,
+        })
+        .collect()
+}
+
+/// An unquantized database holding `vectors`, to compare approximate ones against
+fn exact_db(path: &str, vectors: &[Vec<f32>]) -> NanoVectorDB {
+    let mut db = NanoVectorDB::new(vectors[0].len(), path).unwrap();
+    db.upsert(numbered_records(0, vectors)).unwrap();
+    db
+}
+
+/// A database holding `vectors` quantized with one global scale, which is
+/// coarse enough that the raw int8 order has errors
+fn quantized_db(
+    path: &str,
+    vectors: &[Vec<f32>],
+    configure: impl FnOnce(NanoVectorDB) -> NanoVectorDB,
+) -> NanoVectorDB {
+    let params = QuantParams {
+        per_vector_scale: false,
+    };
+    let db = NanoVectorDB::with_scalar_quantization(vectors[0].len(), path, params).unwrap();
+    let mut db = configure(db);
+    db.upsert(numbered_records(0, vectors)).unwrap();
+    db
+}
+
 #[test]
 fn test_basic_operations() {
     let temp_file = NamedTempFile::new().unwrap();
@@ -13,6 +67,7 @@ fn test_basic_operations() {
         id: "test".to_string(),
         vector: vec![0.1; 128],
         fields: HashMap::new(),
+        weight: None,
     };
     let (updates, inserts) = db.upsert(vec![data]).unwrap();
     db.save().unwrap();
@@ -42,6 +97,7 @@ fn test_persistence() {
         id: "test".to_string(),
         vector: vec![0.1; 128],
         fields: HashMap::new(),
+        weight: None,
     }])
     .unwrap();
     db.save().unwrap();
@@ -89,12 +145,14 @@ fn test_get_method() {
         id: "test1".to_string(),
         vector: vec![0.1; 128],
         fields: [("color".to_string(), "red".into())].into(),
+        weight: None,
     };
 
     let data2 = Data {
         id: "test2".to_string(),
         vector: vec![0.2; 128],
         fields: [("color".to_string(), "blue".into())].into(),
+        weight: None,
     };
 
     db.upsert(vec![data1, data2]).unwrap();
@@ -117,12 +175,14 @@ fn test_delete_method() {
         id: "test1".to_string(),
         vector: vec![0.1; 128],
         fields: HashMap::new(),
+        weight: None,
     };
 
     let data2 = Data {
         id: "test2".to_string(),
         vector: vec![0.2; 128],
         fields: HashMap::new(),
+        weight: None,
     };
 
     db.upsert(vec![data1, data2]).unwrap();
@@ -261,6 +321,7 @@ fn test_empty_state_checks() {
         id: "test".to_string(),
         vector: vec![0.1; 128],
         fields: HashMap::new(),
+        weight: None,
     }])
     .unwrap();
     assert!(!db.is_empty());
@@ -276,3 +337,3985 @@ fn test_empty_state_checks() {
     let db2 = NanoVectorDB::new(128, path).unwrap();
     assert!(db2.is_empty());
 }
+
+#[test]
+fn test_content_hash_id_strategy() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(4, path)
+        .unwrap()
+        .with_id_strategy(IdStrategy::ContentHash);
+
+    let (_, inserts) = db
+        .upsert(vec![
+            Data {
+                id: String::new(),
+                vector: vec![1.0, 2.0, 3.0, 4.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+            Data {
+                id: String::new(),
+                vector: vec![4.0, 3.0, 2.0, 1.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+        ])
+        .unwrap();
+    assert_eq!(inserts.len(), 2);
+    assert!(inserts.iter().all(|id| !id.is_empty()));
+    assert_ne!(inserts[0], inserts[1]);
+
+    // Re-upserting an identical vector resolves to the same generated id
+    let (updates, inserts_again) = db
+        .upsert(vec![Data {
+            id: String::new(),
+            vector: vec![1.0, 2.0, 3.0, 4.0],
+            fields: HashMap::new(),
+            weight: None,
+        }])
+        .unwrap();
+    assert!(inserts_again.is_empty());
+    assert_eq!(updates, vec![inserts[0].clone()]);
+    assert_eq!(db.len(), 2);
+
+    // Identical vectors within one batch collapse into one record
+    let record = |id: &str| Data {
+        id: id.to_string(),
+        vector: vec![0.0, 1.0, 0.0, 1.0],
+        fields: HashMap::new(),
+        weight: None,
+    };
+    let (_, inserts) = db.upsert(vec![record(""), record("")]).unwrap();
+    assert_eq!(inserts.len(), 1);
+    assert_eq!(db.len(), 3);
+
+    // Repeated caller-supplied ids are stored as given, as without a strategy
+    let (_, inserts) = db.upsert(vec![record("dup"), record("dup")]).unwrap();
+    assert_eq!(inserts, ["dup", "dup"]);
+    assert_eq!(db.len(), 5);
+}
+
+#[test]
+fn test_query_full_includes_vector() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(4, path).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 2.0, 3.0, 4.0],
+            fields: [("color".to_string(), "red".into())].into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![-1.0, 0.5, 0.0, 2.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    let results = db.query_full(&[1.0, 2.0, 3.0, 4.0], 2, None, None);
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0][constants::F_ID], "a");
+    assert_eq!(results[0]["color"], "red");
+
+    let expected = normalize(&[1.0, 2.0, 3.0, 4.0]);
+    let returned: Vec<f32> = results[0][constants::F_VECTOR]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|v| v.as_f64().unwrap() as f32)
+        .collect();
+    assert_eq!(returned.len(), db.embedding_dim());
+    assert_eq!(returned, expected);
+}
+
+#[test]
+fn test_query_relative_cutoff() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(4, path).unwrap();
+    let records = [
+        ("close1", vec![1.0, 0.05, 0.0, 0.0]),
+        ("close2", vec![1.0, 0.0, 0.1, 0.0]),
+        ("far1", vec![1.0, 1.0, 0.0, 0.0]),
+        ("far2", vec![0.0, 1.0, 0.0, 0.0]),
+        ("far3", vec![0.2, 0.0, 0.0, 1.0]),
+    ];
+    db.upsert(
+        records
+            .iter()
+            .map(|(id, vector)| Data {
+                id: id.to_string(),
+                vector: vector.clone(),
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let results = db.query_relative(&[1.0, 0.0, 0.0, 0.0], 5, 0.95, None);
+    let ids: Vec<_> = results
+        .iter()
+        .map(|r| r[constants::F_ID].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["close1", "close2"]);
+
+    // Distances are cut at the best distance divided by the fraction
+    let mut db = NanoVectorDB::new(2, "").unwrap().with_metric(Metric::L2);
+    db.upsert(
+        [("d1", 1.0), ("d1.04", 1.04), ("d2", 2.0), ("d5", 5.0)]
+            .iter()
+            .map(|(id, x)| Data {
+                id: id.to_string(),
+                vector: vec![*x, 0.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let results = db.query_relative(&[0.0, 0.0], 5, 0.95, None);
+    let ids: Vec<_> = results
+        .iter()
+        .map(|r| r[constants::F_ID].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["d1", "d1.04"]);
+}
+
+#[test]
+fn test_query_empty_database() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let db = NanoVectorDB::new(4, path).unwrap();
+    assert!(db.query(&[0.0; 4], 5, None, None).is_empty());
+    assert!(db.query(&[1.0, 2.0, 3.0, 4.0], 5, None, None).is_empty());
+}
+
+#[test]
+fn test_fields_sidecar() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("db.json");
+    let path = path.to_str().unwrap();
+    let sidecar_path = format!("{path}.fields");
+    let long_text = "lorem ipsum ".repeat(1000);
+
+    let mut db = NanoVectorDB::new(4, path).unwrap().with_fields_sidecar();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 0.0, 0.0, 0.0],
+            fields: [("text".to_string(), long_text.clone().into())].into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![0.0, 1.0, 0.0, 0.0],
+            fields: [("text".to_string(), "short".into())].into(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    db.save().unwrap();
+
+    // The main file only holds ids and vectors
+    let main_contents = std::fs::read_to_string(path).unwrap();
+    assert!(!main_contents.contains("lorem ipsum"));
+    assert!(std::fs::read_to_string(&sidecar_path)
+        .unwrap()
+        .contains("lorem ipsum"));
+
+    // `get` pulls the full fields from the sidecar on demand
+    let db2 = NanoVectorDB::new(4, path).unwrap().with_fields_sidecar();
+    let records = db2.get(&["a".to_string()]);
+    assert_eq!(records[0].fields["text"], long_text.as_str());
+
+    // Vector queries only need the main file; an unreadable sidecar fails the
+    // fallible methods instead of being taken for one without fields
+    let contents = std::fs::read_to_string(&sidecar_path).unwrap();
+    std::fs::write(&sidecar_path, "not json").unwrap();
+    let mut db3 = NanoVectorDB::new(4, path).unwrap().with_fields_sidecar();
+    let results = db3.query(&[0.0, 1.0, 0.0, 0.0], 1, None, None);
+    assert_eq!(results[0][constants::F_ID], "b");
+    assert!(!results[0].contains_key("text"));
+    assert!(matches!(
+        db3.load_fields(),
+        Err(NanoVectorDbError::Deserialize(_))
+    ));
+    assert!(db3.to_bytes().is_err());
+    let record = Data {
+        id: "c".to_string(),
+        vector: vec![0.0, 0.0, 1.0, 0.0],
+        fields: HashMap::new(),
+        weight: None,
+    };
+    assert!(db3.upsert(vec![record]).is_err());
+    std::fs::write(&sidecar_path, contents).unwrap();
+    assert_eq!(
+        db3.get(&["a".to_string()])[0].fields["text"],
+        long_text.as_str()
+    );
+}
+
+#[test]
+fn test_renormalize_all() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(4, path).unwrap();
+    db.upsert_assume_normalized(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![3.0, 4.0, 0.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![0.5, 0.5, 0.5, 2.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    let results = db.query_full(&[1.0, 0.0, 0.0, 0.0], 2, None, None);
+    let row_norm = |result: &HashMap<String, serde_json::Value>| {
+        result[constants::F_VECTOR]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_f64().unwrap().powi(2))
+            .sum::<f64>()
+            .sqrt()
+    };
+    assert!(results.iter().any(|r| (row_norm(r) - 1.0).abs() > 0.1));
+
+    db.renormalize_all().unwrap();
+    let results = db.query_full(&[1.0, 0.0, 0.0, 0.0], 2, None, None);
+    assert_eq!(results.len(), 2);
+    for result in &results {
+        assert!((row_norm(result) - 1.0).abs() < 1e-5);
+    }
+
+    // Zero-length rows are rejected
+    db.upsert_assume_normalized(vec![Data {
+        id: "zero".to_string(),
+        vector: vec![0.0; 4],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    assert!(db.renormalize_all().is_err());
+}
+
+#[test]
+fn test_vector_at_and_id_at() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(3, path).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "first".to_string(),
+            vector: vec![1.0, 2.0, 2.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "second".to_string(),
+            vector: vec![0.0, 1.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    assert_eq!(db.vector_at(0).unwrap(), normalize(&[1.0, 2.0, 2.0]));
+    assert_eq!(db.id_at(0), Some("first"));
+    assert_eq!(db.id_at(1), Some("second"));
+    assert!(db.vector_at(2).is_none());
+    assert!(db.id_at(2).is_none());
+}
+
+#[test]
+fn test_distance_score_transform() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    for transform in [ScoreTransform::Reciprocal, ScoreTransform::Exp] {
+        let mut db = NanoVectorDB::new(2, path)
+            .unwrap()
+            .with_metric(Metric::L2)
+            .with_score_transform(transform);
+        db.upsert(
+            (0..5)
+                .map(|i| Data {
+                    id: format!("p{i}"),
+                    vector: vec![i as f32, 0.0],
+                    fields: HashMap::new(),
+                    weight: None,
+                })
+                .collect(),
+        )
+        .unwrap();
+
+        let results = db.query(&[0.0, 0.0], 5, None, None);
+        let ids: Vec<_> = results
+            .iter()
+            .map(|r| r[constants::F_ID].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["p0", "p1", "p2", "p3", "p4"]);
+
+        let scores: Vec<f64> = results
+            .iter()
+            .map(|r| r[constants::F_METRICS].as_f64().unwrap())
+            .collect();
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+        assert!(scores.windows(2).all(|w| w[0] > w[1]));
+        assert_eq!(scores[0], 1.0);
+    }
+
+    // Without a transform the raw distance is reported, and `better_than` is a max distance
+    let mut db = NanoVectorDB::new(2, path).unwrap().with_metric(Metric::L2);
+    db.upsert(vec![
+        Data {
+            id: "near".to_string(),
+            vector: vec![3.0, 4.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "far".to_string(),
+            vector: vec![30.0, 40.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let results = db.query(&[0.0, 0.0], 2, Some(10.0), None);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0][constants::F_METRICS].as_f64().unwrap(), 5.0);
+}
+
+#[test]
+fn test_query_grouped() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    let records = [
+        ("a1", "news", [1.0, 0.0]),
+        ("a2", "news", [1.0, 0.1]),
+        ("a3", "news", [1.0, 0.2]),
+        ("b1", "blog", [1.0, 0.5]),
+        ("b2", "blog", [1.0, 0.6]),
+        ("c1", "wiki", [0.0, 1.0]),
+    ];
+    let mut datas: Vec<Data> = records
+        .iter()
+        .map(|(id, source, vector)| Data {
+            id: id.to_string(),
+            vector: vector.to_vec(),
+            fields: [("source".to_string(), (*source).into())].into(),
+            weight: None,
+        })
+        .collect();
+    datas.push(Data {
+        id: "untagged".to_string(),
+        vector: vec![1.0, 0.0],
+        fields: HashMap::new(),
+        weight: None,
+    });
+    db.upsert(datas).unwrap();
+
+    let results = db.query_grouped(&[1.0, 0.0], "source", 1, None, None);
+    let ids: Vec<_> = results
+        .iter()
+        .map(|r| r[constants::F_ID].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["a1", "b1", "c1"]);
+
+    let results = db.query_grouped(&[1.0, 0.0], "source", 2, None, None);
+    let mut per_source: HashMap<String, usize> = HashMap::new();
+    for result in &results {
+        *per_source
+            .entry(result["source"].as_str().unwrap().to_string())
+            .or_default() += 1;
+    }
+    assert_eq!(results.len(), 5);
+    assert!(per_source.values().all(|&count| count <= 2));
+    assert_eq!(per_source["wiki"], 1);
+}
+
+#[test]
+fn test_query_mmr_diversity() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(3, path).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "dup1".to_string(),
+            vector: vec![1.0, 0.1, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "dup2".to_string(),
+            vector: vec![1.0, 0.1, 0.001],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "diverse".to_string(),
+            vector: vec![0.8, 0.0, 0.6],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    let ids = |results: Vec<HashMap<String, serde_json::Value>>| {
+        results
+            .iter()
+            .map(|r| r[constants::F_ID].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // Pure relevance keeps both near-duplicates
+    let plain = ids(db.query(&[1.0, 0.0, 0.0], 2, None, None));
+    assert!(plain.contains(&"dup1".to_string()) && plain.contains(&"dup2".to_string()));
+
+    // Low lambda picks one duplicate plus the diverse record
+    let mmr = ids(db.query_mmr(&[1.0, 0.0, 0.0], 2, 0.3, None));
+    assert_eq!(mmr.len(), 2);
+    assert!(mmr[0] == "dup1" || mmr[0] == "dup2");
+    assert_eq!(mmr[1], "diverse");
+}
+
+#[cfg(feature = "vector-serde")]
+#[test]
+fn test_deserialize_data_with_vector() {
+    let data: Data =
+        serde_json::from_str(r#"{"__id__": "doc1", "vector": [0.5, -1.0, 2.0], "title": "hello"}"#)
+            .unwrap();
+    assert_eq!(data.id, "doc1");
+    assert_eq!(data.vector, vec![0.5, -1.0, 2.0]);
+    assert_eq!(data.fields["title"], "hello");
+    assert!(!data.fields.contains_key("vector"));
+
+    // Serialization still leaves the vector to the matrix
+    let serialized = serde_json::to_string(&data).unwrap();
+    assert!(!serialized.contains("vector"));
+}
+
+#[test]
+fn test_score_epsilon() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let records = || {
+        vec![
+            Data {
+                id: "lower".to_string(),
+                vector: vec![0.5, 0.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+            Data {
+                id: "higher".to_string(),
+                vector: vec![0.500_000_2, 0.0],
+                fields: HashMap::new(),
+                weight: None,
+            },
+        ]
+    };
+    let ids = |results: Vec<HashMap<String, serde_json::Value>>| {
+        results
+            .iter()
+            .map(|r| r[constants::F_ID].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+
+    let mut exact = NanoVectorDB::new(2, path).unwrap().with_metric(Metric::Dot);
+    exact.upsert(records()).unwrap();
+    assert_eq!(
+        ids(exact.query(&[1.0, 0.0], 2, None, None)),
+        ["higher", "lower"]
+    );
+    assert_eq!(
+        ids(exact.query(&[1.0, 0.0], 2, Some(0.500_000_1), None)),
+        ["higher"]
+    );
+
+    // Within epsilon both pass the threshold and are ordered by insertion
+    let mut tolerant = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_metric(Metric::Dot)
+        .with_score_epsilon(1e-5);
+    tolerant.upsert(records()).unwrap();
+    assert_eq!(
+        ids(tolerant.query(&[1.0, 0.0], 2, None, None)),
+        ["lower", "higher"]
+    );
+    assert_eq!(
+        ids(tolerant.query(&[1.0, 0.0], 2, Some(0.500_000_1), None)),
+        ["lower", "higher"]
+    );
+    // Near-ties at the top_k cut-off are settled by insertion order too
+    assert_eq!(ids(tolerant.query(&[1.0, 0.0], 1, None, None)), ["lower"]);
+
+    // Grouped results across groups are ordered the same way
+    let mut grouped = NanoVectorDB::new(2, "")
+        .unwrap()
+        .with_metric(Metric::Dot)
+        .with_score_epsilon(1e-5);
+    grouped
+        .upsert(
+            records()
+                .into_iter()
+                .map(|mut data| {
+                    data.fields
+                        .insert("group".to_string(), data.id.clone().into());
+                    data
+                })
+                .collect(),
+        )
+        .unwrap();
+    assert_eq!(
+        ids(grouped.query_grouped(&[1.0, 0.0], "group", 1, None, None)),
+        ["lower", "higher"]
+    );
+}
+
+#[test]
+fn test_lsh_recall() {
+    use rand::{Rng, SeedableRng};
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let dim = 32;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+
+    // Ten well-separated clusters of 50 points each
+    let centers = random_vectors(&mut rng, dim, 10);
+    let datas: Vec<Data> = (0..500)
+        .map(|i| Data {
+            id: format!("v{i}"),
+            vector: centers[i % 10]
+                .iter()
+                .map(|c| c + rng.random_range(-0.05..0.05))
+                .collect(),
+            fields: HashMap::new(),
+            weight: None,
+        })
+        .collect();
+
+    let mut db = NanoVectorDB::new(dim, path).unwrap();
+    db.upsert(datas).unwrap();
+
+    let queries: Vec<Vec<f32>> = centers
+        .iter()
+        .map(|c| {
+            c.iter()
+                .map(|x| x + rng.random_range(-0.05..0.05))
+                .collect()
+        })
+        .collect();
+    let top_ids = |db: &NanoVectorDB, q: &[f32]| {
+        db.query(q, 10, None, None)
+            .iter()
+            .map(|r| r[constants::F_ID].as_str().unwrap().to_string())
+            .collect::<Vec<_>>()
+    };
+    let exact: Vec<_> = queries.iter().map(|q| top_ids(&db, q)).collect();
+
+    db.enable_lsh(8, 8, Some(42)).unwrap();
+    let mut hits = 0;
+    for (q, expected) in queries.iter().zip(&exact) {
+        let approx = top_ids(&db, q);
+        hits += approx.iter().filter(|id| expected.contains(id)).count();
+    }
+    let recall = hits as f32 / (queries.len() * 10) as f32;
+    assert!(recall >= 0.9, "recall {recall}");
+
+    // Buckets follow deletes
+    db.delete(&exact[0].clone());
+    let after = top_ids(&db, &queries[0]);
+    assert!(after.iter().all(|id| !exact[0].contains(id)));
+
+    assert!(db.enable_lsh(65, 1, Some(0)).is_err());
+}
+
+#[test]
+fn test_custom_result_field_names() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_id_field("id")
+        .with_metrics_field("score");
+    db.upsert(vec![Data {
+        id: "a".to_string(),
+        vector: vec![1.0, 0.0],
+        fields: [("__metrics__".to_string(), "user value".into())].into(),
+        weight: None,
+    }])
+    .unwrap();
+
+    let results = db.query(&[1.0, 0.0], 1, None, None);
+    assert_eq!(results[0]["id"], "a");
+    assert!(results[0]["score"].as_f64().unwrap() > 0.99);
+    assert_eq!(results[0][constants::F_METRICS], "user value");
+    assert!(!results[0].contains_key(constants::F_ID));
+}
+
+#[test]
+fn test_reserved_field_collision() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    let result = db.upsert(vec![
+        Data {
+            id: "ok".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "clash".to_string(),
+            vector: vec![0.0, 1.0],
+            fields: [(constants::F_METRICS.to_string(), 1.into())].into(),
+            weight: None,
+        },
+    ]);
+
+    let err = result.unwrap_err().to_string();
+    assert!(err.contains("clash"), "{err}");
+    assert!(!err.contains("\"ok\""), "{err}");
+    assert!(db.is_empty());
+}
+
+#[test]
+fn test_binary_results_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(3, path).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 0.2, 0.0],
+            fields: [
+                ("title".to_string(), "Ünïcode".into()),
+                ("tags".to_string(), serde_json::json!(["x", "y"])),
+            ]
+            .into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![0.0, 1.0, 0.3],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    let results = db.query(&[1.0, 0.5, 0.1], 2, None, None);
+    let bytes = db.encode_results(&results).unwrap();
+    let decoded = db.decode_results(&bytes).unwrap();
+    assert_eq!(decoded, results);
+
+    assert!(db.decode_results(&bytes[..bytes.len() - 1]).is_err());
+    // A huge claimed count fails on the missing records instead of allocating
+    assert!(db.decode_results(&u32::MAX.to_le_bytes()).is_err());
+}
+
+#[cfg(feature = "roaring")]
+#[test]
+fn test_query_bitmap() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    db.upsert(
+        (0..10)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32 * 0.1],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let allowed: roaring::RoaringBitmap = [3u32, 5, 7, 100].into_iter().collect();
+    let results = db.query_bitmap(&[1.0, 0.0], &allowed, 10, None);
+    let ids: Vec<_> = results
+        .iter()
+        .map(|r| r[constants::F_ID].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, vec!["v3", "v5", "v7"]);
+}
+
+#[test]
+fn test_snapshot_and_restore() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("db.json");
+    let snapshot_path = temp_dir.path().join("snapshot.json");
+    let snapshot_path = snapshot_path.to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path.to_str().unwrap()).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: [("color".to_string(), "red".into())].into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![0.0, 1.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let before = db.query(&[1.0, 0.5], 2, None, None);
+
+    let snapshot = db.snapshot(snapshot_path).unwrap();
+    assert_eq!(snapshot.len, 2);
+
+    // A bad bulk load
+    db.delete(&["a".to_string()]);
+    db.upsert(vec![Data {
+        id: "c".to_string(),
+        vector: vec![1.0, 1.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+
+    db.restore(snapshot_path).unwrap();
+    assert_eq!(db.len(), 2);
+    assert!(db.get(&["c".to_string()]).is_empty());
+    assert_eq!(db.get(&["a".to_string()])[0].fields["color"], "red");
+    assert_eq!(db.query(&[1.0, 0.5], 2, None, None), before);
+
+    let mut other = NanoVectorDB::new(3, path.to_str().unwrap()).unwrap();
+    assert!(other.restore(snapshot_path).is_err());
+}
+
+#[test]
+fn test_scalar_quantization_recall() {
+    use rand::SeedableRng;
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let dim = 64;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    let vectors = random_vectors(&mut rng, dim, 1000);
+    let exact = exact_db(path, &vectors);
+    let mut quantized =
+        NanoVectorDB::with_scalar_quantization(dim, path, QuantParams::default()).unwrap();
+    quantized.upsert(numbered_records(0, &vectors)).unwrap();
+
+    let error = quantized.quantization_error().unwrap();
+    assert!(error > 0.0 && error < 0.01, "error {error}");
+    assert!(exact.quantization_error().is_none());
+
+    // Documented threshold: recall@10 of at least 0.9 against the f32 scan
+    let mut hits = 0;
+    let num_queries = 20;
+    for query in random_vectors(&mut rng, dim, num_queries) {
+        let ids = |db: &NanoVectorDB| {
+            db.query(&query, 10, None, None)
+                .iter()
+                .map(|r| r[constants::F_ID].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        let expected = ids(&exact);
+        hits += ids(&quantized)
+            .iter()
+            .filter(|id| expected.contains(id))
+            .count();
+    }
+    let recall = hits as f32 / (num_queries * 10) as f32;
+    assert!(recall >= 0.9, "recall {recall}");
+}
+
+#[test]
+fn test_merge_collision_policies() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let shard = |name: &str, records: &[(&str, [f32; 2], &str)]| {
+        let path = temp_dir.path().join(name);
+        let mut db = NanoVectorDB::new(2, path.to_str().unwrap()).unwrap();
+        db.upsert(
+            records
+                .iter()
+                .map(|(id, vector, tag)| Data {
+                    id: id.to_string(),
+                    vector: vector.to_vec(),
+                    fields: [("tag".to_string(), (*tag).into())].into(),
+                    weight: None,
+                })
+                .collect(),
+        )
+        .unwrap();
+        db
+    };
+    let left = || {
+        shard(
+            "left.json",
+            &[("a", [1.0, 0.0], "left"), ("b", [0.0, 1.0], "left")],
+        )
+    };
+    let right = || {
+        let mut db = shard(
+            "right.json",
+            &[("b", [1.0, 1.0], "right"), ("c", [1.0, 2.0], "right")],
+        );
+        let titles = vec![
+            ("b".to_string(), vec![0.0, 2.0]),
+            ("c".to_string(), vec![3.0, 0.0]),
+        ];
+        db.upsert_named_vectors("title", titles).unwrap();
+        db
+    };
+
+    let mut db = left();
+    let report = db.merge(right(), CollisionPolicy::Skip).unwrap();
+    assert_eq!(report.inserted, vec!["c".to_string()]);
+    assert_eq!(report.skipped, vec!["b".to_string()]);
+    assert!(report.updated.is_empty());
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.get(&["b".to_string()])[0].fields["tag"], "left");
+    // Named vectors come along with their records only
+    assert_eq!(db.named_vector("c", "title"), Some([1.0, 0.0].as_slice()));
+    assert_eq!(db.named_vector("b", "title"), None);
+
+    let mut db = left();
+    let report = db.merge(right(), CollisionPolicy::Overwrite).unwrap();
+    assert_eq!(report.updated, vec!["b".to_string()]);
+    assert_eq!(db.len(), 3);
+    let results = db.query(&[1.0, 1.0], 1, None, None);
+    assert_eq!(results[0][constants::F_ID], "b");
+    let b = db.get(&["b".to_string()]);
+    assert_eq!(b[0].fields["tag"], "right");
+    let norm = std::f32::consts::FRAC_1_SQRT_2;
+    assert!(b[0].vector.iter().all(|x| (x - norm).abs() < 1e-6));
+    assert_eq!(db.named_vector("b", "title"), Some([0.0, 1.0].as_slice()));
+
+    let mut db = left();
+    assert!(db.merge(right(), CollisionPolicy::Error).is_err());
+    assert_eq!(db.len(), 2);
+
+    // A named field of another dimension is rejected before anything is merged
+    db.upsert_named_vectors("title", vec![("a".to_string(), vec![1.0, 0.0, 0.0])])
+        .unwrap();
+    assert!(matches!(
+        db.merge(right(), CollisionPolicy::Skip),
+        Err(NanoVectorDbError::DimensionMismatch {
+            expected: 3,
+            got: 2
+        })
+    ));
+    assert_eq!(db.len(), 2);
+
+    let other_dim = NanoVectorDB::new(3, temp_dir.path().join("x.json").to_str().unwrap()).unwrap();
+    assert!(db.merge(other_dim, CollisionPolicy::Skip).is_err());
+}
+
+#[test]
+fn test_load_legacy_nested_matrix() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let legacy = serde_json::json!({
+        "embedding_dim": 2,
+        "data": [
+            {"__id__": "a", "color": "red"},
+            {"__id__": "b", "color": "blue"}
+        ],
+        "matrix": [[1.0, 0.0], [0.0, 1.0]]
+    });
+    std::fs::write(temp_file.path(), legacy.to_string()).unwrap();
+
+    let path = temp_file.path().to_str().unwrap();
+    let db = NanoVectorDB::new(2, path).unwrap();
+    assert_eq!(db.len(), 2);
+    let results = db.query(&[0.1, 0.9], 1, None, None);
+    assert_eq!(results[0][constants::F_ID], "b");
+    assert_eq!(results[0]["color"], "blue");
+
+    db.save().unwrap();
+    let saved: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    assert!(saved["matrix"].is_string());
+    assert_eq!(NanoVectorDB::new(2, path).unwrap().len(), 2);
+}
+
+#[test]
+fn test_query_typed_score_is_exact_f32() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(5, temp_file.path().to_str().unwrap()).unwrap();
+    let vector = vec![0.3, -1.7, 2.9, 0.11, 5.3];
+    db.upsert(vec![Data {
+        id: "a".into(),
+        vector: vector.clone(),
+        fields: [("color".to_string(), "red".into())].into(),
+        weight: None,
+    }])
+    .unwrap();
+
+    let query = vec![1.3, 0.2, -0.7, 4.1, 0.9];
+    let results: Vec<QueryResult> = db.query_typed(&query, 1, None, None);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, "a");
+    assert_eq!(results[0].fields["color"], "red");
+
+    let stored = normalize(&vector);
+    let query_norm = normalize(&query);
+    let chunks: Vec<[f32; 4]> = query_norm
+        .chunks_exact(4)
+        .map(|c| [c[0], c[1], c[2], c[3]])
+        .collect();
+    let expected = dot_product(&stored, &chunks, &query_norm[4..]);
+    assert_eq!(results[0].score, expected);
+
+    assert!(db.query_typed(&query, 1, Some(expected), None).len() == 1);
+    assert!(db
+        .query_typed(&query, 1, Some(expected + 1e-3), None)
+        .is_empty());
+}
+
+#[test]
+fn test_save_creates_missing_directories() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("a/b/c/db.json");
+    let path = path.to_str().unwrap();
+
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    db.upsert(vec![Data {
+        id: "a".into(),
+        vector: vec![1.0, 0.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    db.save().unwrap();
+    assert_eq!(NanoVectorDB::new(2, path).unwrap().len(), 1);
+
+    let missing = temp_dir.path().join("x/y/db.json");
+    let db = NanoVectorDB::new(2, missing.to_str().unwrap())
+        .unwrap()
+        .with_create_dirs(false);
+    let err = db.save().unwrap_err();
+    assert!(err.to_string().contains("does not exist"));
+}
+
+#[test]
+fn test_capacity_and_reserve() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.reserve(100);
+    assert!(db.capacity() >= 100);
+    assert_eq!(db.len(), 0);
+
+    db.upsert(vec![Data {
+        id: "a".into(),
+        vector: vec![1.0, 2.0, 3.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    assert!(db.capacity() >= 100);
+    assert_eq!(db.len(), 1);
+
+    db.shrink_to_fit();
+    assert!(db.capacity() >= db.len());
+
+    let empty_dim = NanoVectorDB::new(0, temp_file.path().to_str().unwrap()).unwrap();
+    assert_eq!(empty_dim.capacity(), 0);
+}
+
+#[test]
+fn test_query_min_backfills_below_threshold() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        [
+            ("a", [1.0, 0.0]),
+            ("b", [1.0, 0.5]),
+            ("c", [1.0, 1.5]),
+            ("d", [0.0, 1.0]),
+        ]
+        .iter()
+        .map(|(id, vector)| Data {
+            id: id.to_string(),
+            vector: vector.to_vec(),
+            fields: HashMap::new(),
+            weight: None,
+        })
+        .collect(),
+    )
+    .unwrap();
+
+    assert_eq!(db.query(&[1.0, 0.0], 10, Some(0.99), None).len(), 1);
+
+    let results = db.query_min(&[1.0, 0.0], 10, Some(0.99), 3, None);
+    let ids: Vec<&str> = results
+        .iter()
+        .map(|(r, _)| r[constants::F_ID].as_str().unwrap())
+        .collect();
+    let passed: Vec<bool> = results.iter().map(|(_, passed)| *passed).collect();
+    assert_eq!(ids, vec!["a", "b", "c"]);
+    assert_eq!(passed, vec![true, false, false]);
+
+    // Enough results pass the threshold, so nothing is backfilled
+    let results = db.query_min(&[1.0, 0.0], 10, Some(0.5), 2, None);
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|(_, passed)| *passed));
+}
+
+#[test]
+fn test_query_ref_matches_cloning_query() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..20)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32, (i % 3) as f32],
+                fields: [
+                    ("text".to_string(), "x".repeat(100 + i).into()),
+                    ("n".to_string(), i.into()),
+                ]
+                .into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let query = [1.0, 4.0, 2.0];
+    let cloned = db.query(&query, 5, None, None);
+    let borrowed: Vec<QueryHit> = db.query_ref(&query, 5, None, None);
+    assert_eq!(cloned.len(), borrowed.len());
+    for (map, hit) in cloned.iter().zip(&borrowed) {
+        assert_eq!(map[constants::F_ID], hit.id());
+        assert_eq!(
+            map[constants::F_METRICS].as_f64().unwrap() as f32,
+            hit.score
+        );
+        assert_eq!(map.get("text"), hit.field("text"));
+        assert_eq!(map.get("n"), hit.field("n"));
+        assert_eq!(map.len(), hit.data.fields.len() + 2);
+    }
+}
+
+#[test]
+fn test_repair_matrix_size_mismatch() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    // Three records but only two complete rows (plus a stray value)
+    let short = serde_json::json!({
+        "embedding_dim": 2,
+        "data": [{"__id__": "a"}, {"__id__": "b"}, {"__id__": "c"}],
+        "matrix": [[1.0, 0.0], [0.0, 1.0], [0.5]]
+    });
+    std::fs::write(path, short.to_string()).unwrap();
+    assert!(NanoVectorDB::new(2, path).is_err());
+
+    let (db, report) = NanoVectorDB::new_repaired(2, path).unwrap();
+    assert_eq!(
+        report,
+        RepairReport {
+            truncated_values: 1,
+            dropped_ids: vec!["c".to_string()],
+            ..Default::default()
+        }
+    );
+    assert_eq!(db.len(), 2);
+    assert_eq!(
+        db.query(&[0.0, 1.0], 1, None, None)[0][constants::F_ID],
+        "b"
+    );
+    db.verify().unwrap();
+
+    // One record but extra rows
+    let long = serde_json::json!({
+        "embedding_dim": 2,
+        "data": [{"__id__": "a"}],
+        "matrix": [[1.0, 0.0], [0.0, 1.0]]
+    });
+    std::fs::write(path, long.to_string()).unwrap();
+    let (db, report) = NanoVectorDB::new_repaired(2, path).unwrap();
+    assert_eq!(report.truncated_values, 2);
+    assert!(report.dropped_ids.is_empty());
+    assert_eq!(db.len(), 1);
+
+    db.save().unwrap();
+    let (_, report) = NanoVectorDB::new_repaired(2, path).unwrap();
+    assert!(report.is_clean());
+}
+
+#[test]
+fn test_pruned_scoring_matches_exact() {
+    use rand::SeedableRng;
+
+    let dim = 300;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let db = exact_db(path, &random_vectors(&mut rng, dim, 500));
+    db.save().unwrap();
+    let pruned = NanoVectorDB::new(dim, path)
+        .unwrap()
+        .with_pruned_scoring(true);
+
+    for query in random_vectors(&mut rng, dim, 10) {
+        for (top_k, better_than) in [(1, None), (10, None), (10, Some(0.05))] {
+            let exact = db.query(&query, top_k, better_than, None);
+            let fast = pruned.query(&query, top_k, better_than, None);
+            assert_eq!(exact.len(), fast.len());
+            for (e, f) in exact.iter().zip(&fast) {
+                assert_eq!(e[constants::F_ID], f[constants::F_ID]);
+                let (e, f) = (
+                    e[constants::F_METRICS].as_f64().unwrap(),
+                    f[constants::F_METRICS].as_f64().unwrap(),
+                );
+                assert!((e - f).abs() < 1e-5);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_pruned_scoring_skips_non_unit_rows() {
+    let dim = 128;
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(dim, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_pruned_scoring(true);
+    let axis = |i: usize, value: f32| {
+        let mut vector = vec![0.0; dim];
+        vector[i] = value;
+        vector
+    };
+    db.upsert(vec![Data {
+        id: "near".to_string(),
+        vector: axis(dim - 1, 1.0),
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    // Its energy in the first block would wrongly bound it below "near"
+    let mut big = axis(0, 3.0);
+    big[dim - 1] = 3.0;
+    db.upsert_assume_normalized(vec![Data {
+        id: "big".to_string(),
+        vector: big,
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+
+    let query = axis(dim - 1, 1.0);
+    let results = db.query(&query, 1, None, None);
+    assert_eq!(results[0][constants::F_ID], "big");
+
+    db.renormalize_all().unwrap();
+    let results = db.query(&query, 1, None, None);
+    assert_eq!(results[0][constants::F_ID], "near");
+}
+
+#[test]
+fn test_embedding_dim_and_metric_getters() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let db = NanoVectorDB::new(7, path).unwrap();
+    assert_eq!(db.embedding_dim(), 7);
+    assert_eq!(db.metric(), Metric::Cosine);
+
+    let db = db.with_metric(Metric::L2);
+    assert_eq!(db.metric(), Metric::L2);
+}
+
+#[test]
+fn test_get_vectors_flat_buffer() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..5)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32, 2.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let wanted = ["vec_3", "missing", "vec_0", "vec_4"].map(String::from);
+    let (matrix, ids) = db.get_vectors(&wanted);
+    assert_eq!(ids, vec!["vec_3", "vec_0", "vec_4"]);
+    assert_eq!(matrix.len(), ids.len() * db.embedding_dim());
+    for (row, i) in matrix.chunks(db.embedding_dim()).zip([3.0, 0.0, 4.0]) {
+        assert_eq!(row, normalize(&[1.0, i, 2.0]).as_slice());
+    }
+}
+
+#[test]
+fn test_configurable_norm_epsilon() {
+    let epsilon = 1e-6;
+    let above = [0.6e-6, 0.8e-6 + 1e-8];
+    let below = [0.6e-6, 0.8e-6 - 1e-8];
+
+    let unit = normalize_with_epsilon(&above, epsilon).unwrap();
+    assert!((unit[0].hypot(unit[1]) - 1.0).abs() < 1e-5);
+    assert!(normalize_with_epsilon(&below, epsilon).is_err());
+
+    // The default bound rejects tiny but legitimate embeddings
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let record = |id: &str, vector: [f32; 2]| Data {
+        id: id.into(),
+        vector: vector.to_vec(),
+        fields: HashMap::new(),
+        weight: None,
+    };
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    assert!(db.upsert(vec![record("a", above)]).is_err());
+    assert!(db.is_empty());
+
+    let mut db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_norm_epsilon(epsilon);
+    db.upsert(vec![record("a", above)]).unwrap();
+    let err = db
+        .upsert(vec![record("b", [1.0, 0.0]), record("c", below)])
+        .unwrap_err();
+    assert!(err.to_string().contains("\"c\""));
+    assert_eq!(db.len(), 1);
+}
+
+#[test]
+fn test_replace_all() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let records = |prefix: &str, n: usize| -> Vec<Data> {
+        (0..n)
+            .map(|i| Data {
+                id: format!("{prefix}_{i}"),
+                vector: vec![1.0, i as f32],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect()
+    };
+    db.upsert(records("old", 5)).unwrap();
+    db.store_additional_data([("model".to_string(), "v1".into())].into());
+
+    db.replace_all(records("new", 3)).unwrap();
+    assert_eq!(db.len(), 3);
+    assert!(db.get(&["old_0".to_string()]).is_empty());
+    assert_eq!(db.get(&["new_2".to_string()]).len(), 1);
+    assert_eq!(db.get_additional_data()["model"], "v1");
+    db.verify().unwrap();
+
+    // A failed replacement keeps the previous records
+    let mut bad = records("bad", 2);
+    bad[1].vector = vec![0.0, 0.0];
+    assert!(db.replace_all(bad).is_err());
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.get(&["new_0".to_string()]).len(), 1);
+}
+
+#[test]
+fn test_write_hooks() {
+    use std::sync::{Arc, Mutex};
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let upserted = Arc::new(Mutex::new(Vec::new()));
+    let deleted = Arc::new(Mutex::new(Vec::new()));
+    let sink = upserted.clone();
+    db.on_upsert(Box::new(move |ids| sink.lock().unwrap().push(ids.to_vec())));
+    let sink = deleted.clone();
+    db.on_delete(Box::new(move |ids| sink.lock().unwrap().push(ids.to_vec())));
+
+    let record = |id: &str| Data {
+        id: id.into(),
+        vector: vec![1.0, 2.0],
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![record("a"), record("b")]).unwrap();
+    db.upsert(vec![record("a"), record("c")]).unwrap();
+    db.delete(&["b".to_string(), "missing".to_string()]);
+    db.delete(&["missing".to_string()]);
+
+    assert_eq!(
+        *upserted.lock().unwrap(),
+        vec![vec!["a", "b"], vec!["a", "c"]]
+    );
+    assert_eq!(*deleted.lock().unwrap(), vec![vec!["b"]]);
+}
+
+#[test]
+fn test_to_bytes_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..10)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32, -(i as f32)],
+                fields: [("n".to_string(), i.into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    db.store_additional_data([("model".to_string(), "v1".into())].into());
+
+    let bytes = db.to_bytes().unwrap();
+    let restored = NanoVectorDB::from_bytes(3, &bytes).unwrap();
+    assert_eq!(restored.len(), db.len());
+    assert_eq!(restored.get_additional_data(), db.get_additional_data());
+    restored.verify().unwrap();
+
+    let query = [0.5, 2.0, -1.0];
+    assert_eq!(
+        restored.query(&query, 5, None, None),
+        db.query(&query, 5, None, None)
+    );
+    assert!(restored.save().is_err());
+    assert!(NanoVectorDB::from_bytes(4, &bytes).is_err());
+    assert!(NanoVectorDB::from_bytes(3, &bytes[..bytes.len() - 1]).is_err());
+
+    // A header value that does not fit in a u32 is refused, not truncated
+    let wide_file = NamedTempFile::new().unwrap();
+    let wide = NanoVectorDB::new(1 << 32, wide_file.path().to_str().unwrap()).unwrap();
+    assert!(matches!(
+        wide.to_bytes(),
+        Err(NanoVectorDbError::InvalidInput(_))
+    ));
+}
+
+#[test]
+fn test_f64_accumulation_fixes_cancellation() {
+    // In f32, 1e8 + 1 rounds back to 1e8, so "a" cancels to 0 and loses to "b"
+    let a = vec![1e8, 1.0, -1e8, 0.0];
+    let b = vec![0.0, 0.5, 0.0, 0.0];
+    let query = [1.0, 1.0, 1.0, 1.0];
+    assert_eq!(dot_product_f64_acc(&a, &query), 1.0);
+    let chunks = [[1.0, 1.0, 1.0, 1.0]];
+    assert_eq!(dot_product(&a, &chunks, &[]), 0.0);
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let records = || {
+        vec![
+            Data {
+                id: "a".into(),
+                vector: a.clone(),
+                fields: HashMap::new(),
+                weight: None,
+            },
+            Data {
+                id: "b".into(),
+                vector: b.clone(),
+                fields: HashMap::new(),
+                weight: None,
+            },
+        ]
+    };
+
+    let mut db = NanoVectorDB::new(4, path).unwrap().with_metric(Metric::Dot);
+    db.upsert(records()).unwrap();
+    assert_eq!(db.query(&query, 1, None, None)[0][constants::F_ID], "b");
+
+    let mut db = NanoVectorDB::new(4, path)
+        .unwrap()
+        .with_metric(Metric::Dot)
+        .with_f64_accumulation(true);
+    db.upsert(records()).unwrap();
+    let results = db.query_typed(&query, 2, None, None);
+    assert_eq!(results[0].id, "a");
+    assert_eq!(results[0].score, 1.0);
+}
+
+#[test]
+fn test_record_weights() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    let record = |id: &str, vector: [f32; 2]| Data {
+        id: id.into(),
+        vector: vector.to_vec(),
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("plain", [1.0, 0.0]),
+        record("trusted", [1.0, 0.0]),
+        record("other", [0.0, 1.0]),
+    ])
+    .unwrap();
+
+    let ids = |db: &NanoVectorDB| -> Vec<String> {
+        db.query_typed(&[1.0, 0.0], 2, None, None)
+            .into_iter()
+            .map(|r| r.id)
+            .collect()
+    };
+    assert_eq!(db.weight("trusted"), Some(1.0));
+    assert_eq!(ids(&db), vec!["plain", "trusted"]);
+
+    db.set_weight("trusted", 2.0).unwrap();
+    assert_eq!(ids(&db), vec!["trusted", "plain"]);
+    assert_eq!(db.query_typed(&[1.0, 0.0], 1, None, None)[0].score, 2.0);
+    assert!(db.set_weight("trusted", 0.0).is_err());
+    assert!(db.set_weight("missing", 2.0).is_err());
+
+    // Weights survive deletes, new inserts and a save/load cycle
+    db.delete(&["plain".to_string()]);
+    db.upsert(vec![record("late", [1.0, 0.0])]).unwrap();
+    db.save().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    assert_eq!(db.weight("trusted"), Some(2.0));
+    assert_eq!(db.weight("late"), Some(1.0));
+    assert_eq!(ids(&db), vec!["trusted", "late"]);
+
+    // Weights given on upsert are stored with the record, replacing any set before
+    let weighted = |id: &str, weight: f32| Data {
+        weight: Some(weight),
+        ..record(id, [1.0, 0.0])
+    };
+    db.upsert(vec![weighted("heavy", 3.0), record("trusted", [1.0, 0.0])])
+        .unwrap();
+    assert_eq!(db.weight("heavy"), Some(3.0));
+    assert_eq!(db.weight("trusted"), Some(1.0));
+    assert_eq!(ids(&db), vec!["heavy", "trusted"]);
+    assert!(db.upsert(vec![weighted("bad", -1.0)]).is_err());
+    assert_eq!(db.weight("bad"), None);
+}
+
+#[test]
+fn test_recover_truncated_file() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("db.json");
+    let path = path.to_str().unwrap();
+    let record = |id: &str| Data {
+        id: id.into(),
+        vector: vec![1.0, 2.0],
+        fields: HashMap::new(),
+        weight: None,
+    };
+
+    let mut db = NanoVectorDB::new(2, path).unwrap().with_backup(true);
+    db.upsert(vec![record("a")]).unwrap();
+    db.save().unwrap();
+    db.upsert(vec![record("b")]).unwrap();
+    db.save().unwrap();
+
+    // Simulate a crash mid-write of the storage file
+    let contents = std::fs::read_to_string(path).unwrap();
+    std::fs::write(path, &contents[..contents.len() / 2]).unwrap();
+    assert!(NanoVectorDB::new(2, path).is_err());
+
+    let (db, report) = NanoVectorDB::new_repaired(2, path).unwrap();
+    assert!(report.used_backup);
+    assert!(report.unreadable.is_some());
+    assert!(!report.is_clean());
+    assert_eq!(db.len(), 1);
+    assert_eq!(
+        db.query(&[1.0, 2.0], 1, None, None)[0][constants::F_ID],
+        "a"
+    );
+
+    // Without a backup, recovery falls back to an empty database
+    std::fs::remove_file(format!("{path}.bak")).unwrap();
+    let (mut db, report) = NanoVectorDB::new_repaired(2, path).unwrap();
+    assert!(!report.used_backup);
+    assert!(report.unreadable.is_some());
+    assert!(db.is_empty());
+    db.upsert(vec![record("c")]).unwrap();
+    db.save().unwrap();
+    assert_eq!(NanoVectorDB::new(2, path).unwrap().len(), 1);
+}
+
+#[test]
+fn test_similarity_honors_metric() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let a = [3.0, -4.0, 12.0];
+    let b = [1.0, 0.0, 0.0];
+
+    let db = NanoVectorDB::new(3, path).unwrap();
+    assert!((db.similarity(&a, &a).unwrap() - 1.0).abs() < 1e-6);
+    assert!((db.similarity(&a, &b).unwrap() - 3.0 / 13.0).abs() < 1e-6);
+    assert!(db.similarity(&a, &[0.0; 3]).is_err());
+    assert!(db.similarity(&a, &[1.0, 2.0]).is_err());
+
+    let db = NanoVectorDB::new(3, path).unwrap().with_metric(Metric::Dot);
+    assert_eq!(db.similarity(&a, &a).unwrap(), 169.0);
+
+    let db = NanoVectorDB::new(3, path).unwrap().with_metric(Metric::L2);
+    assert_eq!(db.similarity(&a, &a).unwrap(), 0.0);
+    assert!((db.similarity(&a, &b).unwrap() - 164f32.sqrt()).abs() < 1e-5);
+}
+
+#[test]
+fn test_filter_first_matches_interleaved_filter() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(4, path).unwrap();
+    db.upsert(
+        (0..200)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, (i % 7) as f32, (i % 11) as f32, i as f32 / 50.0],
+                fields: [("category".to_string(), (i % 3).into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    db.save().unwrap();
+    let filter_first = NanoVectorDB::new(4, path).unwrap().with_filter_first(true);
+
+    let in_category = |category: i64| -> Box<dyn Fn(&Data) -> bool + Send + Sync> {
+        Box::new(move |d: &Data| d.fields["category"] == category)
+    };
+    let query = [0.5, 3.0, 2.0, 1.0];
+    for category in 0..3 {
+        let expected = db.query(&query, 10, None, Some(in_category(category)));
+        let actual = filter_first.query(&query, 10, None, Some(in_category(category)));
+        assert_eq!(expected.len(), 10);
+        assert_eq!(expected, actual);
+    }
+    assert_eq!(
+        db.query(&query, 10, Some(0.9), None),
+        filter_first.query(&query, 10, Some(0.9), None)
+    );
+}
+
+#[test]
+fn test_pretty_save_reloads_identically() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let compact_path = temp_dir.path().join("compact.json");
+    let pretty_path = temp_dir.path().join("pretty.json");
+    let records = || -> Vec<Data> {
+        (0..5)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32, 0.5],
+                fields: [("name".to_string(), format!("item {i}").into())].into(),
+                weight: None,
+            })
+            .collect()
+    };
+
+    let mut compact = NanoVectorDB::new(3, compact_path.to_str().unwrap()).unwrap();
+    compact.upsert(records()).unwrap();
+    compact.save().unwrap();
+    let mut pretty = NanoVectorDB::new(3, pretty_path.to_str().unwrap())
+        .unwrap()
+        .with_pretty(true);
+    pretty.upsert(records()).unwrap();
+    pretty.save().unwrap();
+
+    let pretty_text = std::fs::read_to_string(&pretty_path).unwrap();
+    assert!(pretty_text.lines().count() > 10);
+    let parsed: serde_json::Value = serde_json::from_str(&pretty_text).unwrap();
+    assert!(parsed["matrix"].is_string());
+
+    let compact = NanoVectorDB::new(3, compact_path.to_str().unwrap()).unwrap();
+    let pretty = NanoVectorDB::new(3, pretty_path.to_str().unwrap()).unwrap();
+    let query = [1.0, 2.0, 0.5];
+    assert_eq!(
+        compact.query(&query, 5, None, None),
+        pretty.query(&query, 5, None, None)
+    );
+    assert_eq!(compact.to_bytes().unwrap(), pretty.to_bytes().unwrap());
+}
+
+#[test]
+fn test_query_auto_k_cuts_at_largest_gap() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let mut records: Vec<Data> = (0..3)
+        .map(|i| Data {
+            id: format!("close_{i}"),
+            vector: vec![1.0, 0.05 * i as f32],
+            fields: HashMap::new(),
+            weight: None,
+        })
+        .collect();
+    records.extend((0..10).map(|i| Data {
+        id: format!("far_{i}"),
+        vector: vec![0.2 - 0.05 * i as f32, 1.0],
+        fields: HashMap::new(),
+        weight: None,
+    }));
+    db.upsert(records).unwrap();
+
+    let results = db.query_auto_k(&[1.0, 0.0], 10, None, None);
+    assert_eq!(results.len(), 3);
+    assert!(results
+        .iter()
+        .all(|r| r[constants::F_ID].as_str().unwrap().starts_with("close_")));
+    assert_eq!(db.query_auto_k(&[1.0, 0.0], 1, None, None).len(), 1);
+}
+
+#[test]
+fn test_field_truncated_respects_char_boundaries() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(vec![Data {
+        id: "a".into(),
+        vector: vec![1.0, 0.0],
+        fields: [
+            ("text".to_string(), "héllo wörld 🚀🚀".into()),
+            ("n".to_string(), 3.into()),
+        ]
+        .into(),
+        weight: None,
+    }])
+    .unwrap();
+
+    let typed = &db.query_typed(&[1.0, 0.0], 1, None, None)[0];
+    assert_eq!(typed.field_truncated("text", 2).unwrap(), "hé");
+    assert_eq!(typed.field_truncated("text", 13).unwrap(), "héllo wörld 🚀");
+    assert_eq!(
+        typed.field_truncated("text", 100).unwrap(),
+        "héllo wörld 🚀🚀"
+    );
+    assert_eq!(typed.field_truncated("n", 5), None);
+    assert_eq!(typed.field_truncated("missing", 5), None);
+
+    let hit = &db.query_ref(&[1.0, 0.0], 1, None, None)[0];
+    assert_eq!(hit.field_truncated("text", 2).unwrap(), "hé");
+}
+
+#[test]
+fn test_original_norm_persists() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(3, path).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".into(),
+            vector: vec![2.0, 3.0, 6.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "b".into(),
+            vector: vec![0.0, 0.0, 0.5],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    assert_eq!(db.original_norm("a"), Some(7.0));
+    db.delete(&["b".to_string()]);
+    db.upsert(vec![Data {
+        id: "c".into(),
+        vector: vec![0.0, 4.0, 3.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    db.save().unwrap();
+
+    let db = NanoVectorDB::new(3, path).unwrap();
+    assert_eq!(db.original_norm("a"), Some(7.0));
+    assert_eq!(db.original_norm("b"), None);
+    assert_eq!(db.original_norm("c"), Some(5.0));
+    // Stored vectors are still unit length
+    assert!((db.similarity(&[2.0, 3.0, 6.0], &[2.0, 3.0, 6.0]).unwrap() - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_sharded_query_matches_single_db() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let open =
+        |name: &str| NanoVectorDB::new(3, temp_dir.path().join(name).to_str().unwrap()).unwrap();
+    let records: Vec<(String, Vec<f32>)> = (0..40)
+        .map(|i| {
+            let x = i as f32;
+            (
+                format!("vec_{i}"),
+                vec![1.0 + (x * 0.37).sin(), (x * 0.11).cos(), x / 40.0],
+            )
+        })
+        .collect();
+    let to_data = |(id, vector): &(String, Vec<f32>)| Data {
+        id: id.clone(),
+        vector: vector.clone(),
+        fields: [("id_copy".to_string(), id.clone().into())].into(),
+        weight: None,
+    };
+
+    let mut single = open("single.json");
+    single
+        .upsert(records.iter().map(to_data).collect())
+        .unwrap();
+    let mut left = open("left.json");
+    left.upsert(records[..25].iter().map(to_data).collect())
+        .unwrap();
+    let mut right = open("right.json");
+    right
+        .upsert(records[25..].iter().map(to_data).collect())
+        .unwrap();
+    let sharded = ShardedNanoVectorDB::new(vec![left, right]).unwrap();
+    assert_eq!(sharded.len(), 40);
+
+    let query = [0.4, 0.9, 0.2];
+    assert_eq!(
+        sharded.query(&query, 7, None, None),
+        single.query(&query, 7, None, None)
+    );
+    assert_eq!(
+        sharded.query(&query, 40, Some(0.8), None),
+        single.query(&query, 40, Some(0.8), None)
+    );
+
+    let mismatched = vec![open("a.json"), open("b.json").with_metric(Metric::L2)];
+    assert!(ShardedNanoVectorDB::new(mismatched).is_err());
+}
+
+#[test]
+fn test_validate_dim_reports_first_mismatch() {
+    let mut vectors = vec![vec![0.5; 4]; 6];
+    assert!(validate_dim(&vectors, 4).is_ok());
+    assert!(validate_dim(&[], 4).is_ok());
+
+    vectors[3] = vec![0.5; 3];
+    vectors[5] = vec![0.5; 7];
+    let err = validate_dim(&vectors, 4).unwrap_err().to_string();
+    assert!(err.contains("index 3"), "{err}");
+    assert!(err.contains("got 3"), "{err}");
+}
+
+#[test]
+fn test_query_heap_reuse_matches_brute_force() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(4, temp_file.path().to_str().unwrap()).unwrap();
+    let vectors: Vec<Vec<f32>> = (0..500)
+        .map(|i| {
+            let x = i as f32;
+            vec![
+                (x * 0.13).sin() + 1.5,
+                (x * 0.71).cos(),
+                (x * 0.29).sin(),
+                0.1,
+            ]
+        })
+        .collect();
+    db.upsert(
+        vectors
+            .iter()
+            .enumerate()
+            .map(|(i, vector)| Data {
+                id: format!("vec_{i}"),
+                vector: vector.clone(),
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let query = [0.3, -0.8, 0.5, 0.2];
+    let mut expected: Vec<(f32, String)> = vectors
+        .iter()
+        .enumerate()
+        .map(|(i, vector)| (db.similarity(&query, vector).unwrap(), format!("vec_{i}")))
+        .collect();
+    expected.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    for top_k in [0, 1, 10, 499, 500, 2000] {
+        let ids: Vec<String> = db
+            .query_typed(&query, top_k, None, None)
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        let want: Vec<String> = expected
+            .iter()
+            .take(top_k)
+            .map(|(_, id)| id.clone())
+            .collect();
+        assert_eq!(ids, want, "top_k = {top_k}");
+    }
+}
+
+#[test]
+fn test_query_diagnostics_are_self_consistent() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..20)
+            .map(|i| {
+                let x = i as f32;
+                Data {
+                    id: format!("vec_{i}"),
+                    vector: vec![1.0 + x, (x * 0.5).sin(), 2.0 - x * 0.1],
+                    fields: HashMap::new(),
+                    weight: None,
+                }
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let query = normalize(&[0.2, 0.9, -0.4]);
+    let hits = db.query_diagnostics(&query, 5, None, None);
+    assert_eq!(hits.len(), 5);
+    for (result, ScoreDiagnostics { cosine, l2, dot }) in hits {
+        // Both the query and the stored vectors are unit length
+        assert!((cosine - result.score).abs() < 1e-5);
+        assert!((dot - cosine).abs() < 1e-5);
+        assert!((l2 * l2 - (2.0 - 2.0 * cosine)).abs() < 1e-4);
+    }
+}
+
+#[test]
+fn test_query_cancellable_returns_early() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(16, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..50_000)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: (0..16)
+                    .map(|j| ((i * 16 + j) as f32 * 0.01).sin() + 1.1)
+                    .collect(),
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let query = vec![0.5; 16];
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let (results, cancelled) = db.query_cancellable(&query, 5, None, None, cancel.clone());
+    assert!(!cancelled);
+    assert_eq!(results, db.query_typed(&query, 5, None, None));
+
+    // A flag set mid-scan stops the remaining rows from being visited
+    let visited = Arc::new(AtomicUsize::new(0));
+    let filter = {
+        let (visited, cancel) = (visited.clone(), cancel.clone());
+        Box::new(move |_: &Data| {
+            if visited.fetch_add(1, Ordering::Relaxed) == 100 {
+                cancel.store(true, Ordering::Relaxed);
+            }
+            true
+        })
+    };
+    let (_, cancelled) = db.query_cancellable(&query, 5, None, Some(filter), cancel.clone());
+    assert!(cancelled);
+    assert!(visited.load(Ordering::Relaxed) < 10_000);
+
+    // A flag set beforehand visits no rows at all
+    visited.store(0, Ordering::Relaxed);
+    let filter = {
+        let visited = visited.clone();
+        Box::new(move |_: &Data| {
+            visited.fetch_add(1, Ordering::Relaxed);
+            true
+        })
+    };
+    let (results, cancelled) = db.query_cancellable(&query, 5, None, Some(filter), cancel);
+    assert!(cancelled);
+    assert!(results.is_empty());
+    assert_eq!(visited.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_named_vector_fields() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    db.upsert(
+        ["a", "b", "c"]
+            .iter()
+            .map(|id| Data {
+                id: id.to_string(),
+                vector: vec![1.0, 0.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    // Titles and bodies live in different spaces, with opposite rankings
+    let named = |vectors: [[f32; 3]; 3]| -> Vec<(String, Vec<f32>)> {
+        ["a", "b", "c"]
+            .iter()
+            .zip(vectors)
+            .map(|(id, v)| (id.to_string(), v.to_vec()))
+            .collect()
+    };
+    db.upsert_named_vectors(
+        "title",
+        named([[1.0, 0.0, 0.0], [0.7, 0.7, 0.0], [0.0, 1.0, 0.0]]),
+    )
+    .unwrap();
+    db.upsert_named_vectors(
+        "body",
+        named([[0.0, 1.0, 0.0], [0.7, 0.7, 0.0], [1.0, 0.0, 0.0]]),
+    )
+    .unwrap();
+    assert_eq!(db.vector_fields(), ["body", "title"]);
+
+    let ranked = |db: &NanoVectorDB, field: &str| -> Vec<String> {
+        db.query_field(field, &[1.0, 0.1, 0.0], 3, None, None)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.id)
+            .collect()
+    };
+    assert_eq!(ranked(&db, "title"), ["a", "b", "c"]);
+    assert_eq!(ranked(&db, "body"), ["c", "b", "a"]);
+
+    assert!(db
+        .query_field("summary", &[1.0, 0.0, 0.0], 3, None, None)
+        .is_err());
+    assert!(db.query_field("title", &[1.0, 0.0], 3, None, None).is_err());
+    assert!(db
+        .upsert_named_vectors("title", vec![("missing".to_string(), vec![1.0, 0.0, 0.0])])
+        .is_err());
+
+    // Named vectors persist and are dropped with their record
+    db.save().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    assert_eq!(ranked(&db, "body"), ["c", "b", "a"]);
+    let b_title = db.named_vector("b", "title").unwrap().to_vec();
+    db.delete(&["a".to_string(), "c".to_string()]);
+    assert_eq!(ranked(&db, "body"), ["b"]);
+    assert!(db.named_vector("c", "body").is_none());
+    assert_eq!(db.named_vector("b", "title"), Some(b_title.as_slice()));
+}
+
+#[test]
+fn test_sample_is_distinct_and_reproducible() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..100)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let ids = |n, seed| -> Vec<String> {
+        db.sample(n, Some(seed))
+            .iter()
+            .map(|d| d.id.clone())
+            .collect()
+    };
+    for n in [0, 1, 10, 100, 250] {
+        let sample = ids(n, 7);
+        assert_eq!(sample.len(), n.min(100));
+        let distinct: std::collections::HashSet<_> = sample.iter().collect();
+        assert_eq!(distinct.len(), sample.len());
+        assert_eq!(sample, ids(n, 7));
+    }
+    assert_ne!(ids(10, 7), ids(10, 8));
+}
+
+#[test]
+fn test_errors_match_on_variant() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+
+    match db.similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]) {
+        Err(NanoVectorDbError::DimensionMismatch { expected, got }) => {
+            assert_eq!((expected, got), (3, 2));
+        }
+        other => panic!("expected DimensionMismatch, got {other:?}"),
+    }
+    assert!(matches!(
+        normalize_with_epsilon(&[0.0, 0.0], 1e-6),
+        Err(NanoVectorDbError::ZeroVector { .. })
+    ));
+
+    let mut db = db;
+    db.upsert(vec![Data {
+        id: "a".to_string(),
+        vector: vec![1.0, 0.0, 0.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    db.save().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    assert!(matches!(
+        db.set_weight("b", 2.0),
+        Err(NanoVectorDbError::NotFound(id)) if id == "b"
+    ));
+
+    std::fs::write(temp_file.path(), "{ not json").unwrap();
+    assert!(matches!(
+        NanoVectorDB::new(3, temp_file.path().to_str().unwrap()),
+        Err(NanoVectorDbError::Deserialize(_))
+    ));
+}
+
+#[test]
+fn test_delete_range_removes_middle_third() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..9)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let expected_rows: Vec<Vec<f32>> = [0, 1, 2, 6, 7, 8]
+        .iter()
+        .map(|&i| db.vector_at(i).unwrap().to_vec())
+        .collect();
+
+    assert!(db.delete_range(4..10).is_err());
+    assert_eq!(db.len(), 9);
+
+    let removed = db.delete_range(3..6).unwrap();
+    assert_eq!(removed, ["vec_3", "vec_4", "vec_5"]);
+    assert_eq!(db.len(), 6);
+    for (index, i) in [0, 1, 2, 6, 7, 8].iter().enumerate() {
+        assert_eq!(db.id_at(index), Some(format!("vec_{i}").as_str()));
+        assert_eq!(db.vector_at(index).unwrap(), expected_rows[index]);
+    }
+    assert!(db.vector_at(6).is_none());
+    assert!(db.verify().is_ok());
+}
+
+#[test]
+fn test_query_masked_ranks_in_subspace() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(4, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_metric(Metric::Dot);
+    db.upsert(vec![
+        Data {
+            id: "full".to_string(),
+            vector: vec![0.1, 0.1, 5.0, 5.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "sub".to_string(),
+            vector: vec![1.0, 1.0, 0.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "half".to_string(),
+            vector: vec![0.5, 0.5, 0.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let query = [1.0, 1.0, 1.0, 1.0];
+    let ids =
+        |results: Vec<QueryResult>| -> Vec<String> { results.into_iter().map(|r| r.id).collect() };
+
+    assert_eq!(
+        ids(db.query_typed(&query, 3, None, None)),
+        ["full", "sub", "half"]
+    );
+    let masked = db
+        .query_masked(&query, &[true, true, false, false], 3, None, None)
+        .unwrap();
+    assert_eq!(masked[0].score, 2.0);
+    assert_eq!(ids(masked), ["sub", "half", "full"]);
+    assert!(db
+        .query_masked(&query, &[true, false], 3, None, None)
+        .is_err());
+}
+
+#[test]
+fn test_query_cache_hits_and_invalidation() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_query_cache(4);
+    let record = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("a", vec![1.0, 0.0]),
+        record("b", vec![0.0, 1.0]),
+    ])
+    .unwrap();
+
+    let query = [0.9, 0.1];
+    let first = db.query(&query, 1, None, None);
+    let second = db.query(&query, 1, None, None);
+    assert_eq!(first, second);
+    let stats = db.query_cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses, stats.len), (1, 1, 1));
+
+    // Filtered queries bypass the cache
+    db.query(&query, 1, None, Some(Box::new(|_| true)));
+    assert_eq!(db.query_cache_stats().unwrap().misses, 1);
+
+    db.upsert(vec![record("c", vec![1.0, 0.1])]).unwrap();
+    assert_eq!(db.query_cache_stats().unwrap().len, 0);
+    let third = db.query(&query, 1, None, None);
+    assert_eq!(third[0][constants::F_ID], "c");
+    assert_eq!(
+        db.query_cache_stats().unwrap(),
+        CacheStats {
+            hits: 1,
+            misses: 2,
+            len: 1,
+            capacity: 4
+        }
+    );
+}
+
+#[test]
+fn test_segmented_matrix_matches_contiguous() {
+    let records = || -> Vec<Data> {
+        (0..7)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32, (i * i) as f32 - 10.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect()
+    };
+    let contiguous_file = NamedTempFile::new().unwrap();
+    let segmented_file = NamedTempFile::new().unwrap();
+    let mut contiguous = NanoVectorDB::new(3, contiguous_file.path().to_str().unwrap()).unwrap();
+    // Two 3-dimensional f32 rows per segment, so 7 records span four segments
+    let mut segmented = NanoVectorDB::new(3, segmented_file.path().to_str().unwrap())
+        .unwrap()
+        .with_segment_bytes(24);
+    contiguous.upsert(records()).unwrap();
+    segmented.upsert(records()).unwrap();
+
+    let ids =
+        |results: Vec<QueryResult>| -> Vec<String> { results.into_iter().map(|r| r.id).collect() };
+    for query in [[1.0, 6.0, 26.0], [1.0, 2.0, -6.0], [0.0, -1.0, 1.0]] {
+        assert_eq!(
+            ids(segmented.query_typed(&query, 7, None, None)),
+            ids(contiguous.query_typed(&query, 7, None, None))
+        );
+    }
+    assert_eq!(
+        segmented.query_typed(&[1.0, 6.0, 26.0], 1, None, None)[0].id,
+        "vec_6"
+    );
+
+    // Deleting across a segment boundary shifts the later rows down
+    segmented.delete(&["vec_1".to_string(), "vec_2".to_string()]);
+    assert_eq!(segmented.id_at(1), Some("vec_3"));
+    assert_eq!(
+        segmented.vector_at(4).unwrap(),
+        contiguous.vector_at(6).unwrap()
+    );
+    assert!(segmented.verify().is_ok());
+
+    segmented.save().unwrap();
+    let reloaded = NanoVectorDB::new(3, segmented_file.path().to_str().unwrap()).unwrap();
+    assert_eq!(reloaded.len(), 5);
+    assert_eq!(
+        ids(reloaded.query_typed(&[1.0, 6.0, 26.0], 5, None, None)),
+        ids(segmented.query_typed(&[1.0, 6.0, 26.0], 5, None, None))
+    );
+
+    // Splitting the loaded matrix keeps every row, and reserving fills up the
+    // partial last segment
+    let mut resegmented = reloaded.with_segment_bytes(24);
+    assert!(resegmented.verify().is_ok());
+    for index in 0..5 {
+        assert_eq!(resegmented.vector_at(index), segmented.vector_at(index));
+    }
+    resegmented.reserve(1);
+    assert!(resegmented.capacity() >= 6);
+}
+
+#[test]
+fn test_query_where_matches_with_and_without_index() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    let record = |i: usize| Data {
+        id: format!("vec_{i}"),
+        vector: vec![1.0, i as f32, (i % 4) as f32],
+        fields: [("source".to_string(), format!("s{}", i % 3).into())].into(),
+        weight: None,
+    };
+    db.upsert((0..30).map(record).collect()).unwrap();
+
+    let query = [1.0, 12.0, 2.0];
+    let source = serde_json::json!("s1");
+    let scan = |db: &NanoVectorDB| db.query_where(&query, "source", &source, 5, None);
+
+    let unindexed = scan(&db);
+    assert_eq!(unindexed.len(), 5);
+    assert!(unindexed.iter().all(|r| r["source"] == "s1"));
+    db.build_field_index("source").unwrap();
+    assert_eq!(scan(&db), unindexed);
+
+    // The index follows inserts and the index shifts caused by deletes
+    db.upsert((30..40).map(record).collect()).unwrap();
+    db.delete(&["vec_1".to_string(), "vec_13".to_string()]);
+    let indexed = scan(&db);
+    db.drop_field_index("source");
+    assert_eq!(indexed, scan(&db));
+    assert!(db
+        .query_where(&query, "source", &serde_json::json!("missing"), 5, None)
+        .is_empty());
+}
+
+#[test]
+fn test_nearest_matches_top_query_result() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    assert!(db.nearest(&[1.0, 0.0], None, None).is_none());
+    db.upsert(
+        (0..10)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32],
+                fields: [("even".to_string(), (i % 2 == 0).into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let query = [1.0, 4.2];
+    let best = db.nearest(&query, None, None).unwrap();
+    let top = &db.query(&query, 1, None, None)[0];
+    assert_eq!(best.id, "vec_4");
+    assert_eq!(top[constants::F_ID], best.id);
+    assert_eq!(top[constants::F_METRICS], best.score as f64);
+
+    let odd = db
+        .nearest(&query, None, Some(Box::new(|d| d.fields["even"] == false)))
+        .unwrap();
+    assert_eq!(odd.id, "vec_5");
+    assert!(db.nearest(&query, Some(1.5), None).is_none());
+}
+
+#[test]
+fn test_query_projected_keeps_only_named_fields() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: [
+                ("title".to_string(), "A".into()),
+                ("body".to_string(), "long text".into()),
+            ]
+            .into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![0.0, 1.0],
+            fields: [("body".to_string(), "no title".into())].into(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    let results = db.query_projected(&[1.0, 0.2], 2, None, None, &["title".to_string()]);
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0][constants::F_ID], "a");
+    assert_eq!(results[0]["title"], "A");
+    assert!(results[0].contains_key(constants::F_METRICS));
+    assert!(!results[0].contains_key("body"));
+    assert_eq!(results[1].len(), 2);
+    assert!(!results[1].contains_key("title"));
+}
+
+#[test]
+fn test_rerank_fixes_quantized_ordering() {
+    use rand::SeedableRng;
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+    let vectors = random_vectors(&mut rng, 32, 500);
+    let exact = exact_db(path, &vectors);
+    let raw = quantized_db(path, &vectors, |db| db);
+    let reranked = quantized_db(path, &vectors, |db| db.with_rerank(true));
+
+    let ids = |db: &NanoVectorDB, query: &[f32]| -> Vec<String> {
+        db.query_typed(query, 10, None, None)
+            .into_iter()
+            .map(|r| r.id)
+            .collect()
+    };
+    let (mut raw_correct, mut reranked_correct) = (0, 0);
+    for query in random_vectors(&mut rng, 32, 20) {
+        let expected = ids(&exact, &query);
+        let correct = |got: Vec<String>| got.iter().zip(&expected).filter(|(a, b)| a == b).count();
+        raw_correct += correct(ids(&raw, &query));
+        reranked_correct += correct(ids(&reranked, &query));
+    }
+    assert!(raw_correct < 200, "raw ordering already exact");
+    assert!(reranked_correct > raw_correct);
+    assert!(reranked_correct >= 195, "reranked {reranked_correct}/200");
+}
+
+#[test]
+fn test_duplication_report_finds_known_pair() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    let record = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("a", vec![1.0, 0.0, 0.0]),
+        record("b", vec![0.0, 1.0, 0.0]),
+        record("a_copy", vec![1.0, 0.01, 0.0]),
+        record("c", vec![0.0, 0.0, 1.0]),
+    ])
+    .unwrap();
+
+    assert_eq!(
+        db.duplication_report(0.99),
+        DuplicationStats {
+            total: 4,
+            duplicated: 2,
+            clusters: vec![vec!["a".to_string(), "a_copy".to_string()]],
+        }
+    );
+    assert_eq!(db.duplication_report(1.5).duplicated, 0);
+}
+
+#[test]
+fn test_records_keep_insertion_order() {
+    // Also with one row per segment, so insert_at shifts rows across segments
+    for segment_bytes in [0, 8] {
+        let temp_file = NamedTempFile::new().unwrap();
+        let path = temp_file.path().to_str().unwrap();
+        let mut db = NanoVectorDB::new(2, path)
+            .unwrap()
+            .with_segment_bytes(segment_bytes);
+        let record = |i: usize| Data {
+            id: format!("t{i}"),
+            vector: vec![1.0, i as f32],
+            fields: HashMap::new(),
+            weight: None,
+        };
+        db.upsert((0..6).map(record).collect()).unwrap();
+        db.delete(&["t1".to_string(), "t4".to_string()]);
+        db.upsert(vec![record(6)]).unwrap();
+        assert_eq!(db.insert_at(1, record(9)).unwrap(), "t9");
+        assert!(db.insert_at(1, record(9)).is_err());
+        assert!(db.insert_at(7, record(10)).is_err());
+
+        let order = |db: &NanoVectorDB| -> Vec<String> {
+            (0..db.len())
+                .map(|i| db.id_at(i).unwrap().to_string())
+                .collect()
+        };
+        let expected = ["t0", "t9", "t2", "t3", "t5", "t6"];
+        assert_eq!(order(&db), expected);
+        assert_eq!(db.vector_at(1).unwrap(), normalize(&[1.0, 9.0]));
+        assert_eq!(db.vector_at(5).unwrap(), normalize(&[1.0, 6.0]));
+        assert!(db.verify().is_ok());
+
+        db.save().unwrap();
+        let reloaded = NanoVectorDB::new(2, path).unwrap();
+        assert_eq!(order(&reloaded), expected);
+    }
+}
+
+#[test]
+fn test_nearest_centroid_routes_to_closest() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let centroids = vec![vec![10.0, 0.0], vec![0.0, 10.0]];
+
+    let db = NanoVectorDB::new(2, path).unwrap();
+    assert_eq!(db.nearest_centroid(&[0.9, 0.2], &centroids).unwrap(), 0);
+    assert_eq!(db.nearest_centroid(&[0.1, 3.0], &centroids).unwrap(), 1);
+
+    let db = NanoVectorDB::new(2, path).unwrap().with_metric(Metric::L2);
+    assert_eq!(db.nearest_centroid(&[1.0, 8.0], &centroids).unwrap(), 1);
+
+    assert!(db.nearest_centroid(&[1.0, 8.0], &[]).is_err());
+    assert!(db.nearest_centroid(&[1.0, 8.0], &[vec![1.0]]).is_err());
+    assert!(db.nearest_centroid(&[1.0], &centroids).is_err());
+}
+
+#[test]
+fn test_ivf_recall_grows_with_nprobe() {
+    use rand::SeedableRng;
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+    let mut db = exact_db(path, &random_vectors(&mut rng, 16, 1000));
+    let queries = random_vectors(&mut rng, 16, 20);
+
+    let ids = |db: &NanoVectorDB, query: &[f32]| -> Vec<String> {
+        db.query_typed(query, 10, None, None)
+            .into_iter()
+            .map(|r| r.id)
+            .collect()
+    };
+    let recall = |db: &mut NanoVectorDB, nprobe: usize| -> f32 {
+        db.drop_ivf();
+        let expected: Vec<Vec<String>> = queries.iter().map(|q| ids(db, q)).collect();
+        db.build_ivf(16, Some(42)).unwrap();
+        db.set_ivf_nprobe(nprobe);
+        let hits: usize = queries
+            .iter()
+            .zip(&expected)
+            .map(|(q, expected)| ids(db, q).iter().filter(|id| expected.contains(id)).count())
+            .sum();
+        hits as f32 / (queries.len() * 10) as f32
+    };
+
+    let recalls: Vec<f32> = [1, 4, 16].iter().map(|&n| recall(&mut db, n)).collect();
+    assert!(
+        recalls[0] < recalls[1] && recalls[1] <= recalls[2],
+        "{recalls:?}"
+    );
+    assert_eq!(recalls[2], 1.0);
+
+    // Partitions follow upserts and deletes
+    db.upsert(numbered_records(1000, &random_vectors(&mut rng, 16, 100)))
+        .unwrap();
+    db.delete(&(0..50).map(|i| format!("v{i}")).collect::<Vec<_>>());
+    let with_ivf: Vec<Vec<String>> = queries.iter().map(|q| ids(&db, q)).collect();
+    db.drop_ivf();
+    let exact: Vec<Vec<String>> = queries.iter().map(|q| ids(&db, q)).collect();
+    assert_eq!(with_ivf, exact);
+
+    assert!(db.build_ivf(0, Some(1)).is_err());
+    assert!(db.build_ivf(2000, Some(1)).is_err());
+}
+
+#[test]
+fn test_query_range_combines_numeric_filter_and_similarity() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let record = |id: &str, x: f32, price: serde_json::Value| Data {
+        id: id.to_string(),
+        vector: vec![1.0, x],
+        fields: [("price".to_string(), price)].into(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("cheap", 1.0, serde_json::json!(5)),
+        record("low", 2.0, serde_json::json!(10)),
+        record("mid", 3.0, serde_json::json!(25.5)),
+        record("high", 4.0, serde_json::json!(50)),
+        record("luxury", 3.1, serde_json::json!(120)),
+        record("text", 3.0, serde_json::json!("30")),
+    ])
+    .unwrap();
+    db.upsert(vec![Data {
+        id: "unpriced".to_string(),
+        vector: vec![1.0, 3.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+
+    let ids = |results: Vec<HashMap<String, serde_json::Value>>| -> Vec<String> {
+        results
+            .iter()
+            .map(|r| r[constants::F_ID].as_str().unwrap().to_string())
+            .collect()
+    };
+    let query = [1.0, 3.05];
+    let scanned = ids(db.query_range(&query, "price", 10.0..=50.0, 10, None));
+    assert_eq!(scanned, ["mid", "high", "low"]);
+
+    db.build_field_index("price").unwrap();
+    assert_eq!(
+        ids(db.query_range(&query, "price", 10.0..=50.0, 10, None)),
+        scanned
+    );
+    db.delete(&["mid".to_string()]);
+    assert_eq!(
+        ids(db.query_range(&query, "price", 10.0..=50.0, 1, None)),
+        ["high"]
+    );
+}
+
+#[test]
+fn test_query_stream_matches_collected_results() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    assert_eq!(db.query_stream(&[1.0, 0.0], 5, None, None).count(), 0);
+    db.upsert(
+        (0..50)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32 / 10.0],
+                fields: [("i".to_string(), i.into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let query = [1.0, 2.0];
+    let streamed: Vec<QueryResult> = db.query_stream(&query, 30, None, None).collect();
+    assert_eq!(streamed.len(), 30);
+    assert_eq!(streamed, db.query_typed(&query, 30, None, None));
+    let first = db.query_stream(&query, 30, None, None).next().unwrap();
+    assert_eq!(first.id, "vec_20");
+}
+
+#[test]
+fn test_query_custom_matches_metric_ranking() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_metric(Metric::L2);
+    db.upsert(
+        (0..20)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![(i % 5) as f32, (i / 5) as f32 * 1.5],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let l2 = |a: &[f32], b: &[f32]| -> f32 {
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| (x - y) * (x - y))
+            .sum::<f32>()
+            .sqrt()
+    };
+    let ids =
+        |results: Vec<QueryResult>| -> Vec<String> { results.into_iter().map(|r| r.id).collect() };
+
+    let query = [2.2, 3.1];
+    let expected = ids(db.query_typed(&query, 8, None, None));
+    let negated = db.query_custom(&query, 8, |q, v| -l2(q, v), false, None, None);
+    assert!(negated[0].score <= 0.0);
+    assert_eq!(ids(negated), expected);
+
+    let inverted = db.query_custom(&query, 8, l2, true, Some(1.6), None);
+    assert!(inverted.iter().all(|r| r.score <= 1.6));
+    let within = inverted.len();
+    assert!(within > 0 && within < 8);
+    assert_eq!(ids(inverted), expected[..within]);
+
+    // Record weights only apply to the metric's own scores
+    db.set_weight(&expected[0], 3.0).unwrap();
+    let weighted = db.query_custom(&query, 8, l2, true, None, None);
+    assert_eq!(weighted[0].id, expected[0]);
+    for result in &weighted {
+        let vector = db.vector_at(result.index).unwrap();
+        assert_eq!(result.score, l2(&query, vector));
+    }
+    assert_eq!(ids(weighted), expected);
+}
+
+#[test]
+fn test_boundary_ties_are_all_returned() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let record = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::new(),
+        weight: None,
+    };
+    let records = || {
+        vec![
+            record("best", vec![1.0, 0.0]),
+            record("tie_a", vec![1.0, 1.0]),
+            record("tie_b", vec![2.0, 2.0]),
+            record("worst", vec![0.0, 1.0]),
+        ]
+    };
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    db.upsert(records()).unwrap();
+    let query = [1.0, 0.2];
+    assert_eq!(db.query_typed(&query, 2, None, None).len(), 2);
+
+    let mut db = NanoVectorDB::new(2, path).unwrap().with_boundary_ties(true);
+    db.upsert(records()).unwrap();
+    let results = db.query_typed(&query, 2, None, None);
+    let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, ["best", "tie_a", "tie_b"]);
+    assert_eq!(results[1].score, results[2].score);
+    assert_eq!(db.query_typed(&query, 1, None, None).len(), 1);
+}
+
+#[test]
+fn test_disabled_normalization_stores_raw_vectors() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_normalization(false);
+    let vectors = [
+        vec![0.1, 0.2, 0.3],
+        vec![3.0, 0.0, 0.0],
+        vec![0.0, 2.5, 0.5],
+    ];
+    db.upsert(
+        vectors
+            .iter()
+            .enumerate()
+            .map(|(i, vector)| Data {
+                id: format!("vec_{i}"),
+                vector: vector.clone(),
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    for (i, vector) in vectors.iter().enumerate() {
+        let stored: Vec<u32> = db
+            .vector_at(i)
+            .unwrap()
+            .iter()
+            .map(|x| x.to_bits())
+            .collect();
+        let input: Vec<u32> = vector.iter().map(|x| x.to_bits()).collect();
+        assert_eq!(stored, input);
+    }
+    assert!(db.verify().is_ok());
+
+    // Scores are raw dot products with the unnormalized query
+    let results = db.query_typed(&[1.0, 1.0, 0.0], 3, None, None);
+    let ids: Vec<&str> = results.iter().map(|r| r.id.as_str()).collect();
+    assert_eq!(ids, ["vec_1", "vec_2", "vec_0"]);
+    assert!((results[0].score - 3.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_retain_keeps_matching_records() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..10)
+            .map(|i| Data {
+                id: format!("vec_{i}"),
+                vector: vec![1.0, i as f32],
+                fields: [("score".to_string(), i.into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let expected_rows: Vec<Vec<f32>> = [1, 3, 5, 7, 9]
+        .iter()
+        .map(|&i| db.vector_at(i).unwrap().to_vec())
+        .collect();
+
+    let calls = std::cell::Cell::new(0);
+    db.retain(|d| {
+        calls.set(calls.get() + 1);
+        d.fields["score"].as_u64().unwrap() % 2 == 1
+    });
+    assert_eq!(calls.get(), 10);
+    assert_eq!(db.len(), 5);
+    for (index, i) in [1, 3, 5, 7, 9].iter().enumerate() {
+        assert_eq!(db.id_at(index), Some(format!("vec_{i}").as_str()));
+        assert_eq!(db.vector_at(index).unwrap(), expected_rows[index]);
+    }
+    assert!(db.verify().is_ok());
+}
+
+#[test]
+fn test_pearson_ignores_constant_offset() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let pattern = vec![1.0, -1.0, 2.0, -2.0];
+    let offset: Vec<f32> = pattern.iter().map(|x| x + 100.0).collect();
+    let records = || {
+        vec![Data {
+            id: "offset".to_string(),
+            vector: offset.clone(),
+            fields: HashMap::new(),
+            weight: None,
+        }]
+    };
+
+    let mut cosine = NanoVectorDB::new(4, path).unwrap();
+    cosine.upsert(records()).unwrap();
+    let mut pearson = NanoVectorDB::new(4, path)
+        .unwrap()
+        .with_metric(Metric::Pearson);
+    pearson.upsert(records()).unwrap();
+
+    // The offset dominates raw cosine, making the pattern look unrelated
+    let cosine_score = cosine.query(&pattern, 1, None, None)[0]["__metrics__"]
+        .as_f64()
+        .unwrap();
+    assert!(cosine_score < 0.1, "cosine score {cosine_score}");
+    let pearson_score = pearson.query(&pattern, 1, None, None)[0]["__metrics__"]
+        .as_f64()
+        .unwrap();
+    assert!(
+        (pearson_score - 1.0).abs() < 1e-5,
+        "pearson score {pearson_score}"
+    );
+
+    let negated: Vec<f32> = pattern.iter().map(|x| -x).collect();
+    let score = pearson.similarity(&negated, &offset).unwrap();
+    assert!((score + 1.0).abs() < 1e-5);
+
+    // A constant query has no variance to correlate with
+    assert!(pearson.query(&[2.0; 4], 1, None, None).is_empty());
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_prometheus_metrics() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(
+        (0..4)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32, 0.5],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    assert!(!db
+        .prometheus_metrics()
+        .contains("nanovectordb_last_query_seconds"));
+    db.query(&[1.0, 0.0, 0.0], 2, None, None);
+
+    let output = db.prometheus_metrics();
+    let value = |name: &str| -> f64 {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{name} ")))
+            .unwrap_or_else(|| panic!("missing {name}"))
+            .parse()
+            .unwrap()
+    };
+    assert_eq!(value("nanovectordb_records"), 4.0);
+    assert_eq!(value("nanovectordb_embedding_dim"), 3.0);
+    assert!(value("nanovectordb_last_query_seconds") > 0.0);
+    assert!(value("nanovectordb_memory_bytes") >= (4 * 3 * 4) as f64);
+    assert!(output.contains("# TYPE nanovectordb_records gauge"));
+}
+
+#[test]
+fn test_query_biased_shifts_ranking() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "east".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "north_east".to_string(),
+            vector: vec![1.0, 1.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let query = [1.0, 0.2];
+    assert_eq!(db.query_typed(&query, 1, None, None)[0].id, "east");
+
+    // A bias pulling everything south moves north_east towards the query and
+    // east away from it...
+    let results = db.query_biased(&query, &[0.0, 0.0], 2, None, None).unwrap();
+    assert_eq!(results[0].id, "east");
+    let results = db
+        .query_biased(&query, &[0.0, -0.8], 2, None, None)
+        .unwrap();
+    assert_eq!(results[0].id, "north_east");
+    // ...and scores match scoring the shifted vectors directly
+    let expected = db.similarity(&query, &[1.0, -0.8]).unwrap();
+    let east = results.iter().find(|r| r.id == "east").unwrap();
+    assert!((east.score - expected).abs() < 1e-5);
+
+    assert!(db.query_biased(&query, &[0.0], 2, None, None).is_err());
+}
+
+#[test]
+fn test_field_value_counts() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let categories = ["news", "sports", "news", "tech", "news", "sports"];
+    let mut records: Vec<Data> = categories
+        .iter()
+        .enumerate()
+        .map(|(i, category)| Data {
+            id: format!("v{i}"),
+            vector: vec![1.0, i as f32],
+            fields: [("category".to_string(), (*category).into())].into(),
+            weight: None,
+        })
+        .collect();
+    records.push(Data {
+        id: "untagged".to_string(),
+        vector: vec![0.0, 1.0],
+        fields: HashMap::new(),
+        weight: None,
+    });
+    db.upsert(records).unwrap();
+
+    assert_eq!(
+        db.field_value_counts("category"),
+        vec![
+            (serde_json::json!("news"), 3),
+            (serde_json::json!("sports"), 2),
+            (serde_json::json!("tech"), 1),
+        ]
+    );
+    assert_eq!(
+        db.distinct_field_values("category"),
+        vec![
+            serde_json::json!("news"),
+            serde_json::json!("sports"),
+            serde_json::json!("tech"),
+        ]
+    );
+    assert!(db.distinct_field_values("missing").is_empty());
+}
+
+#[test]
+fn test_chunked_matrix_growth_bounds_capacity() {
+    let records = || -> Vec<Data> {
+        (0..1100)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32, 0.5, 0.25],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect()
+    };
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+
+    let mut doubling = NanoVectorDB::new(4, path).unwrap();
+    doubling.upsert(records()).unwrap();
+
+    // Spare room never exceeds one chunk, however the records arrive
+    let mut chunked = NanoVectorDB::new(4, path)
+        .unwrap()
+        .with_matrix_growth(MatrixGrowth::Chunked(100));
+    let mut batch = records();
+    while !batch.is_empty() {
+        let rest = batch.split_off(batch.len().min(370));
+        chunked.upsert(batch).unwrap();
+        assert!(chunked.capacity() >= chunked.len());
+        assert!(chunked.capacity() - chunked.len() < 100);
+        batch = rest;
+    }
+    assert_eq!(chunked.len(), 1100);
+    assert_eq!(
+        chunked.query_typed(&[1.0, 7.0, 0.5, 0.25], 1, None, None)[0].id,
+        doubling.query_typed(&[1.0, 7.0, 0.5, 0.25], 1, None, None)[0].id
+    );
+}
+
+#[test]
+fn test_concurrent_delete_then_len_is_atomic() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let handle = ConcurrentNanoVectorDB::new(db);
+    let stale = |d: &Data| d.fields.get("stale") == Some(&serde_json::Value::Bool(true));
+
+    // Writers only ever insert stale records, so once they are deleted under the
+    // same lock nothing can remain
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let handle = handle.clone();
+            std::thread::spawn(move || {
+                for i in 0..200 {
+                    handle
+                        .write()
+                        .upsert(vec![Data {
+                            id: format!("t{t}_{i}"),
+                            vector: vec![1.0, i as f32],
+                            fields: [("stale".to_string(), true.into())].into(),
+                            weight: None,
+                        }])
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for _ in 0..200 {
+        assert_eq!(handle.delete_where_then_len(stale), 0);
+    }
+    for writer in writers {
+        writer.join().unwrap();
+    }
+
+    handle
+        .write()
+        .upsert(vec![Data {
+            id: "fresh".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        }])
+        .unwrap();
+    let results = handle.delete_where_then_query(stale, &[1.0, 0.0], 10, None);
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].id, "fresh");
+    assert_eq!(handle.read().len(), 1);
+}
+
+#[test]
+fn test_open_metadata_only() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let dim = 16;
+    let mut db = NanoVectorDB::new(dim, path).unwrap();
+    db.upsert(
+        (0..2000)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: (0..dim).map(|d| (i * d % 97) as f32 + 1.0).collect(),
+                fields: [("n".to_string(), i.into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    db.store_additional_data([("source".to_string(), "test".into())].into());
+    db.save().unwrap();
+
+    // The matrix is never decoded, so a corrupt one only fails the full open
+    let mut json: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+    json["matrix"] = "not base64!".into();
+    std::fs::write(path, json.to_string()).unwrap();
+    assert!(NanoVectorDB::new(dim, path).is_err());
+
+    // The view has no query methods, so querying it does not compile
+    let view = NanoVectorDB::open_metadata_only(path).unwrap();
+    assert_eq!(view.len(), 2000);
+    assert_eq!(view.embedding_dim(), dim);
+    assert_eq!(view.ids().nth(7), Some("v7"));
+    assert_eq!(view.fields("v42").unwrap()["n"], 42);
+    assert!(view.records().iter().all(|d| d.vector.is_empty()));
+    assert_eq!(view.additional_data()["source"], "test");
+    assert!(NanoVectorDB::open_metadata_only("/nonexistent/db.json").is_err());
+}
+
+#[test]
+fn test_seed_makes_randomized_operations_reproducible() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let build = |seed: u64| {
+        let mut db = NanoVectorDB::new(2, path)
+            .unwrap()
+            .with_id_strategy(IdStrategy::Uuid)
+            .with_seed(seed);
+        db.upsert(
+            (0..20)
+                .map(|i| Data {
+                    id: String::new(),
+                    vector: vec![1.0, i as f32],
+                    fields: HashMap::new(),
+                    weight: None,
+                })
+                .collect(),
+        )
+        .unwrap();
+        db.enable_lsh(4, 2, None).unwrap();
+        let sample: Vec<String> = db.sample(5, None).iter().map(|d| d.id.clone()).collect();
+        let ids: Vec<String> = (0..db.len())
+            .map(|i| db.id_at(i).unwrap().to_string())
+            .collect();
+        let hits: Vec<String> = db
+            .query_typed(&[1.0, 3.0], 3, None, None)
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        (ids, sample, hits)
+    };
+
+    let first = build(7);
+    assert_eq!(first, build(7));
+    assert_ne!(first.0, build(8).0);
+    // Still well-formed v4 UUIDs
+    assert!(first
+        .0
+        .iter()
+        .all(|id| id.len() == 36 && id.chars().nth(14) == Some('4')));
+}
+
+#[test]
+fn test_angular_degrees_reporting() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap())
+        .unwrap()
+        .with_angular_degrees(true);
+    db.upsert(vec![
+        Data {
+            id: "same".to_string(),
+            vector: vec![3.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "orthogonal".to_string(),
+            vector: vec![0.0, 2.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+
+    // Ranking still uses the cosine, best first
+    let results = db.query_typed(&[1.0, 0.0], 2, None, None);
+    assert_eq!(results[0].id, "same");
+    assert!(results[0].score.abs() < 1e-2, "{}", results[0].score);
+    assert_eq!(results[1].id, "orthogonal");
+    assert!((results[1].score - 90.0).abs() < 1e-3);
+    let results = db.query(&[1.0, 0.0], 1, Some(0.5), None);
+    assert_eq!(results.len(), 1);
+    assert!(results[0]["__metrics__"].as_f64().unwrap().is_finite());
+}
+
+#[test]
+fn test_content_hash_is_order_independent() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let record = |i: usize| Data {
+        id: format!("v{i}"),
+        vector: vec![1.0, i as f32, 0.5],
+        fields: [
+            ("n".to_string(), i.into()),
+            ("tag".to_string(), format!("t{}", i % 3).into()),
+        ]
+        .into(),
+        weight: None,
+    };
+
+    let mut forward = NanoVectorDB::new(3, path).unwrap();
+    forward.upsert((0..10).map(record).collect()).unwrap();
+    let mut backward = NanoVectorDB::new(3, path).unwrap();
+    // Hashing before the inserts exercises the incremental updates
+    let empty = backward.content_hash();
+    backward
+        .upsert((0..10).rev().map(record).collect())
+        .unwrap();
+    assert_ne!(backward.content_hash(), empty);
+    assert_eq!(forward.content_hash(), backward.content_hash());
+
+    // Updates and deletes keep the incremental hash equal to a fresh one
+    backward
+        .upsert(vec![Data {
+            id: "v3".to_string(),
+            vector: vec![0.0, 1.0, 0.0],
+            fields: HashMap::new(),
+            weight: None,
+        }])
+        .unwrap();
+    assert_ne!(forward.content_hash(), backward.content_hash());
+    backward.delete(&["v3".to_string()]);
+    forward.delete(&["v3".to_string()]);
+    assert_eq!(forward.content_hash(), backward.content_hash());
+    backward.delete_range(0..2).unwrap();
+    let mut fresh = NanoVectorDB::new(3, path).unwrap();
+    fresh
+        .upsert(
+            (0..10)
+                .rev()
+                .filter(|&i| i != 3)
+                .skip(2)
+                .map(record)
+                .collect(),
+        )
+        .unwrap();
+    assert_eq!(fresh.content_hash(), backward.content_hash());
+    backward.replace_all((0..10).map(record).collect()).unwrap();
+    let mut all = NanoVectorDB::new(3, path).unwrap();
+    all.upsert((0..10).map(record).collect()).unwrap();
+    assert_eq!(all.content_hash(), backward.content_hash());
+}
+
+#[test]
+fn test_rerank_budget_improves_recall() {
+    use rand::SeedableRng;
+
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+    let vectors = random_vectors(&mut rng, 32, 500);
+    let exact = exact_db(path, &vectors);
+    let dbs: Vec<NanoVectorDB> = [10, 20, 100]
+        .iter()
+        .map(|&budget| quantized_db(path, &vectors, |db| db.with_rerank_budget(budget)))
+        .collect();
+
+    let queries = random_vectors(&mut rng, 32, 20);
+    let recall = |db: &NanoVectorDB| -> usize {
+        queries
+            .iter()
+            .map(|query| {
+                let expected: Vec<String> = exact
+                    .query_typed(query, 10, None, None)
+                    .into_iter()
+                    .map(|r| r.id)
+                    .collect();
+                db.query_typed(query, 10, None, None)
+                    .iter()
+                    .filter(|r| expected.contains(&r.id))
+                    .count()
+            })
+            .sum()
+    };
+    let recalls: Vec<usize> = dbs.iter().map(recall).collect();
+    assert!(recalls.windows(2).all(|w| w[0] <= w[1]), "{recalls:?}");
+    assert!(recalls[0] < recalls[2], "{recalls:?}");
+    assert_eq!(recalls[2], 200);
+}
+
+#[test]
+fn test_is_normalized() {
+    assert!(is_normalized(&normalize(&[3.0, 4.0, 12.0]), 1e-6));
+    assert!(is_normalized(&[0.6, 0.8], 1e-6));
+    assert!(!is_normalized(&[3.0, 4.0], 1e-6));
+    assert!(!is_normalized(&[0.0, 0.0, 0.0], 1e-6));
+    assert!(!is_normalized(&[0.0, 0.0, 0.0], 1.0));
+    assert!(!is_normalized(&[], 2.0));
+    // A scaled unit vector passes only within a loose enough tolerance
+    assert!(is_normalized(&[0.6 * 1.01, 0.8 * 1.01], 0.02));
+    assert!(!is_normalized(&[0.6 * 1.01, 0.8 * 1.01], 1e-3));
+}
+
+#[test]
+fn test_query_multi_aggregates_across_queries() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(3, temp_file.path().to_str().unwrap()).unwrap();
+    let record = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("original_match", vec![1.0, 0.0, 0.0]),
+        record("paraphrase_match", vec![0.0, 1.0, 0.0]),
+        record("unrelated", vec![0.3, 0.0, 1.0]),
+        record("between", vec![1.0, 1.0, 0.2]),
+    ])
+    .unwrap();
+    let original = vec![1.0, 0.0, 0.0];
+    let paraphrase = vec![0.0, 1.0, 0.05];
+
+    // The original alone ranks the paraphrase's match last
+    let ids =
+        |results: Vec<QueryResult>| -> Vec<String> { results.into_iter().map(|r| r.id).collect() };
+    let single = ids(db.query_typed(&original, 3, None, None));
+    assert!(!single.contains(&"paraphrase_match".to_string()));
+
+    let queries = vec![original, paraphrase];
+    let max = db.query_multi(&queries, QueryAgg::Max, 4, None, None);
+    let top_two: Vec<&str> = max[..2].iter().map(|r| r.id.as_str()).collect();
+    assert!(top_two.contains(&"original_match") && top_two.contains(&"paraphrase_match"));
+    assert_eq!(max[3].id, "unrelated");
+
+    // The mean rewards the record close to both
+    let mean = db.query_multi(&queries, QueryAgg::Mean, 4, None, None);
+    assert_eq!(mean[0].id, "between");
+    assert!(db.query_multi(&[], QueryAgg::Max, 4, None, None).is_empty());
+}
+
+#[test]
+fn test_flush_recovers_after_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.json");
+    let path = path.to_str().unwrap();
+    let record = |id: &str, x: f32| Data {
+        id: id.to_string(),
+        vector: vec![x, 1.0],
+        fields: [("x".to_string(), x.into())].into(),
+        weight: None,
+    };
+
+    let mut db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_write_ahead_log()
+        .unwrap();
+    db.upsert(vec![record("a", 1.0), record("b", 2.0), record("c", 3.0)])
+        .unwrap();
+    db.delete(&["b".to_string()]);
+    db.upsert(vec![record("a", 5.0)]).unwrap();
+    db.set_weight("c", 2.0).unwrap();
+    db.flush().unwrap();
+    // Never flushed, so lost in the crash
+    db.upsert(vec![record("d", 4.0)]).unwrap();
+    let expected_a = db.vector_at(0).unwrap().to_vec();
+    drop(db);
+
+    let db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_write_ahead_log()
+        .unwrap();
+    let ids: Vec<&str> = (0..db.len()).map(|i| db.id_at(i).unwrap()).collect();
+    assert_eq!(ids, ["a", "c"]);
+    assert_eq!(db.vector_at(0).unwrap(), expected_a.as_slice());
+    assert_eq!(db.get(&["a".to_string()])[0].fields["x"], 5.0);
+    assert_eq!(db.original_norm("a"), Some(26.0f32.sqrt()));
+    assert_eq!(db.get(&["c".to_string()])[0].fields["x"], 3.0);
+    assert_eq!(db.weight("c"), Some(2.0));
+
+    // A save snapshots everything and empties the log
+    db.save().unwrap();
+    assert_eq!(std::fs::read_to_string(format!("{path}.wal")).unwrap(), "");
+    let db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_write_ahead_log()
+        .unwrap();
+    assert_eq!(db.len(), 2);
+}
+
+#[test]
+fn test_id_index_matches_scan() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    let record = |i: usize| Data {
+        id: format!("v{i}"),
+        vector: vec![1.0, i as f32],
+        fields: [("n".to_string(), i.into())].into(),
+        weight: None,
+    };
+    db.upsert((0..50).map(record).collect()).unwrap();
+    let ids: Vec<String> = ["v30", "v3", "missing", "v3", "v49", "v0"]
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+    let fetch =
+        |db: &NanoVectorDB| -> Vec<String> { db.get(&ids).iter().map(|d| d.id.clone()).collect() };
+
+    let scanned = fetch(&db);
+    assert_eq!(scanned, ["v0", "v3", "v30", "v49"]);
+    db.build_id_index();
+    assert_eq!(fetch(&db), scanned);
+
+    // The index follows inserts, deletes and moves
+    db.delete(&["v3".to_string(), "v10".to_string()]);
+    db.upsert(vec![record(60)]).unwrap();
+    db.insert_at(0, record(70)).unwrap();
+    db.upsert(vec![record(30)]).unwrap();
+    let ids: Vec<String> = (0..80).map(|i| format!("v{i}")).collect();
+    let indexed: Vec<String> = db.get(&ids).iter().map(|d| d.id.clone()).collect();
+    db.drop_id_index();
+    let scanned: Vec<String> = db.get(&ids).iter().map(|d| d.id.clone()).collect();
+    assert_eq!(indexed, scanned);
+    assert_eq!(indexed.len(), 50);
+    assert_eq!(indexed[0], "v70");
+}
+
+#[test]
+fn test_query_to_writer() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::new(2, temp_file.path().to_str().unwrap()).unwrap();
+    db.upsert(vec![
+        Data {
+            id: "a".to_string(),
+            vector: vec![1.0, 0.0],
+            fields: [
+                ("title".to_string(), "Hello, \"world\"".into()),
+                ("rank".to_string(), 1.into()),
+            ]
+            .into(),
+            weight: None,
+        },
+        Data {
+            id: "b".to_string(),
+            vector: vec![1.0, 1.0],
+            fields: [("rank".to_string(), 2.into())].into(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let expected = db.query(&[1.0, 0.1], 2, None, None);
+
+    let mut jsonl = Vec::new();
+    let written = db
+        .query_to_writer(
+            &[1.0, 0.1],
+            2,
+            None,
+            None,
+            &mut jsonl,
+            ResultFormat::JsonLines,
+        )
+        .unwrap();
+    assert_eq!(written, 2);
+    let parsed: Vec<HashMap<String, serde_json::Value>> = String::from_utf8(jsonl)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(parsed.len(), expected.len());
+    for (got, want) in parsed.iter().zip(&expected) {
+        // Scores round-trip through text, so only approximately
+        let score = |r: &HashMap<String, serde_json::Value>| r["__metrics__"].as_f64().unwrap();
+        assert!((score(got) - score(want)).abs() < 1e-9);
+        let without_score = |r: &HashMap<String, serde_json::Value>| {
+            let mut r = r.clone();
+            r.remove("__metrics__");
+            r
+        };
+        assert_eq!(without_score(got), without_score(want));
+    }
+
+    let mut csv = Vec::new();
+    db.query_to_writer(&[1.0, 0.1], 2, None, None, &mut csv, ResultFormat::Csv)
+        .unwrap();
+    let csv = String::from_utf8(csv).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[0], "__id__,__metrics__,rank,title");
+    let score = expected[0]["__metrics__"].as_f64().unwrap();
+    let row: Vec<&str> = lines[1].splitn(4, ',').collect();
+    assert_eq!(row[0], "a");
+    assert!((row[1].parse::<f64>().unwrap() - score).abs() < 1e-6);
+    assert_eq!(row[2], "1");
+    assert_eq!(row[3], "\"Hello, \"\"world\"\"\"");
+    assert!(lines[2].starts_with("b,") && lines[2].ends_with(",2,"));
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_field_compression_round_trip() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let body = "the quick brown fox jumps over the lazy dog. ".repeat(200);
+    let mut db = NanoVectorDB::new(2, path)
+        .unwrap()
+        .with_field_compression(1024);
+    db.upsert(vec![Data {
+        id: "doc".to_string(),
+        vector: vec![1.0, 0.0],
+        fields: HashMap::from([
+            ("body".to_string(), serde_json::json!(body)),
+            ("title".to_string(), serde_json::json!("short")),
+        ]),
+        weight: None,
+    }])
+    .unwrap();
+    db.save().unwrap();
+
+    let stored = std::fs::read_to_string(path).unwrap();
+    assert!(stored.len() < body.len());
+    assert!(stored.contains("__gzip__"));
+    assert!(stored.contains("\"short\""));
+
+    let reloaded = NanoVectorDB::new(2, path).unwrap();
+    let record = &reloaded.get(&["doc".to_string()])[0];
+    assert_eq!(record.fields["body"], serde_json::json!(body));
+    assert_eq!(record.fields["title"], serde_json::json!("short"));
+    let results = reloaded.query(&[1.0, 0.0], 1, None, None);
+    assert_eq!(results[0]["body"], serde_json::json!(body));
+}
+
+#[test]
+fn test_prewarm_is_a_safe_no_op() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let empty = NanoVectorDB::new(4, path).unwrap();
+    empty.prewarm();
+
+    let mut db = NanoVectorDB::new(4, path)
+        .unwrap()
+        .with_matrix_growth(MatrixGrowth::Chunked(64));
+    db.upsert(
+        (0..2000)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32, 0.5, -1.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let before = db.query(&[1.0, 3.0, 0.5, -1.0], 5, None, None);
+    db.prewarm();
+    assert_eq!(db.query(&[1.0, 3.0, 0.5, -1.0], 5, None, None), before);
+    assert_eq!(db.len(), 2000);
+}
+
+#[test]
+fn test_soft_delete_and_compaction() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap().with_soft_delete(0.9);
+    db.upsert(
+        (0..100)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32 / 100.0],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let live_hash = {
+        let mut expected = NanoVectorDB::new(2, "").unwrap();
+        expected
+            .upsert(
+                (50..100)
+                    .map(|i| Data {
+                        id: format!("v{i}"),
+                        vector: vec![1.0, i as f32 / 100.0],
+                        fields: HashMap::new(),
+                        weight: None,
+                    })
+                    .collect(),
+            )
+            .unwrap();
+        expected.content_hash()
+    };
+
+    let deleted: Vec<String> = (0..50).map(|i| format!("v{i}")).collect();
+    db.delete(&deleted);
+    assert_eq!(db.tombstone_count(), 50);
+    assert_eq!(db.len(), 100);
+    assert!((db.tombstone_ratio() - 0.5).abs() < 1e-6);
+    assert!(db.get(&deleted).is_empty());
+    let results = db.query_typed(&[1.0, 0.0], 100, None, None);
+    assert_eq!(results.len(), 50);
+    assert!(results.iter().all(|r| !deleted.contains(&r.id)));
+    assert_eq!(db.content_hash(), live_hash);
+
+    // Tombstones survive a reload
+    db.save().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    assert_eq!(db.tombstone_count(), 50);
+    assert_eq!(db.query(&[1.0, 0.0], 100, None, None).len(), 50);
+
+    // Upserting a tombstoned id revives it
+    db.upsert(vec![Data {
+        id: "v0".to_string(),
+        vector: vec![0.0, 1.0],
+        fields: [("revived".to_string(), true.into())].into(),
+        weight: None,
+    }])
+    .unwrap();
+    assert_eq!(db.tombstone_count(), 49);
+    let revived = db.get(&["v0".to_string()]);
+    assert_eq!(revived.len(), 1);
+    assert_eq!(revived[0].vector, [0.0, 1.0]);
+    assert_eq!(revived[0].fields["revived"], true);
+
+    db.compact();
+    assert_eq!(db.tombstone_count(), 0);
+    assert_eq!(db.len(), 51);
+    assert_eq!(db.vector_bytes_len(), 51 * 2);
+    assert_eq!(db.query(&[1.0, 0.0], 100, None, None).len(), 51);
+
+    // Reaching the ratio compacts on its own
+    let mut db = db.with_soft_delete(0.05);
+    let batch: Vec<String> = (50..55).map(|i| format!("v{i}")).collect();
+    db.delete(&batch);
+    assert_eq!((db.len(), db.tombstone_count()), (46, 0));
+
+    // Inserting before a tombstone shifts it along with its record
+    let mut db = NanoVectorDB::new(2, "").unwrap().with_soft_delete(0.9);
+    db.upsert(
+        ["a", "b", "c"]
+            .into_iter()
+            .map(|id| Data {
+                id: id.to_string(),
+                vector: vec![1.0, 0.0],
+                fields: [("tag".to_string(), id.into())].into(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    db.delete(&["b".to_string()]);
+    let tags = db.field_value_counts("tag");
+    assert_eq!(tags, [("a".into(), 1), ("c".into(), 1)]);
+    assert_eq!(db.weight("b"), None);
+    assert_eq!(db.original_norm("b"), None);
+    db.insert_at(
+        0,
+        Data {
+            id: "z".to_string(),
+            vector: vec![0.0, 1.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    )
+    .unwrap();
+    let ids = ["a", "b", "c", "z"].map(String::from);
+    let live: Vec<&str> = db.get(&ids).iter().map(|d| d.id.as_str()).collect();
+    assert_eq!(live, ["z", "a", "c"]);
+}
+
+#[test]
+fn test_import_csv() {
+    let mut db = NanoVectorDB::new(3, "").unwrap();
+    let csv = "id,embedding,title,year,draft\n\
+               a,\"[1, 0, 0]\",\"Hello, world\",2021,false\n\
+               b,\"[0, 1, 0]\",Second,2022.5,\n\
+               c,\"[0, 0, 1]\",\"Multi\nline\",,true\n";
+    let columns = ColumnMap {
+        id_col: Some("id".to_string()),
+        vector: VectorColumns::Json("embedding".to_string()),
+        field_cols: Vec::new(),
+    };
+    assert_eq!(db.import_csv(csv.as_bytes(), columns.clone()).unwrap(), 3);
+    assert_eq!(db.len(), 3);
+
+    let results = db.query_typed(&[0.9, 0.1, 0.0], 1, None, None);
+    assert_eq!(results[0].id, "a");
+    let fields = &results[0].fields;
+    assert_eq!(fields["title"], serde_json::json!("Hello, world"));
+    assert_eq!(fields["year"], serde_json::json!(2021));
+    assert_eq!(fields["draft"], serde_json::json!(false));
+    let b = &db.get(&["b".to_string()])[0];
+    assert_eq!(b.fields["year"], serde_json::json!(2022.5));
+    assert!(!b.fields.contains_key("draft"));
+    let c = &db.get(&["c".to_string()])[0];
+    assert_eq!(c.fields["title"], serde_json::json!("Multi\nline"));
+
+    // Numeric columns, with a bad row reported by line and nothing imported
+    let mut db = NanoVectorDB::new(2, "").unwrap();
+    let csv = "name,x,y,tag\nfirst,1.0,0.0,red\nsecond,0.0,oops,blue\n";
+    let columns = ColumnMap {
+        id_col: Some("name".to_string()),
+        vector: VectorColumns::Starting("x".to_string()),
+        field_cols: vec!["tag".to_string()],
+    };
+    match db.import_csv(csv.as_bytes(), columns.clone()) {
+        Err(NanoVectorDbError::AtLine { line, .. }) => assert_eq!(line, 3),
+        other => panic!("expected AtLine, got {other:?}"),
+    }
+    assert!(db.is_empty());
+
+    // Rows the upsert would reject are reported by line as well
+    let csv = "name,x,y,tag\nfirst,1.0,0.0,red\nzero,0.0,0.0,blue\n";
+    match db.import_csv(csv.as_bytes(), columns.clone()) {
+        Err(NanoVectorDbError::AtLine { line, source }) => {
+            assert_eq!(line, 3);
+            assert!(matches!(*source, NanoVectorDbError::ZeroVector { .. }));
+        }
+        other => panic!("expected AtLine, got {other:?}"),
+    }
+    db.set_schema(HashMap::from([("tag".to_string(), FieldType::Number)]));
+    let csv = "name,x,y,tag\nfirst,1.0,0.0,3\nsecond,0.0,1.0,blue\n";
+    match db.import_csv(csv.as_bytes(), columns.clone()) {
+        Err(NanoVectorDbError::AtLine { line, .. }) => assert_eq!(line, 3),
+        other => panic!("expected AtLine, got {other:?}"),
+    }
+    db.set_schema(HashMap::new());
+    assert!(db.is_empty());
+
+    // A byte order mark does not end up in the first column name
+    let csv = "\u{feff}name,x,y,tag\nfirst,1.0,0.0,red\nsecond,0.0,1.0,blue\n";
+    assert_eq!(db.import_csv(csv.as_bytes(), columns).unwrap(), 2);
+    let results = db.query_typed(&[0.0, 1.0], 1, None, None);
+    assert_eq!(results[0].id, "second");
+    assert_eq!(results[0].fields["tag"], serde_json::json!("blue"));
+}
+
+#[test]
+fn test_query_since() {
+    let mut db = NanoVectorDB::new(2, "").unwrap().with_timestamps(true);
+    let hour_ago = timestamp_now() - 3_600_000;
+    let old = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::from([(
+            constants::F_TIMESTAMP.to_string(),
+            serde_json::json!(hour_ago - 60_000),
+        )]),
+        weight: None,
+    };
+    db.upsert(vec![
+        old("old-a", vec![1.0, 0.0]),
+        old("old-b", vec![1.0, 0.1]),
+    ])
+    .unwrap();
+    db.upsert(vec![
+        Data {
+            id: "new-a".to_string(),
+            vector: vec![0.5, 1.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+        Data {
+            id: "new-b".to_string(),
+            vector: vec![0.0, 1.0],
+            fields: HashMap::new(),
+            weight: None,
+        },
+    ])
+    .unwrap();
+    let stamp = &db.get(&["new-a".to_string()])[0].fields[constants::F_TIMESTAMP];
+    assert!(stamp.as_u64().unwrap() > hour_ago);
+
+    let ids =
+        |results: Vec<QueryResult>| -> Vec<String> { results.into_iter().map(|r| r.id).collect() };
+    assert_eq!(ids(db.query_typed(&[1.0, 0.0], 1, None, None)), ["old-a"]);
+    let recent = ids(db.query_since(&[1.0, 0.0], hour_ago, 10, None, None));
+    assert_eq!(recent, ["new-a", "new-b"]);
+
+    db.build_field_index(constants::F_TIMESTAMP).unwrap();
+    let filter: Box<dyn Fn(&Data) -> bool + Send + Sync> = Box::new(|d| d.id != "new-a");
+    let recent = ids(db.query_since(&[1.0, 0.0], hour_ago, 10, None, Some(filter)));
+    assert_eq!(recent, ["new-b"]);
+
+    // Rewriting an old record stamps it again
+    db.upsert(vec![Data {
+        id: "old-a".to_string(),
+        vector: vec![1.0, 0.0],
+        fields: HashMap::new(),
+        weight: None,
+    }])
+    .unwrap();
+    let recent = ids(db.query_since(&[1.0, 0.0], hour_ago, 10, None, None));
+    assert_eq!(recent, ["old-a", "new-a", "new-b"]);
+    assert!(db
+        .query_since(&[1.0, 0.0], timestamp_now() + 1, 10, None, None)
+        .is_empty());
+}
+
+#[test]
+fn test_with_capacity_preallocates() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let mut db = NanoVectorDB::with_capacity(8, temp_file.path().to_str().unwrap(), 5000).unwrap();
+    assert_eq!(db.capacity(), 5000);
+    assert!(db.is_empty());
+
+    for batch in 0..10 {
+        db.upsert(
+            (0..500)
+                .map(|i| Data {
+                    id: format!("v{batch}-{i}"),
+                    vector: vec![1.0, i as f32, batch as f32, 0.5, 0.0, 0.0, 0.0, 1.0],
+                    fields: HashMap::new(),
+                    weight: None,
+                })
+                .collect(),
+        )
+        .unwrap();
+        // Never grown past the reservation
+        assert_eq!(db.capacity(), 5000);
+    }
+    assert_eq!(db.len(), 5000);
+}
+
+#[test]
+fn test_query_result_index_matches_vector_at() {
+    let mut db = NanoVectorDB::new(3, "").unwrap();
+    db.upsert(
+        (0..20)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32 * 0.1, (i % 3) as f32],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    db.delete(&["v3".to_string(), "v7".to_string()]);
+
+    let query = [1.0, 0.5, 1.0];
+    let results = db.query_typed(&query, 5, None, None);
+    assert_eq!(results.len(), 5);
+    for result in &results {
+        assert_eq!(db.id_at(result.index), Some(result.id.as_str()));
+        let vector = db.vector_at(result.index).unwrap();
+        let (stored, _) = db.get_vectors(std::slice::from_ref(&result.id));
+        assert_eq!(vector, stored.as_slice());
+        let score = db.similarity(&query, vector).unwrap();
+        assert!((score - result.score).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn test_max_parallel_queries_under_load() {
+    let mut db = NanoVectorDB::new(16, "")
+        .unwrap()
+        .with_max_parallel_queries(2);
+    db.upsert(
+        (0..2000)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: (0..16).map(|d| ((i * 16 + d) as f32).sin()).collect(),
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+    let query: Vec<f32> = (0..16).map(|d| d as f32).collect();
+    let expected = db.query_typed(&query, 10, None, None);
+
+    let db = ConcurrentNanoVectorDB::new(db);
+    let (done, finished) = std::sync::mpsc::channel();
+    // Counts filtered queries whose filter ran on the querying thread itself,
+    // i.e. that fell back to a serial scan
+    let serial = Arc::new(AtomicUsize::new(0));
+    let start = Arc::new(std::sync::Barrier::new(32));
+    for _ in 0..32 {
+        let (db, query, expected, done, serial, start) = (
+            db.clone(),
+            query.clone(),
+            expected.clone(),
+            done.clone(),
+            serial.clone(),
+            start.clone(),
+        );
+        std::thread::spawn(move || {
+            start.wait();
+            for _ in 0..20 {
+                assert_eq!(db.read().query_typed(&query, 10, None, None), expected);
+                let caller = std::thread::current().id();
+                let ran_here = Arc::new(AtomicBool::new(false));
+                let flag = ran_here.clone();
+                let filter = Box::new(move |_: &Data| {
+                    if std::thread::current().id() == caller {
+                        flag.store(true, Ordering::Relaxed);
+                    }
+                    true
+                });
+                assert_eq!(
+                    db.read().query_typed(&query, 10, None, Some(filter)),
+                    expected
+                );
+                if ran_here.load(Ordering::Relaxed) {
+                    serial.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            done.send(()).unwrap();
+        });
+    }
+    for _ in 0..32 {
+        finished
+            .recv_timeout(std::time::Duration::from_secs(60))
+            .expect("a query thread hung or panicked");
+    }
+    assert!(serial.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn test_export_for_external() {
+    let temp_dir = tempfile::tempdir().unwrap();
+    let matrix_path = temp_dir.path().join("vectors.f32");
+    let ids_path = temp_dir.path().join("ids.txt");
+    let mut db = NanoVectorDB::new(3, "").unwrap();
+    db.upsert(
+        (0..5)
+            .map(|i| Data {
+                id: format!("v{i}"),
+                vector: vec![1.0, i as f32, -0.5],
+                fields: HashMap::new(),
+                weight: None,
+            })
+            .collect(),
+    )
+    .unwrap();
+
+    let written = db
+        .export_for_external(matrix_path.to_str().unwrap(), ids_path.to_str().unwrap())
+        .unwrap();
+    assert_eq!(written, db.len());
+    let bytes = std::fs::read(&matrix_path).unwrap();
+    assert_eq!(bytes.len(), db.len() * db.embedding_dim() * 4);
+    let ids = std::fs::read_to_string(&ids_path).unwrap();
+    let ids: Vec<&str> = ids.lines().collect();
+    assert_eq!(ids.len(), db.len());
+    for (index, id) in ids.iter().enumerate() {
+        assert_eq!(db.id_at(index), Some(*id));
+        let row: Vec<f32> = bytes[index * 12..(index + 1) * 12]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(row.as_slice(), db.vector_at(index).unwrap());
+    }
+}
+
+#[test]
+fn test_query_ordered_worst_first() {
+    let mut db = NanoVectorDB::new(2, "").unwrap();
+    let record = |id: &str, vector: Vec<f32>| Data {
+        id: id.to_string(),
+        vector,
+        fields: HashMap::new(),
+        weight: None,
+    };
+    db.upsert(vec![
+        record("same", vec![1.0, 0.0]),
+        record("close", vec![1.0, 0.2]),
+        record("orthogonal", vec![0.0, 1.0]),
+        record("opposite", vec![-1.0, 0.0]),
+        record("mostly-opposite", vec![-1.0, 0.3]),
+    ])
+    .unwrap();
+
+    let ids =
+        |results: &[QueryResult]| -> Vec<String> { results.iter().map(|r| r.id.clone()).collect() };
+    let worst = db.query_ordered(&[1.0, 0.0], 2, None, None, Order::Worst);
+    assert_eq!(ids(&worst), ["opposite", "mostly-opposite"]);
+    assert!((worst[0].score + 1.0).abs() < 1e-6);
+    assert!(worst[0].score <= worst[1].score);
+
+    let best = db.query_ordered(&[1.0, 0.0], 2, None, None, Order::Best);
+    assert_eq!(best, db.query_typed(&[1.0, 0.0], 2, None, None));
+    assert_eq!(ids(&best), ["same", "close"]);
+
+    // The bound still applies to the worst-first ranking
+    let bounded = db.query_ordered(&[1.0, 0.0], 2, Some(0.0), None, Order::Worst);
+    assert_eq!(ids(&bounded), ["orthogonal", "close"]);
+}
+
+#[test]
+fn test_schema_rejects_wrong_field_types() {
+    let mut db = NanoVectorDB::new(2, "").unwrap();
+    db.set_schema(HashMap::from([
+        ("year".to_string(), FieldType::Integer),
+        ("tags".to_string(), FieldType::Array),
+    ]));
+    let record = |id: &str, fields: serde_json::Value| Data {
+        id: id.to_string(),
+        vector: vec![1.0, 0.0],
+        fields: serde_json::from_value(fields).unwrap(),
+        weight: None,
+    };
+
+    db.upsert(vec![
+        record("ok", serde_json::json!({"year": 2024, "tags": ["a"]})),
+        record("partial", serde_json::json!({"title": "no schema fields"})),
+    ])
+    .unwrap();
+    assert_eq!(db.len(), 2);
+
+    let err = db
+        .upsert(vec![
+            record("fine", serde_json::json!({"year": 1999})),
+            record("bad-year", serde_json::json!({"year": "2024"})),
+            record(
+                "bad-tags",
+                serde_json::json!({"year": 2000.5, "tags": "a,b"}),
+            ),
+        ])
+        .unwrap_err();
+    let NanoVectorDbError::Multiple(errors) = err else {
+        panic!("expected Multiple, got {err:?}");
+    };
+    let violations: Vec<(&str, &str, FieldType)> = errors
+        .iter()
+        .map(|e| match e {
+            NanoVectorDbError::Record { id, source } => match source.as_ref() {
+                NanoVectorDbError::SchemaViolation {
+                    field, expected, ..
+                } => (id.as_str(), field.as_str(), *expected),
+                other => panic!("expected SchemaViolation, got {other:?}"),
+            },
+            other => panic!("expected Record, got {other:?}"),
+        })
+        .collect();
+    assert_eq!(
+        violations,
+        [
+            ("bad-year", "year", FieldType::Integer),
+            ("bad-tags", "tags", FieldType::Array),
+            ("bad-tags", "year", FieldType::Integer),
+        ]
+    );
+    assert_eq!(db.len(), 2);
+
+    let mut db = db.with_schema_required(true);
+    let err = db
+        .upsert(vec![record("partial-2", serde_json::json!({"year": 2024}))])
+        .unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("Field \"tags\" must be Array, got nothing"));
+}
+
+#[test]
+fn test_score_quantiles() {
+    let temp_file = NamedTempFile::new().unwrap();
+    let path = temp_file.path().to_str().unwrap();
+    let mut db = NanoVectorDB::new(2, path).unwrap();
+    assert!(db.score_quantiles(&[1.0, 0.0], &[0.5]).is_empty());
+
+    // Cosine scores cos(θ) for θ evenly spread over [0, π], with median 0
+    let data = (0..=1000)
+        .map(|i| {
+            let theta = std::f32::consts::PI * i as f32 / 1000.0;
+            Data {
+                id: format!("vec_{i}"),
+                vector: vec![theta.cos(), theta.sin()],
+                fields: HashMap::new(),
+                weight: None,
+            }
+        })
+        .collect();
+    db.upsert(data).unwrap();
+
+    let quantiles = db.score_quantiles(&[1.0, 0.0], &[0.0, 0.5, 1.0]);
+    assert_eq!(quantiles.len(), 3);
+    assert!((quantiles[0] + 1.0).abs() < 1e-3);
+    assert!(quantiles[1].abs() < 0.01);
+    assert!((quantiles[2] - 1.0).abs() < 1e-3);
+
+    assert!(db.score_quantiles(&[0.0, 0.0], &[0.5]).is_empty());
+}